@@ -0,0 +1,56 @@
+//! Compares allocations made when copying packet data into new buffers and
+//! into buffers reused from [BufferPool].
+//!
+//! Run with `cargo bench --bench buffer_pool`.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use pktreplay::channel::BufferPool;
+
+/// Allocator counting the number of allocations made.
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Number of packets copied in each run.
+const PACKETS: u64 = 1_000_000;
+
+/// Copies `PACKETS` packets of `data` with `copy` and drops them with
+/// `done`, printing the number of allocations and time taken.
+fn run(name: &str, data: &[u8], copy: impl Fn(&[u8]) -> Vec<u8>, done: impl Fn(Vec<u8>)) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        let buf = copy(black_box(data));
+        done(black_box(buf));
+    }
+    let elapsed = start.elapsed();
+    let allocs = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{name}: {allocs} allocations for {PACKETS} packets, {:.1}ns per packet",
+        elapsed.as_nanos() as f64 / PACKETS as f64
+    );
+}
+
+fn main() {
+    let data = vec![0xab_u8; 1500];
+    run("to_vec", &data, |d| d.to_vec(), drop);
+    let pool = BufferPool::default();
+    run("pool", &data, |d| pool.copy_of(d), |buf| pool.recycle(buf));
+}
@@ -0,0 +1,122 @@
+//! Prefix-preserving anonymization of IP addresses.
+//!
+//! Addresses are anonymized in the style of Crypto-PAn: each bit of the
+//! address is flipped or kept based on a keyed pseudo random function of
+//! the bits before it. Addresses sharing a prefix of any length are
+//! anonymized to addresses sharing a prefix of the same length, so subnet
+//! structure is kept while the real addresses are hidden. The mapping only
+//! depends on the key, so runs with the same key anonymize addresses the
+//! same way.
+//!
+//! SipHash-2-4 is used as the pseudo random function instead of AES used by
+//! Crypto-PAn, so the anonymized addresses differ from ones produced by
+//! Crypto-PAn implementations.
+use std::collections::HashMap;
+
+/// Number of anonymized addresses kept in cache. Cache is cleared once it
+/// is full.
+const CACHE_SIZE: usize = 65536;
+
+/// Does one SipRound on state `v`.
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13);
+    v[1] ^= v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16);
+    v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21);
+    v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17);
+    v[1] ^= v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/// Adds message word `m` to state `v`.
+fn compress(v: &mut [u64; 4], m: u64) {
+    v[3] ^= m;
+    sip_round(v);
+    sip_round(v);
+    v[0] ^= m;
+}
+
+/// Returns SipHash-2-4 of `data` with key `(k0, k1)`.
+fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f_6d65_7073_6575,
+        k1 ^ 0x646f_7261_6e64_6f6d,
+        k0 ^ 0x6c79_6765_6e65_7261,
+        k1 ^ 0x7465_6462_7974_6573,
+    ];
+    let mut words = data.chunks_exact(8);
+    for w in &mut words {
+        compress(&mut v, u64::from_le_bytes(w.try_into().unwrap()));
+    }
+    let rest = words.remainder();
+    let mut last = [0u8; 8];
+    last[..rest.len()].copy_from_slice(rest);
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Prefix-preserving anonymizer for IPv4 and IPv6 addresses.
+pub struct PrefixPreserving {
+    k0: u64,
+    k1: u64,
+    /// Anonymized addresses by IP version and original address.
+    cache: HashMap<(u8, u128), u128>,
+}
+
+impl PrefixPreserving {
+    /// Creates [PrefixPreserving] anonymizing addresses with key derived
+    /// from `key`.
+    pub fn new(key: &str) -> Self {
+        PrefixPreserving {
+            k0: siphash(0, 0, key.as_bytes()),
+            k1: siphash(0, 1, key.as_bytes()),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Returns anonymized IPv4 address for `addr`.
+    pub fn ipv4(&mut self, addr: u32) -> u32 {
+        self.anonymize(4, u128::from(addr), 32) as u32
+    }
+
+    /// Returns anonymized IPv6 address for `addr`.
+    pub fn ipv6(&mut self, addr: u128) -> u128 {
+        self.anonymize(6, addr, 128)
+    }
+
+    /// Returns anonymized address for `addr` of `bits` bits long addresses
+    /// of IP `version`.
+    fn anonymize(&mut self, version: u8, addr: u128, bits: u32) -> u128 {
+        if let Some(anon) = self.cache.get(&(version, addr)) {
+            return *anon;
+        }
+        let mut flip = 0u128;
+        for i in 0..bits {
+            // each bit depends on the bits before it only
+            let prefix = if i == 0 { 0 } else { addr >> (bits - i) };
+            let mut input = [0u8; 18];
+            input[0] = version;
+            input[1] = i as u8;
+            input[2..].copy_from_slice(&prefix.to_be_bytes());
+            let bit = u128::from(siphash(self.k0, self.k1, &input) & 1);
+            flip |= bit << (bits - 1 - i);
+        }
+        if self.cache.len() >= CACHE_SIZE {
+            self.cache.clear();
+        }
+        self.cache.insert((version, addr), addr ^ flip);
+        addr ^ flip
+    }
+}
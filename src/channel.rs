@@ -6,9 +6,10 @@ use std::{
         mpsc::{self, Receiver, SendError, Sender},
         Arc, Condvar, Mutex,
     },
+    time::{Duration, Instant},
 };
 
-use crate::input::Packet;
+use crate::{input::Packet, ring::Ring};
 /// Error returned by channel operations
 #[derive(Debug)]
 pub enum ChannelError {
@@ -31,22 +32,107 @@ impl From<SendError<Packet>> for ChannelError {
     }
 }
 
+/// Largest number of buffers kept in [BufferPool].
+const MAX_POOLED: usize = 1024;
+
+/// Pool of packet data buffers, allowing the writer to return buffers of
+/// written packets for the reader to reuse instead of allocating a new
+/// buffer for every packet.
+#[derive(Clone, Default)]
+pub struct BufferPool(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl BufferPool {
+    /// Returns buffer containing copy of `data`, reusing a returned buffer
+    /// if one is available.
+    pub fn copy_of(&self, data: &[u8]) -> Vec<u8> {
+        let buf = self.0.lock().unwrap().pop();
+        match buf {
+            Some(mut buf) => {
+                buf.clear();
+                buf.extend_from_slice(data);
+                buf
+            }
+            None => data.to_vec(),
+        }
+    }
+
+    /// Returns buffer of `len` zero bytes for reading packet data into,
+    /// reusing a returned buffer if one is available.
+    pub fn buffer(&self, len: usize) -> Vec<u8> {
+        let buf = self.0.lock().unwrap().pop();
+        match buf {
+            Some(mut buf) => {
+                buf.clear();
+                buf.resize(len, 0);
+                buf
+            }
+            None => vec![0; len],
+        }
+    }
+
+    /// Returns `buf` to the pool for reuse. Buffer is dropped if the pool
+    /// already is full.
+    pub fn recycle(&self, buf: Vec<u8>) {
+        let mut bufs = self.0.lock().unwrap();
+        if bufs.len() < MAX_POOLED {
+            bufs.push(buf);
+        }
+    }
+}
+
+/// Unit in which channel watermarks are expressed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    /// Watermarks are number of packets
+    Packets,
+    /// Watermarks are number of bytes in packet data
+    Bytes,
+}
+
 /// Context for channel
 struct ChannelContext {
     /// number of packets waiting on channel
     packets: u64,
+    /// number of bytes in packets waiting on channel
+    bytes: u64,
+    /// largest number of packets waiting on channel at any time
+    max_packets: u64,
     /// should the producer be paused
     paused: bool,
+    /// total time the producer has been paused
+    stalled: Duration,
+}
+
+impl ChannelContext {
+    /// Returns fill level of the channel in given unit.
+    fn level(&self, unit: Unit) -> u64 {
+        match unit {
+            Unit::Packets => self.packets,
+            Unit::Bytes => self.bytes,
+        }
+    }
 }
 
 /// Receiver side of channel.
 ///
 /// Rx can be used as iterator to read packets from channel.
 pub struct Rx {
-    recv: Receiver<Packet>,
-    ctx: Arc<(Mutex<ChannelContext>, Condvar)>,
-    watermark_lo: u64,
+    inner: RxInner,
     stop: Arc<AtomicBool>,
+    pool: BufferPool,
+}
+
+/// Queue packets are received from.
+enum RxInner {
+    /// Channel created with [create()].
+    Watermark {
+        recv: Receiver<Packet>,
+        ctx: Arc<(Mutex<ChannelContext>, Condvar)>,
+        watermark_lo: u64,
+        unit: Unit,
+    },
+    /// Channel created with [ring()].
+    Ring(Arc<Ring<Packet>>),
 }
 
 /// Iterator for reading packets.
@@ -62,12 +148,24 @@ impl Iterator for IntoRxIter {
         if self.rx.stop.load(std::sync::atomic::Ordering::Relaxed) {
             return None;
         }
-        let (mux, cvar) = &*self.rx.ctx;
-        let packet = self.rx.recv.recv().ok();
-        if packet.is_some() {
+        let (recv, ctx, watermark_lo, unit) = match &self.rx.inner {
+            RxInner::Watermark {
+                recv,
+                ctx,
+                watermark_lo,
+                unit,
+            } => (recv, ctx, *watermark_lo, *unit),
+            RxInner::Ring(ring) => return ring.pop(&self.rx.stop),
+        };
+        let (mux, cvar) = &**ctx;
+        let packet = recv.recv().ok();
+        if let Some(ref pkt) = packet {
             let mut ctx = mux.lock().unwrap();
             ctx.packets -= 1;
-            if ctx.packets < self.rx.watermark_lo && ctx.paused {
+            ctx.bytes -= pkt.data.len() as u64;
+            // resume once the level drops to the low watermark, a strict
+            // comparison would never resume with a low watermark of 0
+            if ctx.paused && ctx.level(unit) <= watermark_lo {
                 ctx.paused = false;
                 tracing::trace!("waking packet reader");
                 cvar.notify_one();
@@ -78,6 +176,64 @@ impl Iterator for IntoRxIter {
     }
 }
 
+impl Rx {
+    /// Returns the flag signaling that this [Rx] should terminate.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop)
+    }
+
+    /// Returns [Monitor] for observing the fill level of this channel.
+    pub fn monitor(&self) -> Monitor {
+        Monitor(match &self.inner {
+            RxInner::Watermark { ctx, .. } => MonitorInner::Watermark(Arc::clone(ctx)),
+            RxInner::Ring(ring) => MonitorInner::Ring(Arc::clone(ring)),
+        })
+    }
+
+    /// Returns [BufferPool] to return buffers of processed packets to, for
+    /// the sender to reuse.
+    pub fn pool(&self) -> BufferPool {
+        self.pool.clone()
+    }
+}
+
+/// Monitor can be used to observe fill level of a channel.
+pub struct Monitor(MonitorInner);
+
+/// Channel observed by [Monitor].
+enum MonitorInner {
+    Watermark(Arc<(Mutex<ChannelContext>, Condvar)>),
+    Ring(Arc<Ring<Packet>>),
+}
+
+impl Monitor {
+    /// Returns largest number of packets that have been waiting on the
+    /// channel at the same time.
+    pub fn max_depth(&self) -> u64 {
+        match &self.0 {
+            MonitorInner::Watermark(ctx) => ctx.0.lock().unwrap().max_packets,
+            MonitorInner::Ring(ring) => ring.max_len() as u64,
+        }
+    }
+
+    /// Returns the number of packets waiting on the channel.
+    pub fn depth(&self) -> u64 {
+        match &self.0 {
+            MonitorInner::Watermark(ctx) => ctx.0.lock().unwrap().packets,
+            MonitorInner::Ring(ring) => ring.len() as u64,
+        }
+    }
+
+    /// Returns the total time the sender has been blocked waiting for
+    /// room in the channel.
+    pub fn stalled(&self) -> Duration {
+        match &self.0 {
+            MonitorInner::Watermark(ctx) => ctx.0.lock().unwrap().stalled,
+            MonitorInner::Ring(ring) => ring.stalled(),
+        }
+    }
+}
+
 impl IntoIterator for Rx {
     type Item = Packet;
 
@@ -90,10 +246,15 @@ impl IntoIterator for Rx {
 
 impl Drop for Rx {
     fn drop(&mut self) {
-        let (mux, cvar) = &*self.ctx;
+        let ctx = match &self.inner {
+            RxInner::Watermark { ctx, .. } => ctx,
+            RxInner::Ring(ring) => return ring.close_rx(),
+        };
+        let (mux, cvar) = &**ctx;
         let mut ctx = mux.lock().unwrap();
         // ensure any sender will not be paused anymore.
         ctx.packets = 0;
+        ctx.bytes = 0;
         if ctx.paused {
             ctx.paused = false;
             cvar.notify_all();
@@ -103,61 +264,208 @@ impl Drop for Rx {
 
 /// Sender side of channel
 pub struct Tx {
-    sender: Sender<Packet>,
-    watermark_hi: u64,
-    ctx: Arc<(Mutex<ChannelContext>, Condvar)>,
+    inner: TxInner,
+    pool: BufferPool,
+}
+
+/// Queue packets are sent to.
+enum TxInner {
+    /// Channel created with [create()].
+    Watermark {
+        sender: Sender<Packet>,
+        watermark_hi: u64,
+        unit: Unit,
+        ctx: Arc<(Mutex<ChannelContext>, Condvar)>,
+    },
+    /// Channel created with [ring()].
+    Ring(Arc<Ring<Packet>>),
 }
 
 impl Tx {
+    /// Returns [BufferPool] containing buffers returned by the receiver,
+    /// for reading packets into.
+    pub fn pool(&self) -> BufferPool {
+        self.pool.clone()
+    }
+
     /// Writes a packet to channel.
     ///
     /// If channel already is full, then this method blocks until the low
-    /// packet threshold is reached.
+    /// threshold is reached, or for a ring until there is room for the
+    /// packet.
     pub fn write_packet(&self, pkt: Packet) -> Result<(), ChannelError> {
-        let (mux, cvar) = &*self.ctx;
+        let (sender, watermark_hi, unit, ctx) = match &self.inner {
+            TxInner::Watermark {
+                sender,
+                watermark_hi,
+                unit,
+                ctx,
+            } => (sender, *watermark_hi, *unit, ctx),
+            TxInner::Ring(ring) => return Ok(ring.push(pkt).map_err(SendError)?),
+        };
+        let (mux, cvar) = &**ctx;
         let mut ctx = mux.lock().unwrap();
-        if ctx.packets >= self.watermark_hi {
+        // never pause on an empty channel, there would be no packet to
+        // receive to wake this up again
+        if ctx.packets > 0 && ctx.level(unit) >= watermark_hi {
             ctx.paused = true;
         }
-        while ctx.paused {
-            tracing::trace!("Packet reading paused");
-            ctx = cvar.wait(ctx).unwrap();
+        if ctx.paused {
+            let start = Instant::now();
+            while ctx.paused {
+                tracing::trace!("Packet reading paused");
+                ctx = cvar.wait(ctx).unwrap();
+            }
+            ctx.stalled += start.elapsed();
         }
-        self.sender.send(pkt)?;
+        let len = pkt.data.len() as u64;
+        sender.send(pkt)?;
         ctx.packets += 1;
+        ctx.bytes += len;
+        ctx.max_packets = ctx.max_packets.max(ctx.packets);
         tracing::trace!("tx complete, packets in channel: {}", ctx.packets);
         Ok(())
     }
 }
 
+impl Drop for Tx {
+    fn drop(&mut self) {
+        if let TxInner::Ring(ring) = &self.inner {
+            ring.close_tx();
+        }
+    }
+}
+
 /// Creates a channel, returning [Tx] and [Rx] for a channel that allows
-/// `hi` number of packets to be queued. `stop` can be used to signal that
-/// [Rx] should terminate immediately instead of draining the buffer.
+/// `hi` number of packets (or bytes, depending on `unit`) to be queued.
+/// `stop` can be used to signal that [Rx] should terminate immediately
+/// instead of draining the buffer.
 ///
 /// When hi number of packets are queued, the [Tx::write_packet()] will
-/// block until packets are consumed from channel and only `lo` number of
-/// packets are left.
-pub fn create(hi: u64, lo: u64, stop: Arc<AtomicBool>) -> (Tx, Rx) {
+/// block until packets are consumed from channel and at most `lo` number
+/// of packets are left.
+pub fn create(hi: u64, lo: u64, unit: Unit, stop: Arc<AtomicBool>) -> (Tx, Rx) {
     let (sender, recv) = mpsc::channel();
     let ctx = Arc::new((
         Mutex::new(ChannelContext {
             packets: 0,
+            bytes: 0,
+            max_packets: 0,
             paused: false,
+            stalled: Duration::ZERO,
         }),
         Condvar::new(),
     ));
     let ctx2 = Arc::clone(&ctx);
+    let pool = BufferPool::default();
+    (
+        Tx {
+            inner: TxInner::Watermark {
+                sender,
+                ctx,
+                watermark_hi: hi,
+                unit,
+            },
+            pool: pool.clone(),
+        },
+        Rx {
+            inner: RxInner::Watermark {
+                recv,
+                ctx: ctx2,
+                watermark_lo: lo,
+                unit,
+            },
+            stop,
+            pool,
+        },
+    )
+}
+
+/// Creates a channel backed by a lock-free ring buffer holding at most
+/// `capacity` packets, returning [Tx] and [Rx] for it. `stop` can be used
+/// to signal that [Rx] should terminate immediately instead of draining
+/// the buffer.
+///
+/// When the ring is full, [Tx::write_packet()] blocks until the receiver
+/// has consumed a packet. There is no separate low watermark, nor locking
+/// to track the fill level of the channel.
+pub fn ring(capacity: usize, stop: Arc<AtomicBool>) -> (Tx, Rx) {
+    let ring = Arc::new(Ring::new(capacity));
+    let pool = BufferPool::default();
     (
         Tx {
-            sender,
-            ctx,
-            watermark_hi: hi,
+            inner: TxInner::Ring(Arc::clone(&ring)),
+            pool: pool.clone(),
         },
         Rx {
-            recv,
-            ctx: ctx2,
-            watermark_lo: lo,
+            inner: RxInner::Ring(ring),
             stop,
+            pool,
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::RecvTimeoutError, thread, time::SystemTime};
+
+    use super::*;
+
+    /// Number of packets sent through channels in tests, more than the
+    /// channels can hold.
+    const PACKETS: u32 = 1000;
+
+    /// Sends [PACKETS] packets from a thread to `tx` while receiving them
+    /// from `rx`, and checks that all are received in order. Panics if the
+    /// transfer does not finish in time.
+    fn transfer(tx: Tx, rx: Rx) {
+        let sender = thread::spawn(move || {
+            for i in 0..PACKETS {
+                let pkt = Packet {
+                    data: i.to_be_bytes().to_vec(),
+                    when: SystemTime::UNIX_EPOCH,
+                };
+                tx.write_packet(pkt).unwrap();
+            }
+        });
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let received: Vec<u32> = rx
+                .into_iter()
+                .map(|pkt| u32::from_be_bytes(pkt.data.try_into().unwrap()))
+                .collect();
+            let _ = done_tx.send(received);
+        });
+        match done_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(received) => assert_eq!(received, (0..PACKETS).collect::<Vec<_>>()),
+            Err(RecvTimeoutError::Timeout) => panic!("channel deadlocked"),
+            Err(err) => panic!("receiver failed: {err}"),
+        }
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn watermark_lo_zero_hi_one() {
+        let (tx, rx) = create(1, 0, Unit::Packets, Arc::default());
+        transfer(tx, rx);
+    }
+
+    #[test]
+    fn watermark_lo_one_below_hi() {
+        let (tx, rx) = create(16, 15, Unit::Packets, Arc::default());
+        transfer(tx, rx);
+    }
+
+    #[test]
+    fn watermark_bytes_lo_one_below_hi() {
+        // every packet is 4 bytes, so the channel pauses at every packet
+        let (tx, rx) = create(4, 3, Unit::Bytes, Arc::default());
+        transfer(tx, rx);
+    }
+
+    #[test]
+    fn ring_capacity_one() {
+        let (tx, rx) = ring(1, Arc::default());
+        transfer(tx, rx);
+    }
+}
@@ -0,0 +1,43 @@
+//! Internet checksum helpers used when rewriting packets.
+
+/// Folds 32 bit one's complement sum into 16 bits.
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Returns one's complement sum of `data` as 16 bit words added to `sum`.
+/// Data of odd length is padded with zero byte. Pass the result to
+/// [finish] to get the checksum.
+pub fn add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for w in &mut words {
+        sum += u32::from(u16::from_be_bytes([w[0], w[1]]));
+        sum = u32::from(fold(sum));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+        sum = u32::from(fold(sum));
+    }
+    sum
+}
+
+/// Returns internet checksum for one's complement `sum` from [add].
+pub fn finish(sum: u32) -> u16 {
+    !fold(sum)
+}
+
+/// Returns updated internet checksum when data covered by checksum `csum`
+/// is changed from `old` to `new` (RFC 1624). `old` and `new` need to be of
+/// same, even, length and aligned to 16 bit words in the checksummed data.
+pub fn adjust(csum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = u32::from(!csum);
+    for (o, n) in old.chunks_exact(2).zip(new.chunks_exact(2)) {
+        sum += u32::from(!u16::from_be_bytes([o[0], o[1]]));
+        sum += u32::from(u16::from_be_bytes([n[0], n[1]]));
+        sum = u32::from(fold(sum));
+    }
+    !fold(sum)
+}
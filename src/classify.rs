@@ -0,0 +1,93 @@
+//! Classification of packets by their network and transport protocols.
+use crate::rewrite::{
+    ipv6_transport, network_layer, ETHERTYPE_IPV4, ETHERTYPE_IPV6, IPPROTO_ICMPV6, IPPROTO_TCP,
+    IPPROTO_UDP,
+};
+
+/// EtherType for ARP.
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// IP protocol number for ICMP.
+const IPPROTO_ICMP: u8 = 1;
+
+/// Network layer protocol of a packet.
+#[derive(Clone, Copy)]
+enum Network {
+    Ipv4,
+    Ipv6,
+    Arp,
+    Other,
+    Unknown,
+}
+
+/// Transport layer protocol of an IP packet.
+#[derive(Clone, Copy)]
+enum Transport {
+    Tcp,
+    Udp,
+    Icmp,
+    Other,
+    Unknown,
+}
+
+/// Returns the transport protocol of IPv4 packet with header at `off`.
+fn ipv4_transport(data: &[u8], off: usize) -> Option<u8> {
+    let ihl = usize::from(data.get(off)? & 0x0f) * 4;
+    if ihl < 20 || data.len() < off + ihl {
+        return None;
+    }
+    Some(data[off + 9])
+}
+
+/// Returns network and transport protocols of Ethernet frame in `data`.
+fn classify(data: &[u8]) -> (Network, Option<Transport>) {
+    let Some((ethertype, off)) = network_layer(data) else {
+        return (Network::Unknown, None);
+    };
+    let (network, proto) = match ethertype {
+        ETHERTYPE_IPV4 => (Network::Ipv4, ipv4_transport(data, off)),
+        ETHERTYPE_IPV6 => (
+            Network::Ipv6,
+            ipv6_transport(data, off).map(|(proto, _)| proto),
+        ),
+        ETHERTYPE_ARP => return (Network::Arp, None),
+        _ => return (Network::Other, None),
+    };
+    let transport = match proto {
+        Some(IPPROTO_TCP) => Transport::Tcp,
+        Some(IPPROTO_UDP) => Transport::Udp,
+        Some(IPPROTO_ICMP) | Some(IPPROTO_ICMPV6) => Transport::Icmp,
+        Some(_) => Transport::Other,
+        None => Transport::Unknown,
+    };
+    (network, Some(transport))
+}
+
+/// Packet counts by protocol.
+#[derive(Default)]
+pub struct ProtocolCounts {
+    /// Counts indexed by [Network].
+    network: [u64; 5],
+    /// Counts of IP packets indexed by [Transport].
+    transport: [u64; 5],
+}
+
+impl ProtocolCounts {
+    /// Counts the packet in `data`.
+    pub fn count(&mut self, data: &[u8]) {
+        let (network, transport) = classify(data);
+        self.network[network as usize] += 1;
+        if let Some(transport) = transport {
+            self.transport[transport as usize] += 1;
+        }
+    }
+
+    /// Returns summary of the counts.
+    pub fn summary(&self) -> String {
+        let [ipv4, ipv6, arp, other, unknown] = self.network;
+        let [tcp, udp, icmp, ip_other, ip_unknown] = self.transport;
+        format!(
+            "IPv4: {ipv4}, IPv6: {ipv6}, ARP: {arp}, other: {other}, unknown: {unknown} / \
+             TCP: {tcp}, UDP: {udp}, ICMP: {icmp}, other: {ip_other}, unknown: {ip_unknown}"
+        )
+    }
+}
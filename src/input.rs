@@ -1,14 +1,33 @@
 //! Inputs for reading packets
 //!
-//! Packets can be read from network interface or pcap -file.
+//! Packets can be read from network interface, pcap -file or pcapng -file.
+//! Multiple files can be read one after another or merged in timestamp
+//! order.
+//! Files and standard input compressed with gzip or zstd are decompressed
+//! on the fly.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::iter::Peekable;
+use std::net::{SocketAddr, TcpListener};
 use std::sync::atomic::AtomicBool;
 use std::time::Duration;
 use std::{path::Path, time::SystemTime};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use flate2::read::MultiGzDecoder;
 use luomu_libpcap::Packet as LibpcapPacket;
 use luomu_libpcap::Pcap;
 
+use crate::{channel::BufferPool, linktype, output, pcapfile, pcapng};
+
+/// Magic bytes gzip compressed data starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Magic number zstd compressed data starts with.
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+
 /// Raw packet read from input
 pub struct Packet {
     /// Packet data
@@ -23,38 +42,413 @@ pub struct Packet {
 
 /// Input for reading packets.
 pub struct PcapInput {
-    /// Handle for packet capture reader.
-    handle: Pcap,
-    read_timeout: Option<Duration>,
+    source: Source,
+    /// First damaged packet record found while reading a stream.
+    damage: RefCell<Option<Damage>>,
+    /// Error which stopped reading packets with libpcap.
+    error: RefCell<Option<anyhow::Error>>,
+}
+
+/// Damaged packet record found in pcap or pcapng stream. Packets before
+/// the damaged record are read, reading stops at it.
+#[derive(Clone, Debug)]
+pub struct Damage {
+    /// Number of packets read before the damaged record, which is also the
+    /// index of the damaged record.
+    pub packets: u64,
+    /// What is wrong with the record.
+    pub error: String,
+}
+
+impl Display for Damage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "damaged packet record at index {}: {}",
+            self.packets, self.error
+        )
+    }
+}
+
+/// Where [PcapInput] reads the packets from.
+enum Source {
+    /// Packets are read using libpcap.
+    Pcap {
+        /// Handle for packet capture reader.
+        handle: Pcap,
+        read_timeout: Option<Duration>,
+        /// Snap length of the capture, if known.
+        snaplen: Option<u32>,
+    },
+    /// Packets are read from pcap or pcapng stream with our own reader.
+    Stream(RefCell<Box<dyn StreamReader>>),
+    /// Packets are read from multiple inputs.
+    Multi {
+        inputs: Vec<PcapInput>,
+        combine: Combine,
+    },
+}
+
+/// How packets from multiple inputs are combined.
+#[derive(Clone, Copy)]
+pub enum Combine {
+    /// Read inputs one after another. If `preserve_gaps` is set, the time
+    /// between the last packet of an input and the first packet of the next
+    /// one is kept.
+    Sequence { preserve_gaps: bool },
+    /// Read packets from all inputs in timestamp order.
+    Merge,
+}
+
+/// Where timestamps of packets come from.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimestampSource {
+    /// Use the timestamp read from input.
+    #[default]
+    Capture,
+    /// Use the time packet was read from input.
+    Arrival,
+}
+
+/// Reader for packets in pcap or pcapng stream.
+trait StreamReader {
+    /// Returns the next packet from the stream, or [None] if end of stream
+    /// has been reached. Packet data is placed into buffer taken from
+    /// `pool`, if given.
+    fn next_packet(&mut self, pool: Option<&BufferPool>) -> io::Result<Option<Packet>>;
+    /// Returns the link type of packets in the stream, if known.
+    fn link_type(&self) -> Option<u32>;
+    /// Returns the snap length of the stream, if known.
+    fn snaplen(&self) -> Option<u32>;
+}
+
+impl<R: Read> StreamReader for pcapng::Reader<R> {
+    fn next_packet(&mut self, pool: Option<&BufferPool>) -> io::Result<Option<Packet>> {
+        pcapng::Reader::next_packet(self, pool)
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        pcapng::Reader::link_type(self)
+    }
+
+    fn snaplen(&self) -> Option<u32> {
+        pcapng::Reader::snaplen(self)
+    }
+}
+
+impl<R: Read> StreamReader for pcapfile::Reader<R> {
+    fn next_packet(&mut self, pool: Option<&BufferPool>) -> io::Result<Option<Packet>> {
+        pcapfile::Reader::next_packet(self, pool)
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        Some(pcapfile::Reader::link_type(self))
+    }
+
+    fn snaplen(&self) -> Option<u32> {
+        Some(pcapfile::Reader::snaplen(self))
+    }
+}
+
+/// Returns [StreamReader] for reading packets from `rd`. Format of the
+/// stream is detected from its magic number, gzip and zstd compressed
+/// streams are decompressed.
+fn open_stream(mut rd: Box<dyn Read>) -> Result<Box<dyn StreamReader>> {
+    let mut magic = [0u8; 4];
+    rd.read_exact(&mut magic)?;
+    let rd: Box<dyn Read> = Box::new(io::Cursor::new(magic).chain(rd));
+    if magic[..2] == GZIP_MAGIC {
+        let gz: Box<dyn Read> = Box::new(BufReader::new(MultiGzDecoder::new(rd)));
+        return open_stream(gz).context("gzip compressed data is not a pcap or pcapng stream");
+    }
+    if u32::from_le_bytes(magic) == ZSTD_MAGIC {
+        let zst: Box<dyn Read> = Box::new(BufReader::new(zstd::stream::read::Decoder::new(rd)?));
+        return open_stream(zst).context("zstd compressed data is not a pcap or pcapng stream");
+    }
+    if u32::from_le_bytes(magic) == pcapng::MAGIC {
+        Ok(Box::new(pcapng::Reader::new(rd)?))
+    } else {
+        Ok(Box::new(pcapfile::Reader::new(rd)?))
+    }
+}
+
+impl PcapInput {
+    /// Returns the link type of packets read from this input, if known. For
+    /// multiple inputs, link type of the first one is returned.
+    pub fn link_type(&self) -> Option<u32> {
+        match &self.source {
+            Source::Pcap { handle, .. } => handle.datalink().ok().map(linktype::from_dlt),
+            Source::Stream(reader) => reader.borrow().link_type(),
+            Source::Multi { inputs, .. } => inputs.first().and_then(PcapInput::link_type),
+        }
+    }
+
+    /// Returns the maximum number of bytes captured from each packet read
+    /// from this input, if known. For multiple inputs, the largest one is
+    /// returned.
+    pub fn snaplen(&self) -> Option<u32> {
+        match &self.source {
+            Source::Pcap { snaplen, .. } => *snaplen,
+            Source::Stream(reader) => reader.borrow().snaplen(),
+            Source::Multi { inputs, .. } => inputs.iter().filter_map(PcapInput::snaplen).max(),
+        }
+    }
+
+    /// Returns the first damaged packet record found while reading packets
+    /// from this input, if any. Damage is only detected in inputs read with
+    /// our own pcap and pcapng readers, not with libpcap.
+    pub fn damage(&self) -> Option<Damage> {
+        match &self.source {
+            Source::Multi { inputs, .. } => inputs.iter().find_map(PcapInput::damage),
+            _ => self.damage.borrow().clone(),
+        }
+    }
+
+    /// Returns the error which stopped reading packets from this input, if
+    /// any. Iterators returned by [PcapInput::packets] end at the error, it
+    /// is taken with this so that it is not mistaken for end of input.
+    pub fn take_error(&self) -> Option<anyhow::Error> {
+        match &self.source {
+            Source::Multi { inputs, .. } => inputs.iter().find_map(PcapInput::take_error),
+            _ => self.error.borrow_mut().take(),
+        }
+    }
+
+    /// Creates [PcapInput] reading packets using given libpcap handle.
+    fn libpcap(handle: Pcap, read_timeout: Option<Duration>, snaplen: Option<u32>) -> Self {
+        PcapInput {
+            source: Source::Pcap {
+                handle,
+                read_timeout,
+                snaplen,
+            },
+            damage: RefCell::default(),
+            error: RefCell::default(),
+        }
+    }
 }
 
-/// Creates [PcapInput] for reading packets from given pcap -file
+/// Creates [PcapInput] for reading packets from given pcap or pcapng -file.
+///
+/// pcapng files are detected from their magic number and read with
+/// [pcapng::Reader] which keeps the timestamp resolution of each interface.
+/// pcap files are read with [pcapfile::Reader], as libpcap would round
+/// nanosecond timestamps to microseconds and does not tell a truncated last
+/// packet record apart from end of file. gzip and zstd compressed files are
+/// decompressed while reading. Other files are passed to libpcap.
 pub fn pcap_file<P>(file: P) -> Result<PcapInput>
 where
     P: AsRef<Path>,
 {
+    let mut f = File::open(file.as_ref())?;
+    let mut magic = [0u8; 4];
+    let own_reader = f.read_exact(&mut magic).is_ok()
+        && (magic[..2] == GZIP_MAGIC
+            || u32::from_le_bytes(magic) == ZSTD_MAGIC
+            || u32::from_le_bytes(magic) == pcapng::MAGIC
+            || [pcapfile::MAGIC_MICROS, pcapfile::MAGIC_NANOS]
+                .iter()
+                .any(|m| u32::from_le_bytes(magic) == *m || u32::from_be_bytes(magic) == *m));
+    if own_reader {
+        let rd = Box::new(BufReader::new(File::open(file.as_ref())?));
+        return Ok(PcapInput {
+            source: Source::Stream(RefCell::new(open_stream(rd)?)),
+            damage: RefCell::default(),
+            error: RefCell::default(),
+        });
+    }
     let pcap = Pcap::offline(file)?;
+    Ok(PcapInput::libpcap(pcap, None, None))
+}
+
+/// Creates [PcapInput] reading packets from given pcap or pcapng -files,
+/// combined as selected by `combine`.
+///
+/// When reading files one after another without preserving gaps, timestamps
+/// of packets in each file after the first one are shifted so that the first
+/// packet of a file follows the last packet of the previous file without
+/// delay.
+pub fn pcap_files<P>(files: &[P], combine: Combine) -> Result<PcapInput>
+where
+    P: AsRef<Path>,
+{
+    let inputs = files.iter().map(pcap_file).collect::<Result<_>>()?;
     Ok(PcapInput {
-        handle: pcap,
-        read_timeout: None,
+        source: Source::Multi { inputs, combine },
+        damage: RefCell::default(),
+        error: RefCell::default(),
     })
 }
 
-// Creates [PcapInput] for reading packets from interface with given name
-pub fn pcap_interface(ifname: &str) -> Result<PcapInput> {
-    let builder = Pcap::builder(ifname)?
-        .set_promiscuous(true)?
-        .set_immediate(true)?;
+/// Summary of packets read from input.
+#[derive(Default)]
+pub struct Summary {
+    /// Number of packets.
+    pub packets: u64,
+    /// Total number of bytes in packets.
+    pub bytes: u64,
+    /// Timestamp of the first packet.
+    pub first: Option<SystemTime>,
+    /// Timestamp of the last packet.
+    pub last: Option<SystemTime>,
+    /// MTU needed for writing all packets to interface.
+    pub max_mtu: usize,
+}
+
+impl Summary {
+    /// Returns time between the first and the last packet.
+    pub fn duration(&self) -> Duration {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => last.duration_since(first).unwrap_or_default(),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Reads all packets from `iter` and returns [Summary] of them.
+pub fn summarize(iter: impl Iterator<Item = Packet>) -> Summary {
+    iter.fold(Summary::default(), |mut sum, pkt| {
+        sum.packets += 1;
+        sum.bytes += pkt.data.len() as u64;
+        sum.first.get_or_insert(pkt.when);
+        sum.last = Some(pkt.when);
+        sum.max_mtu = sum.max_mtu.max(output::required_mtu(&pkt.data));
+        sum
+    })
+}
+
+/// Creates [PcapInput] for reading pcap or pcapng stream from standard
+/// input.
+///
+/// Format of the stream is detected like for files, gzip and zstd
+/// compressed streams are decompressed. The stream can not be rewound, so
+/// it can only be read once.
+pub fn pcap_stdin() -> Result<PcapInput> {
+    let rd = Box::new(BufReader::new(io::stdin()));
+    let reader = open_stream(rd).context("unable to read pcap stream from standard input")?;
+    Ok(PcapInput {
+        source: Source::Stream(RefCell::new(reader)),
+        damage: RefCell::default(),
+        error: RefCell::default(),
+    })
+}
+
+/// Returns [PcapInput] for reading packets streamed over TCP by another
+/// pktreplay writing with [output::tcp]. Waits for a single connection to
+/// `addr` and reads a pcap stream from it, like [pcap_stdin] does from
+/// standard input.
+pub fn tcp_listen(addr: SocketAddr) -> Result<PcapInput> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("unable to listen on {addr}"))?;
+    tracing::info!("waiting for connection on {}", addr);
+    let (stream, peer) = listener.accept()?;
+    tracing::info!("reading packets from {}", peer);
+    let rd = Box::new(BufReader::new(stream));
+    let reader =
+        open_stream(rd).with_context(|| format!("unable to read pcap stream from {peer}"))?;
     Ok(PcapInput {
-        handle: builder.activate()?,
-        read_timeout: Some(Duration::from_millis(100)),
+        source: Source::Stream(RefCell::new(reader)),
+        damage: RefCell::default(),
+        error: RefCell::default(),
     })
 }
 
+/// Options for capturing packets from interface.
+#[derive(Clone, Copy)]
+pub struct CaptureOptions {
+    /// Put the interface into promiscuous mode, capturing also packets not
+    /// addressed to this host.
+    pub promiscuous: bool,
+    /// Deliver packets as soon as they arrive instead of buffering them.
+    pub immediate: bool,
+    /// How long to wait for packets before checking if reading should
+    /// stop. Values below [MIN_READ_TIMEOUT] are raised to it.
+    pub read_timeout: Duration,
+    /// Maximum number of bytes captured from each packet, longer packets
+    /// are truncated.
+    pub snaplen: usize,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            promiscuous: true,
+            immediate: true,
+            read_timeout: Duration::from_millis(100),
+            snaplen: DEFAULT_SNAPLEN,
+        }
+    }
+}
+
+/// Snap length used when capturing from interface by default. This is the
+/// largest snap length libpcap supports, so that full packets are captured.
+pub const DEFAULT_SNAPLEN: usize = 262144;
+
+/// Shortest read timeout used when reading from interface. Without packets
+/// to read, the reader wakes up after every timeout, shorter timeout would
+/// make it spin.
+pub const MIN_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+// Creates [PcapInput] for reading packets from interface with given name.
+// If `filter` is given, only packets matching the BPF filter expression are
+// read. If `exclude` is given, packets matching it are not read even if they
+// match `filter`.
+pub fn pcap_interface(
+    ifname: &str,
+    filter: Option<&str>,
+    exclude: Option<&str>,
+    options: CaptureOptions,
+) -> Result<PcapInput> {
+    let builder = Pcap::builder(ifname)?
+        .set_promiscuous(options.promiscuous)?
+        .set_immediate(options.immediate)?
+        .set_snaplen(options.snaplen)?;
+    let handle = builder.activate()?;
+    // expressions are compiled separately first, so that errors name the
+    // expression at fault
+    for expr in filter.iter().chain(exclude.iter()) {
+        handle
+            .compile(expr)
+            .with_context(|| format!("invalid filter expression \"{expr}\""))?;
+    }
+    // combined expression is matched in kernel, so excluded packets are
+    // dropped before they are copied to the reader
+    let combined = match (filter, exclude) {
+        (Some(filter), Some(exclude)) => Some(format!("({filter}) and not ({exclude})")),
+        (Some(filter), None) => Some(filter.to_string()),
+        (None, Some(exclude)) => Some(format!("not ({exclude})")),
+        (None, None) => None,
+    };
+    if let Some(filter) = combined {
+        let prog = handle
+            .compile(&filter)
+            .with_context(|| format!("invalid filter expression \"{filter}\""))?;
+        handle
+            .set_bpf_filter(prog)
+            .with_context(|| format!("unable to set filter \"{filter}\" on {ifname}"))?;
+    }
+    Ok(PcapInput::libpcap(
+        handle,
+        Some(options.read_timeout.max(MIN_READ_TIMEOUT)),
+        u32::try_from(options.snaplen).ok(),
+    ))
+}
+
+/// Returns copy of packet `data`, in buffer taken from `pool` if given.
+pub(crate) fn copy_data(data: &[u8], pool: Option<&BufferPool>) -> Vec<u8> {
+    match pool {
+        Some(pool) => pool.copy_of(data),
+        None => data.to_vec(),
+    }
+}
+
 /// [Iterator] for reading packets using [luomu_libpcap::NonBlockingIter].
 struct TimeoutIter<'a, 'b> {
     iter: luomu_libpcap::NonBlockingIter<'a>,
+    /// Where the error stopping reading is recorded.
+    error: &'a RefCell<Option<anyhow::Error>>,
     sig: &'b AtomicBool,
+    pool: Option<&'b BufferPool>,
 }
 
 impl Iterator for TimeoutIter<'_, '_> {
@@ -64,13 +458,13 @@ impl Iterator for TimeoutIter<'_, '_> {
         loop {
             match self.iter.next() {
                 Some(Err(err)) => {
-                    tracing::error!("Error while reading packets: {}", err);
+                    *self.error.borrow_mut() = Some(err.into());
                     return None;
                 }
                 Some(Ok(pkt)) => {
                     return Some(Packet {
                         when: pkt.timestamp(),
-                        data: pkt.to_vec(),
+                        data: copy_data(pkt.packet(), self.pool),
                     })
                 }
                 None => {
@@ -87,6 +481,7 @@ impl Iterator for TimeoutIter<'_, '_> {
 struct PacketIter<'a, 'b> {
     iter: luomu_libpcap::PcapIter<'a>,
     sig: &'b AtomicBool,
+    pool: Option<&'b BufferPool>,
 }
 
 impl Iterator for PacketIter<'_, '_> {
@@ -101,7 +496,7 @@ impl Iterator for PacketIter<'_, '_> {
                 } else {
                     Some(Packet {
                         when: pkt.timestamp(),
-                        data: pkt.to_vec(),
+                        data: copy_data(pkt.packet(), self.pool),
                     })
                 }
             }
@@ -109,6 +504,280 @@ impl Iterator for PacketIter<'_, '_> {
     }
 }
 
+/// [Iterator] for reading packets using [StreamReader].
+struct StreamIter<'a, 'b> {
+    reader: &'a RefCell<Box<dyn StreamReader>>,
+    /// Where the first damaged record is recorded.
+    damage: &'a RefCell<Option<Damage>>,
+    sig: &'b AtomicBool,
+    pool: Option<&'b BufferPool>,
+    /// Number of packets read.
+    read: u64,
+}
+
+impl StreamIter<'_, '_> {
+    /// Records damaged record at the current position, unless damage has
+    /// already been found.
+    fn record_damage(&self, error: String) {
+        self.damage.borrow_mut().get_or_insert(Damage {
+            packets: self.read,
+            error,
+        });
+    }
+}
+
+impl Iterator for StreamIter<'_, '_> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.sig.load(std::sync::atomic::Ordering::Relaxed) {
+            return None;
+        }
+        // packets read before a damaged record are still replayed, the
+        // damage is reported instead of ending as if the input was complete
+        match self.reader.borrow_mut().next_packet(self.pool) {
+            Ok(Some(pkt)) => {
+                self.read += 1;
+                Some(pkt)
+            }
+            Ok(None) => None,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                tracing::warn!(
+                    "Input ends with truncated packet record after {} packets, ignoring it",
+                    self.read
+                );
+                self.record_damage("truncated record at end of input".to_string());
+                None
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Stopped reading at corrupt packet record after {} packets: {}",
+                    self.read,
+                    err
+                );
+                self.record_damage(err.to_string());
+                None
+            }
+        }
+    }
+}
+
+/// Returns `when` moved by the difference between `from` and `to`.
+fn shift(when: SystemTime, from: SystemTime, to: SystemTime) -> SystemTime {
+    match when.duration_since(from) {
+        Ok(d) => to + d,
+        Err(e) => to - e.duration(),
+    }
+}
+
+/// Returns [Iterator] yielding packets from `iter` with their timestamps
+/// shifted so that the first packet is captured at `start`. If `start` is
+/// [None], timestamps are not changed.
+pub fn shift_to<'a>(
+    iter: impl Iterator<Item = Packet> + 'a,
+    start: Option<SystemTime>,
+) -> impl Iterator<Item = Packet> + 'a {
+    let mut offset = None;
+    iter.map(move |mut pkt| {
+        if let Some(to) = start {
+            let (from, to) = *offset.get_or_insert((pkt.when, to));
+            pkt.when = shift(pkt.when, from, to);
+        }
+        pkt
+    })
+}
+
+/// [Iterator] yielding packets from multiple iterators, one after another.
+struct Concat<'a> {
+    iters: VecDeque<Box<dyn Iterator<Item = Packet> + 'a>>,
+    preserve_gaps: bool,
+    /// Timestamp of the last packet returned.
+    last: Option<SystemTime>,
+    /// Set when the next packet is the first one from an iterator.
+    starting: bool,
+    /// Timestamps of packets from current iterator are shifted from the
+    /// first timestamp to the second.
+    offset: Option<(SystemTime, SystemTime)>,
+}
+
+impl Iterator for Concat<'_> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Some(mut pkt) = self.iters.front_mut()?.next() else {
+                self.iters.pop_front();
+                self.starting = true;
+                continue;
+            };
+            if self.starting {
+                self.starting = false;
+                if !self.preserve_gaps {
+                    self.offset = self.last.map(|last| (pkt.when, last));
+                }
+            }
+            if let Some((from, to)) = self.offset {
+                pkt.when = shift(pkt.when, from, to);
+            }
+            self.last = Some(pkt.when);
+            return Some(pkt);
+        }
+    }
+}
+
+/// [Iterator] yielding packets from multiple iterators in timestamp order.
+///
+/// Packets from each iterator are expected to be in timestamp order. On equal
+/// timestamps, packet from the iterator given first is returned first.
+struct Merge<'a> {
+    iters: Vec<Peekable<Box<dyn Iterator<Item = Packet> + 'a>>>,
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (idx, _) = self
+            .iters
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, it)| it.peek().map(|pkt| (i, pkt.when)))
+            .min_by_key(|(_, when)| *when)?;
+        self.iters[idx].next()
+    }
+}
+
+/// Point in capture time.
+#[derive(Clone, Copy)]
+pub enum TimePoint {
+    /// Absolute point in time.
+    Absolute(SystemTime),
+    /// Offset from the timestamp of the first packet.
+    Offset(Duration),
+}
+
+impl TimePoint {
+    /// Returns the absolute time for this point, when the first packet was
+    /// captured at `first`.
+    fn resolve(&self, first: SystemTime) -> SystemTime {
+        match self {
+            TimePoint::Absolute(t) => *t,
+            TimePoint::Offset(d) => first + *d,
+        }
+    }
+}
+
+/// [Iterator] passing only packets captured within a time window.
+struct TimeWindow<I> {
+    iter: I,
+    start: Option<TimePoint>,
+    end: Option<TimePoint>,
+    /// Timestamp of the first packet read from `iter`.
+    first: Option<SystemTime>,
+}
+
+impl<I: Iterator<Item = Packet>> Iterator for TimeWindow<I> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let pkt = self.iter.next()?;
+            let first = *self.first.get_or_insert(pkt.when);
+            if let Some(end) = self.end {
+                if pkt.when > end.resolve(first) {
+                    return None;
+                }
+            }
+            match self.start {
+                Some(start) if pkt.when < start.resolve(first) => continue,
+                _ => return Some(pkt),
+            }
+        }
+    }
+}
+
+/// Returns [Iterator] yielding packets from `iter` captured between `start`
+/// and `end`. Packets before `start` are skipped and iteration ends once a
+/// packet captured after `end` is seen.
+pub fn time_window<'a>(
+    iter: impl Iterator<Item = Packet> + 'a,
+    start: Option<TimePoint>,
+    end: Option<TimePoint>,
+) -> impl Iterator<Item = Packet> + 'a {
+    TimeWindow {
+        iter,
+        start,
+        end,
+        first: None,
+    }
+}
+
+/// [Iterator] passing only the last packets of another [Iterator].
+struct TakeLast<I> {
+    /// Iterator to read, [None] once it has been read through.
+    iter: Option<I>,
+    /// Last packets read from `iter`.
+    last: VecDeque<Packet>,
+    n: usize,
+}
+
+impl<I: Iterator<Item = Packet>> Iterator for TakeLast<I> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(iter) = self.iter.take() {
+            for pkt in iter {
+                if self.last.len() == self.n {
+                    self.last.pop_front();
+                }
+                self.last.push_back(pkt);
+            }
+        }
+        self.last.pop_front()
+    }
+}
+
+/// Returns [Iterator] yielding the last `n` packets from `iter`.
+///
+/// `iter` is read through before the first packet is returned, keeping at
+/// most `n` packets in memory.
+pub fn take_last<'a>(
+    iter: impl Iterator<Item = Packet> + 'a,
+    n: usize,
+) -> impl Iterator<Item = Packet> + 'a {
+    TakeLast {
+        iter: Some(iter),
+        last: VecDeque::new(),
+        n,
+    }
+}
+
+/// Returns [Iterator] yielding packets from `iter` until `max` bytes have
+/// been read. The packet crossing the limit is included.
+pub fn take_bytes<'a>(
+    iter: impl Iterator<Item = Packet> + 'a,
+    max: u64,
+) -> impl Iterator<Item = Packet> + 'a {
+    iter.scan(0_u64, move |total, pkt| {
+        if *total >= max {
+            return None;
+        }
+        *total += pkt.data.len() as u64;
+        Some(pkt)
+    })
+}
+
+/// Returns [Iterator] yielding packets from `iter` with their timestamps
+/// replaced with the time they were read.
+pub fn arrival_time<'a>(
+    iter: impl Iterator<Item = Packet> + 'a,
+) -> impl Iterator<Item = Packet> + 'a {
+    iter.map(|mut pkt| {
+        pkt.when = SystemTime::now();
+        pkt
+    })
+}
+
 impl PcapInput {
     /// Returns [Iterator] for reading captured packets.
     ///
@@ -118,14 +787,71 @@ impl PcapInput {
         &'a self,
         sig: &'a AtomicBool,
     ) -> Result<Box<dyn Iterator<Item = Packet> + '_>> {
-        match self.read_timeout {
-            None => {
-                let iter = self.handle.capture();
-                Ok(Box::new(PacketIter { iter, sig }))
+        self.iter(sig, None)
+    }
+
+    /// Returns [Iterator] for reading captured packets like
+    /// [PcapInput::packets], but packets read with libpcap are copied into
+    /// buffers taken from `pool` when there are any, instead of allocating
+    /// a new buffer for every packet.
+    pub fn pooled_packets<'a>(
+        &'a self,
+        sig: &'a AtomicBool,
+        pool: &'a BufferPool,
+    ) -> Result<Box<dyn Iterator<Item = Packet> + '_>> {
+        self.iter(sig, Some(pool))
+    }
+
+    fn iter<'a>(
+        &'a self,
+        sig: &'a AtomicBool,
+        pool: Option<&'a BufferPool>,
+    ) -> Result<Box<dyn Iterator<Item = Packet> + '_>> {
+        match &self.source {
+            Source::Pcap {
+                handle,
+                read_timeout: None,
+                ..
+            } => {
+                let iter = handle.capture();
+                Ok(Box::new(PacketIter { iter, sig, pool }))
             }
-            Some(timeout) => {
-                let iter = self.handle.capture_nonblocking(timeout)?;
-                Ok(Box::new(TimeoutIter { iter, sig }))
+            Source::Pcap {
+                handle,
+                read_timeout: Some(timeout),
+                ..
+            } => {
+                let iter = handle.capture_nonblocking(*timeout)?;
+                Ok(Box::new(TimeoutIter {
+                    iter,
+                    error: &self.error,
+                    sig,
+                    pool,
+                }))
+            }
+            Source::Stream(reader) => Ok(Box::new(StreamIter {
+                reader,
+                damage: &self.damage,
+                sig,
+                pool,
+                read: 0,
+            })),
+            Source::Multi { inputs, combine } => {
+                let iters = inputs.iter().map(|input| input.iter(sig, pool));
+                match *combine {
+                    Combine::Sequence { preserve_gaps } => Ok(Box::new(Concat {
+                        iters: iters.collect::<Result<_>>()?,
+                        preserve_gaps,
+                        last: None,
+                        starting: false,
+                        offset: None,
+                    })),
+                    Combine::Merge => Ok(Box::new(Merge {
+                        iters: iters
+                            .map(|it| it.map(Iterator::peekable))
+                            .collect::<Result<_>>()?,
+                    })),
+                }
             }
         }
     }
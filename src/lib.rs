@@ -0,0 +1,29 @@
+//! Replay packets from pcap files or interfaces.
+//!
+//! [Replayer] reads packets using given [InputMethod] and writes them into
+//! [output::PacketWriter] with given [Rate]. The building blocks used by it
+//! are available in modules [input], [output], [pipe] and [channel] for
+//! programs needing more control.
+mod anon;
+pub mod channel;
+mod checksum;
+mod classify;
+pub mod input;
+pub mod linktype;
+pub mod metrics;
+pub mod netif;
+pub mod output;
+mod pcapfile;
+mod pcapng;
+pub mod pipe;
+#[cfg(target_os = "linux")]
+mod rawsock;
+mod replay;
+pub mod rewrite;
+mod ring;
+pub mod rng;
+mod sched;
+pub mod selftest;
+pub mod statsock;
+
+pub use replay::{InputMethod, Limits, Rate, ReplayBuilder, Replayer, RunOutcome};
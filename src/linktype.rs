@@ -0,0 +1,44 @@
+//! Link-layer header types of packets.
+//!
+//! Link types are identified with the `LINKTYPE_` values used in pcap and
+//! pcapng files. libpcap uses `DLT_` values, which are the same for most
+//! link types, [from_dlt] converts them.
+
+/// Ethernet.
+pub const ETHERNET: u32 = 1;
+/// Raw IPv4 or IPv6 packets without link-layer header.
+pub const RAW: u32 = 101;
+/// IEEE 802.11 wireless LAN.
+pub const IEEE802_11: u32 = 105;
+/// Linux cooked capture (SLL).
+pub const LINUX_SLL: u32 = 113;
+/// IEEE 802.11 with radiotap header.
+pub const IEEE802_11_RADIOTAP: u32 = 127;
+/// Linux cooked capture v2 (SLL2).
+pub const LINUX_SLL2: u32 = 276;
+
+/// `DLT_RAW` on most platforms.
+const DLT_RAW: i32 = 12;
+/// `DLT_RAW` on OpenBSD.
+const DLT_RAW_OPENBSD: i32 = 14;
+
+/// Returns link type for libpcap `DLT_` value.
+pub fn from_dlt(dlt: i32) -> u32 {
+    match dlt {
+        DLT_RAW | DLT_RAW_OPENBSD => RAW,
+        _ => dlt as u32,
+    }
+}
+
+/// Returns human readable name for link type.
+pub fn name(link_type: u32) -> String {
+    match link_type {
+        ETHERNET => "Ethernet".to_string(),
+        RAW => "raw IP".to_string(),
+        IEEE802_11 => "IEEE 802.11".to_string(),
+        LINUX_SLL => "Linux cooked capture".to_string(),
+        IEEE802_11_RADIOTAP => "IEEE 802.11 with radiotap header".to_string(),
+        LINUX_SLL2 => "Linux cooked capture v2".to_string(),
+        other => format!("link type {other}"),
+    }
+}
@@ -1,179 +1,453 @@
-use anyhow::Result;
-use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
 use signal_hook::flag;
-use std::sync::atomic::AtomicBool;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::{Args, Parser};
+use clap::{Args, Parser, ValueEnum};
+use luomu_libpcap::{Address, InterfaceFlag};
+use pktreplay::{
+    channel, input, linktype, metrics, netif, output, pipe, rewrite, rng, selftest, statsock,
+};
+use pktreplay::{InputMethod, Limits, Rate, Replayer, RunOutcome};
+use tracing_subscriber::EnvFilter;
 
-mod channel;
-mod input;
-mod output;
-mod pipe;
-
-/// Method to read packets
-enum InputMethod {
-    /// Read packets from pcap -file
-    File(String),
-    /// Read packets from interface.
-    Interface(String),
-}
-
-impl InputMethod {
-    /// Creates [input::PcapInput] for this input method.
-    fn to_pcap_input(&self) -> Result<input::PcapInput> {
-        match self {
-            InputMethod::File(fname) => Ok(input::pcap_file(fname)?),
-            InputMethod::Interface(ifname) => Ok(input::pcap_interface(ifname)?),
-        }
-    }
-}
-
-/// Packet rate for writing packets
-enum Rate {
-    /// Write as fast as possible
-    Full,
-    /// Write with set packet per second
-    Pps(u32),
-    /// Write given megabits per second.
-    Mbps(u64),
-    /// Write packets with a delay implied by their timestamps. This is used
-    /// when reding from a pcap file and we want to output packets in same
-    /// rate as they were saved to the file.
-    Delayed,
-}
-
-/// Starts task for printing statistics to stdout. Returns [thread::JoinHandle]
-/// for created task.
-fn start_printer_task(receiver: Receiver<String>) -> thread::JoinHandle<()> {
+/// Starts task for printing statistics to stdout, or to stderr if `stderr`
+/// is set. Statistics are also written to clients of `socket`, if given.
+/// Returns [thread::JoinHandle] for created task.
+fn start_printer_task(
+    receiver: Receiver<String>,
+    stderr: bool,
+    socket: Option<statsock::StatsSocket>,
+) -> thread::JoinHandle<()> {
     thread::Builder::new()
         .name("stat-reader".to_string())
         .spawn(move || {
             for line in receiver {
-                println!("{}", line)
+                if stderr {
+                    eprintln!("{}", line)
+                } else {
+                    println!("{}", line)
+                }
+                if let Some(ref socket) = socket {
+                    socket.send(&line);
+                }
             }
         })
         .unwrap()
 }
 
-/// Starts thread to read packets using given [InputMethod].
-///
-/// Packets read are sent to `tx` and `pipe` should be the [pipe::Pipe] consuming
-/// packets.
-/// Returns once all packets are read or termination is requested by setting the
-/// `terminate` to true
-fn input_task(
-    method: InputMethod,
-    loop_file: bool,
-    pipe: pipe::Pipe,
-    tx: channel::Tx,
-    terminate: Arc<AtomicBool>,
-    limit: Option<usize>,
-) -> i32 {
-    let stop = terminate.clone();
-    let rd_handle: thread::JoinHandle<anyhow::Result<()>> = thread::Builder::new()
-        .name("pcap-reader".to_string())
+/// Header row of statistics CSV file.
+const CSV_HEADER: &str = "timestamp,packets,bytes,pps,bps";
+
+/// Starts task writing statistics [pipe::Sample]s from `receiver` as CSV
+/// rows to file in `path`, after [CSV_HEADER]. Every row is flushed once
+/// written, so that rows are not lost if pktreplay is terminated. Returns
+/// error if the file can not be created.
+fn start_csv_task(
+    receiver: Receiver<pipe::Sample>,
+    path: &Path,
+) -> io::Result<thread::JoinHandle<()>> {
+    let mut file = File::create(path)?;
+    writeln!(file, "{CSV_HEADER}")?;
+    let path = path.to_path_buf();
+    thread::Builder::new()
+        .name("stat-csv".to_string())
         .spawn(move || {
-            // set this to true if we are looping and have been able to read
-            // the file at least once.
-            let mut opened: bool = false;
-            loop {
-                let input = match method.to_pcap_input() {
-                    Ok(input) => {
-                        if loop_file {
-                            opened = true
-                        }
-                        Some(input)
-                    }
-                    Err(err) => {
-                        if loop_file && opened {
-                            // we have been able to open this file at least
-                            // once, thus just terminate the looping if
-                            // file has been removed
-                            tracing::info!(?err, "looping and file removed?, terminating");
-                            None
-                        } else {
-                            return Err(err);
-                        }
-                    }
-                };
-                let Some(inp) = input else {
-                    // Input not opened, but do not return error
-                    break;
-                };
-
-                let it = match limit {
-                    Some(n) => Box::new(inp.packets(&stop)?.take(n))
-                        as Box<dyn Iterator<Item = input::Packet>>,
-                    None => Box::new(inp.packets(&stop)?),
-                };
-                pipe::read_packets_to(it, &tx)?;
-                if !loop_file || stop.load(std::sync::atomic::Ordering::Relaxed) {
+            for sample in receiver {
+                let when = sample
+                    .when
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64();
+                // single write for each row
+                let row = format!(
+                    "{:.6},{},{},{:.3},{:.3}\n",
+                    when, sample.packets, sample.bytes, sample.pps, sample.bps
+                );
+                if let Err(e) = file.write_all(row.as_bytes()).and_then(|_| file.flush()) {
+                    tracing::error!("Unable to write statistics to {}: {}", path.display(), e);
                     break;
                 }
-                tracing::info!("pcap file iteration complete");
             }
-            Ok(())
         })
-        .unwrap();
-    let mut ret = 0;
-    if let Err(err) = rd_handle.join().unwrap() {
-        // if we have received signal indicating we should stop, discard
-        // reader errors as the packet writer might have terminated
-        // already and reader just complains about closed channel.
-        if !terminate.load(std::sync::atomic::Ordering::Relaxed) {
-            tracing::error!("Error while reading packets: {}", err);
-            ret = -1;
+}
+
+/// Exit code used when `--strict` is given and some packets were not sent.
+const EXIT_NOT_SENT: i32 = 2;
+
+/// Stream of `--seed` used for `--jitter`, see [rng::Rng::stream_seed].
+const JITTER_STREAM: u64 = 1;
+/// Stream of `--seed` used for `--drop-rate`.
+const DROP_STREAM: u64 = 2;
+/// Stream of `--seed` used for `--dup-rate`.
+const DUP_STREAM: u64 = 3;
+/// Stream of `--seed` used for `--reorder`.
+const REORDER_STREAM: u64 = 4;
+
+/// How long to wait for written packets to be received back after writing
+/// completes, when running self-test.
+const SELFTEST_GRACE: Duration = Duration::from_secs(1);
+
+/// Options for reporting the result once all packets are written.
+struct Report<'a> {
+    /// Write final statistics into this file.
+    stats_file: Option<&'a str>,
+    /// Fail with [EXIT_NOT_SENT] if some packets could not be sent, or
+    /// were not received back in self-test.
+    strict: bool,
+    /// Do not print the final statistics to stdout.
+    quiet: bool,
+    /// Print the final statistics to stderr instead of stdout.
+    stderr: bool,
+}
+
+/// Writes final statistics `summary` into file in `path`.
+fn write_stats_file(path: &str, summary: &str) {
+    if let Err(err) = std::fs::write(path, format!("{summary}\n")) {
+        tracing::error!("Unable to write statistics to {}: {}", path, err);
+    }
+}
+
+impl Report<'_> {
+    /// Reports `outcome` of replaying packets and returns the exit code.
+    fn finish(&self, outcome: RunOutcome) -> i32 {
+        let RunOutcome { stats, error } = outcome;
+        match error {
+            None => {
+                if !self.quiet {
+                    if self.stderr {
+                        eprintln!("Write complete: {}", stats);
+                    } else {
+                        println!("Write complete: {}", stats);
+                    }
+                }
+                if let Some(path) = self.stats_file {
+                    write_stats_file(path, &stats.to_string());
+                }
+                if self.strict && stats.invalid() > 0 {
+                    tracing::error!("{} packets were not sent", stats.invalid());
+                    return EXIT_NOT_SENT;
+                }
+                if let Some(verdict) = stats.selftest().filter(|v| self.strict && v.unmatched > 0) {
+                    tracing::error!("{} packets were not received back", verdict.unmatched);
+                    return EXIT_NOT_SENT;
+                }
+                0
+            }
+            Some(err) => {
+                tracing::error!("{:#}", err);
+                if !self.quiet {
+                    if self.stderr {
+                        eprintln!("Write failed: {}", stats);
+                    } else {
+                        println!("Write failed: {}", stats);
+                    }
+                }
+                if let Some(path) = self.stats_file {
+                    write_stats_file(path, &format!("{stats}\nWrite failed: {err:#}"));
+                }
+                -1
+            }
+        }
+    }
+}
+
+/// Format of packets written to stdout
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum OutputFormat {
+    /// Pcap stream with file header
+    #[default]
+    Pcap,
+    /// Packet data only
+    Raw,
+}
+
+/// Method used for writing packets to interface
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum OutputBackend {
+    /// Inject packets with libpcap
+    #[default]
+    Pcap,
+    /// Write packets to Linux AF_PACKET raw socket
+    Raw,
+    /// Write packets to Linux AF_PACKET raw socket, scheduling their
+    /// transmission time with SO_TXTIME
+    Txtime,
+}
+
+/// Unit for packet buffer watermarks
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum WatermarkUnit {
+    /// Watermarks are number of packets
+    #[default]
+    Packets,
+    /// Watermarks are number of bytes
+    Bytes,
+}
+
+/// Source for packet timestamps
+#[derive(Clone, Copy, Default, ValueEnum)]
+enum TimestampSource {
+    /// Use timestamps read from input
+    #[default]
+    Capture,
+    /// Use the time packets are read from input
+    Arrival,
+}
+
+/// Opens [output::PacketWriter] for writing packets to interface with given
+/// name using given [OutputBackend].
+fn open_interface(
+    ifname: &str,
+    params: &Params,
+    input_link: Option<u32>,
+) -> anyhow::Result<Box<dyn output::PacketWriter + Send>> {
+    let opts = output::SocketOptions {
+        send_buffer: params
+            .output_buffer_size
+            .map(|size| usize::try_from(size).unwrap_or(usize::MAX)),
+        tx_queue: params.tx_queue,
+    };
+    let out: Box<dyn output::PacketWriter + Send> = match params.output_backend {
+        // libpcap does not give access to the socket it writes to
+        OutputBackend::Pcap if opts.send_buffer.is_some() => {
+            anyhow::bail!("--output-buffer-size needs --output-backend raw or txtime")
+        }
+        OutputBackend::Pcap if opts.tx_queue.is_some() => {
+            anyhow::bail!("--tx-queue needs --output-backend raw or txtime")
+        }
+        OutputBackend::Pcap => Box::new(output::interface(ifname)?),
+        #[cfg(target_os = "linux")]
+        OutputBackend::Raw => Box::new(output::raw_socket(ifname, &opts)?),
+        #[cfg(target_os = "linux")]
+        OutputBackend::Txtime => Box::new(output::txtime_socket(ifname, &opts)?),
+        #[cfg(not(target_os = "linux"))]
+        OutputBackend::Raw | OutputBackend::Txtime => {
+            anyhow::bail!("raw socket output is only supported on Linux")
         }
+    };
+    check_link_type(input_link, out.as_ref(), ifname, params.force)?;
+    Ok(Box::new(output::link_monitor(out, ifname)))
+}
+
+/// Checks that link type of the input matches the link type `out` expects,
+/// the packets are unlikely to make sense on the output otherwise. Returns
+/// error on mismatch, unless `force` is set in which case only a warning is
+/// logged.
+fn check_link_type(
+    input_link: Option<u32>,
+    out: &dyn output::PacketWriter,
+    name: &str,
+    force: bool,
+) -> anyhow::Result<()> {
+    let (Some(input), Some(output)) = (input_link, out.link_type()) else {
+        return Ok(());
+    };
+    if input == output {
+        return Ok(());
+    }
+    let msg = format!(
+        "Input link type {} differs from link type {} of {}",
+        linktype::name(input),
+        linktype::name(output),
+        name
+    );
+    if input == linktype::LINUX_SLL && output == linktype::ETHERNET {
+        anyhow::bail!("{msg}, use --sll-to-ethernet to convert the packets");
+    }
+    if !force {
+        anyhow::bail!("{msg}, use --force to replay anyway");
+    }
+    tracing::warn!("{}", msg);
+    Ok(())
+}
+
+/// Creates the [output::PacketWriter] selected by command line parameters.
+/// `input_link` is the link type of the input, if known, and `format` the
+/// format pcap files are written with.
+fn create_output(
+    params: &Params,
+    input_link: Option<u32>,
+    format: output::FileFormat,
+) -> anyhow::Result<Box<dyn output::PacketWriter + Send>> {
+    if params.dry_run {
+        return Ok(Box::new(output::null()));
     }
-    tracing::trace!("Reader terminated");
-    match pipe.wait() {
-        Ok(stats) => println!("Write complete: {}", stats),
-        Err(err) => {
-            tracing::error!("Error while writing packets: {}", err);
-            ret = -1
+    if let Some(ref fname) = params.output_file {
+        let rotate = match (params.rotate_packets, params.rotate_seconds) {
+            (Some(n), _) => Some(output::Rotate::Packets(n)),
+            (None, Some(d)) => Some(output::Rotate::Interval(d)),
+            (None, None) => None,
+        };
+        return Ok(match rotate {
+            Some(rotate) => Box::new(output::rotating_file(fname, rotate, format)?),
+            None => Box::new(output::file(fname, format)?),
+        });
+    }
+    if let Some(addr) = params.output_udp {
+        return Ok(Box::new(output::udp(addr)?));
+    }
+    if let Some(addr) = params.output_tcp {
+        return Ok(Box::new(output::tcp(addr, format)?));
+    }
+    let out: Box<dyn output::PacketWriter + Send> = match params.output.as_slice() {
+        [] => Box::new(output::null()),
+        [name] if name == "-" => {
+            let stream = match params.output_format {
+                OutputFormat::Pcap => output::StreamFormat::Pcap,
+                OutputFormat::Raw => output::StreamFormat::Raw,
+            };
+            Box::new(output::stdout(stream, format)?)
+        }
+        names if names.iter().any(|name| name == "-") => {
+            anyhow::bail!("stdout can not be used together with other outputs")
+        }
+        [ifname] => open_interface(ifname, params, input_link)?,
+        ifnames => Box::new(output::interfaces(
+            ifnames
+                .iter()
+                .map(|ifname| open_interface(ifname, params, input_link))
+                .collect::<anyhow::Result<_>>()?,
+        )),
+    };
+    match params.tee_file {
+        Some(ref fname) => Ok(Box::new(output::tee(out, output::file(fname, format)?))),
+        None => Ok(out),
+    }
+}
+
+/// Warns about output interfaces with MTU too small for some of the packets
+/// in `summary`, when packets are truncated to `snaplen`. Returns `false` if
+/// there were any.
+fn check_mtu(ifnames: &[String], summary: &input::Summary, snaplen: Option<usize>) -> bool {
+    let needed = match snaplen {
+        Some(len) => summary
+            .max_mtu
+            .min(len.saturating_sub(rewrite::ETH_HDR_LEN)),
+        None => summary.max_mtu,
+    };
+    let mut ok = true;
+    for ifname in ifnames {
+        // failing to get the MTU is warned about when interface is opened
+        let Ok(mtu) = netif::mtu(ifname) else {
+            continue;
+        };
+        if needed > mtu {
+            tracing::warn!(
+                "Input contains packets needing MTU of {} bytes, larger than MTU {} of {}, these packets are not written",
+                needed,
+                mtu,
+                ifname
+            );
+            ok = false;
         }
     }
-    ret
+    ok
 }
 
-/// Creates a [pipe::Pipe] with given parameters.
-fn create_pipe(
-    rate: Rate,
-    rx: channel::Rx,
-    output: impl output::PacketWriter + Send + 'static,
-    stats: pipe::Stats,
-) -> anyhow::Result<pipe::Pipe> {
-    match rate {
-        Rate::Full => pipe::fullspeed(rx, output, stats),
-        Rate::Delayed => pipe::delaying(rx, output, stats),
-        Rate::Mbps(bps) => pipe::bps(rx, output, bps, stats),
-        Rate::Pps(pps) => pipe::pps(rx, output, pps, stats),
+/// Returns the seed for a randomized feature: `explicit` seed given for it,
+/// or stream `stream` of the run `seed`.
+fn feature_seed(explicit: Option<u64>, seed: u64, stream: u64) -> u64 {
+    explicit.unwrap_or_else(|| rng::Rng::stream_seed(seed, stream))
+}
+
+/// Creates the [pipe::PacketHook]s selected by command line parameters.
+/// Randomized hooks without their own seed use streams of `seed`.
+fn create_hooks(params: &Params, seed: u64) -> Vec<pipe::PacketHook> {
+    let mut hooks = Vec::new();
+    if let Some(fraction) = params.drop_rate {
+        let seed = feature_seed(params.drop_seed, seed, DROP_STREAM);
+        tracing::info!("using drop seed {}", seed);
+        hooks.push(pipe::random_drop(fraction, seed));
     }
+    hooks
+}
+
+/// Creates the [rewrite::Transform]s selected by command line parameters.
+fn create_transforms(params: &Params) -> Vec<Box<dyn rewrite::Transform + Send>> {
+    let mut transforms: Vec<Box<dyn rewrite::Transform + Send>> = Vec::new();
+    // other transforms expect Ethernet frames
+    if let Some(dst) = params.sll_to_ethernet {
+        transforms.push(Box::new(rewrite::SllToEthernet::new(dst)));
+    }
+    if params.src_mac.is_some() || params.dst_mac.is_some() {
+        transforms.push(Box::new(rewrite::MacRewrite::new(
+            params.src_mac,
+            params.dst_mac,
+        )));
+    }
+    // existing tag is removed before adding the new one
+    if params.strip_vlan {
+        transforms.push(Box::new(rewrite::VlanStrip));
+    }
+    if let Some(id) = params.add_vlan {
+        transforms.push(Box::new(rewrite::VlanAdd::new(id)));
+    }
+    if !params.ip_map.is_empty() {
+        transforms.push(Box::new(rewrite::IpRewrite::new(params.ip_map.clone())));
+    }
+    if let Some(key) = params.anon_key.as_deref().filter(|_| params.anonymize) {
+        transforms.push(Box::new(rewrite::Anonymize::new(key)));
+    }
+    if !params.ipv6_map.is_empty() || params.flow_label.is_some() {
+        transforms.push(Box::new(rewrite::Ipv6Rewrite::new(
+            params.ipv6_map.clone(),
+            params.flow_label,
+        )));
+    }
+    // checksums are fixed last, after other transforms have modified packets
+    if params.fix_checksums {
+        transforms.push(Box::new(rewrite::FixChecksums));
+    }
+    transforms
 }
 
 /// Command line parameters for selecting input
 #[derive(Args)]
 #[group(required = true, multiple = false)]
 struct InputParam {
-    /// Name of the pcap file to read
+    /// Name of the pcap file to read, use `-` to read from standard input.
+    /// Can be given multiple times to read files one after another
     #[arg(long, short = 'f')]
-    file: Option<String>,
+    file: Vec<String>,
     /// Read packets from given interface instead of a file
     #[arg[short, long ]]
     interface: Option<String>,
+    /// Accept one TCP connection on given address and read packets another
+    /// pktreplay sends to it with --output-tcp
+    #[arg(long, value_name = "ADDR:PORT")]
+    listen: Option<SocketAddr>,
 }
 
 impl InputParam {
-    /// Returns input method selected
-    fn method(&self) -> InputMethod {
-        if let Some(ref fname) = self.file {
-            InputMethod::File(fname.clone())
+    /// Returns input method selected, packets read from interface are
+    /// filtered with `filter` and `exclude` and captured with `options`.
+    fn method(
+        &self,
+        filter: Option<String>,
+        exclude: Option<String>,
+        options: input::CaptureOptions,
+    ) -> InputMethod {
+        if self.file == ["-"] {
+            InputMethod::Stdin
+        } else if !self.file.is_empty() {
+            InputMethod::File(self.file.clone())
+        } else if let Some(addr) = self.listen {
+            InputMethod::Tcp(addr)
         } else if let Some(ref ifname) = self.interface {
-            InputMethod::Interface(ifname.clone())
+            InputMethod::Interface {
+                name: ifname.clone(),
+                filter,
+                exclude,
+                options,
+            }
         } else {
             unreachable!()
         }
@@ -193,6 +467,389 @@ struct RateParam {
     /// Write packets as fast as possible
     #[arg(short = 'F', long)]
     fullspeed: bool,
+    /// Write packets with given number of microseconds between every packet
+    #[arg(long, value_name = "USEC")]
+    gap: Option<u64>,
+    /// Write packets with packet rate ramping from FROM to TO packets per
+    /// second over DURATION seconds. With HOLD, rate is held at TO for HOLD
+    /// seconds and then ramped back down to FROM
+    #[arg(long, value_name = "FROM:TO:DURATION[:HOLD]", value_parser = parse_ramp)]
+    ramp: Option<pipe::Ramp>,
+    /// Replay packets with their original rate multiplied by given factor
+    #[arg(long, value_parser = parse_speed)]
+    speed: Option<f64>,
+}
+
+/// Parses duration given as (fractional) number of seconds.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("{e}"))
+}
+
+/// Parses rate ramp `FROM:TO:DURATION[:HOLD]`, where rates are packets per
+/// second larger than zero and durations are seconds, optionally followed
+/// by `s`.
+fn parse_ramp(s: &str) -> Result<pipe::Ramp, String> {
+    let parts: Vec<&str> = s.split(':').collect();
+    let (from, to, duration, hold) = match parts[..] {
+        [from, to, duration] => (from, to, duration, None),
+        [from, to, duration, hold] => (from, to, duration, Some(hold)),
+        _ => return Err("expected FROM:TO:DURATION[:HOLD]".to_string()),
+    };
+    let seconds = |s: &str| parse_seconds(s.strip_suffix('s').unwrap_or(s));
+    Ok(pipe::Ramp {
+        from: parse_max_pps(from)?,
+        to: parse_max_pps(to)?,
+        duration: seconds(duration)?,
+        hold: hold.map(seconds).transpose()?,
+    })
+}
+
+/// Parses duration given as (fractional) number of milliseconds.
+fn parse_millis(s: &str) -> Result<Duration, String> {
+    let ms: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(ms / 1000.0).map_err(|e| format!("{e}"))
+}
+
+/// Returns watermark given in environment variable `name`, if it is set.
+/// Returns error if the value is not a valid number.
+fn env_watermark(name: &str) -> Result<Option<u64>, String> {
+    match std::env::var(name) {
+        Ok(val) => val
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("invalid value \"{val}\" in {name}: {e}")),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(format!("invalid value in {name}: {e}")),
+    }
+}
+
+/// Parses transmit queue number, which is used as socket priority and thus
+/// limited to the 16 traffic classes `mqprio` supports.
+fn parse_tx_queue(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(queue) if queue < 16 => Ok(queue),
+        Ok(_) => Err("transmit queue must be between 0 and 15".to_string()),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses point in capture time, given either as seconds since epoch or
+/// as `+SECONDS` offset from the first packet.
+fn parse_time_point(s: &str) -> Result<input::TimePoint, String> {
+    match s.strip_prefix('+') {
+        Some(offset) => parse_seconds(offset).map(input::TimePoint::Offset),
+        None => parse_seconds(s).map(|d| input::TimePoint::Absolute(UNIX_EPOCH + d)),
+    }
+}
+
+/// Wall-clock time to start writing at.
+#[derive(Clone, Copy, Debug)]
+enum StartAt {
+    /// Given point in time.
+    Absolute(SystemTime),
+    /// Next occurrence of time of day in UTC, as offset from midnight.
+    Daily(Duration),
+}
+
+impl StartAt {
+    /// Returns the time to start at, when current time is `now`. Returns
+    /// error if absolute time has already passed.
+    fn resolve(&self, now: SystemTime) -> Result<SystemTime, String> {
+        match *self {
+            StartAt::Absolute(when) => match now.duration_since(when) {
+                Ok(ago) if !ago.is_zero() => Err(format!(
+                    "start time is {:.3}s in the past, use time of day (HH:MM[:SS]) to start at its next occurrence",
+                    ago.as_secs_f64()
+                )),
+                _ => Ok(when),
+            },
+            StartAt::Daily(time) => {
+                let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let today = UNIX_EPOCH
+                    + Duration::from_secs(since_epoch.as_secs() / SECS_PER_DAY * SECS_PER_DAY)
+                    + time;
+                if today > now {
+                    Ok(today)
+                } else {
+                    Ok(today + Duration::from_secs(SECS_PER_DAY))
+                }
+            }
+        }
+    }
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Returns the number of days from 1970-01-01 to given date in the
+/// proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Returns the number of days in `month` of `year`.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parses time of day `HH:MM[:SS]`, seconds may be fractional. Returns the
+/// time as offset from midnight.
+fn parse_time_of_day(s: &str) -> Result<Duration, String> {
+    let parts: Vec<&str> = s.split(':').collect();
+    let (hours, minutes, seconds) = match parts[..] {
+        [h, m] => (h, m, "0"),
+        [h, m, sec] => (h, m, sec),
+        _ => return Err(format!("invalid time of day {s}, expected HH:MM[:SS]")),
+    };
+    let hours: u64 = hours.parse().map_err(|e| format!("{e}"))?;
+    let minutes: u64 = minutes.parse().map_err(|e| format!("{e}"))?;
+    let seconds: f64 = seconds.parse().map_err(|e| format!("{e}"))?;
+    if hours > 23 || minutes > 59 || !(0.0..60.0).contains(&seconds) {
+        return Err(format!("invalid time of day {s}"));
+    }
+    Ok(Duration::from_secs(hours * 3600 + minutes * 60) + Duration::from_secs_f64(seconds))
+}
+
+/// Parses UTC offset `+HH:MM` or `-HH:MM` to seconds.
+fn parse_utc_offset(s: &str) -> Result<i64, String> {
+    let (sign, rest) = match s.split_at(1) {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return Err(format!("invalid UTC offset {s}")),
+    };
+    let Some((hours, minutes)) = rest.split_once(':') else {
+        return Err(format!("invalid UTC offset {s}, expected +HH:MM"));
+    };
+    let hours: i64 = hours.parse().map_err(|e| format!("{e}"))?;
+    let minutes: i64 = minutes.parse().map_err(|e| format!("{e}"))?;
+    if hours > 23 || minutes > 59 {
+        return Err(format!("invalid UTC offset {s}"));
+    }
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Parses start time given either as date and time
+/// `YYYY-MM-DDTHH:MM[:SS]`, followed by `Z` or UTC offset (UTC if neither
+/// is given), or as UTC time of day `HH:MM[:SS]`.
+fn parse_start_at(s: &str) -> Result<StartAt, String> {
+    let Some((date, time)) = s.split_once(['T', 't', ' ']) else {
+        return parse_time_of_day(s).map(StartAt::Daily);
+    };
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else if let Some(pos) = time.rfind(['+', '-']) {
+        let (time, offset) = time.split_at(pos);
+        (time, parse_utc_offset(offset)?)
+    } else {
+        (time, 0)
+    };
+    let time = parse_time_of_day(time)?;
+    let fields = date
+        .split('-')
+        .map(|f| f.parse::<i64>().map_err(|e| format!("{e}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [year, month, day] = fields[..] else {
+        return Err(format!("invalid date {date}, expected YYYY-MM-DD"));
+    };
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return Err(format!("invalid date {date}"));
+    }
+    let secs = days_from_civil(year, month, day) * SECS_PER_DAY as i64 - offset;
+    let secs = u64::try_from(secs).map_err(|_| format!("{s} is before epoch"))?;
+    Ok(StartAt::Absolute(
+        UNIX_EPOCH + Duration::from_secs(secs) + time,
+    ))
+}
+
+/// Parses size in bytes, optionally with `K`, `M` or `G` suffix (with or
+/// without trailing `B`) for kilo-, mega- or gigabytes (powers of 1024).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_ascii_uppercase();
+    let num = upper.strip_suffix('B').unwrap_or(&upper);
+    let (num, mult) = match num.as_bytes().last() {
+        Some(b'K') => (&num[..num.len() - 1], 1024),
+        Some(b'M') => (&num[..num.len() - 1], 1024 * 1024),
+        Some(b'G') => (&num[..num.len() - 1], 1024 * 1024 * 1024),
+        _ => (num, 1),
+    };
+    let val: u64 = num.trim().parse().map_err(|e| format!("{e}"))?;
+    val.checked_mul(mult)
+        .ok_or_else(|| "size is too large".to_string())
+}
+
+/// Parses MAC address given as six colon separated hex octets.
+fn parse_mac(s: &str) -> Result<[u8; 6], String> {
+    let mut mac = [0u8; 6];
+    let mut octets = s.split(':');
+    for b in mac.iter_mut() {
+        let octet = octets.next().ok_or("too few octets in MAC address")?;
+        *b = u8::from_str_radix(octet, 16).map_err(|e| format!("{e}"))?;
+    }
+    if octets.next().is_some() {
+        return Err("too many octets in MAC address".to_string());
+    }
+    Ok(mac)
+}
+
+/// Parses VLAN ID, which needs to be between 0 and 4094.
+fn parse_vlan_id(s: &str) -> Result<u16, String> {
+    match s.parse() {
+        Ok(id) if id <= 4094 => Ok(id),
+        Ok(_) => Err("VLAN ID must be between 0 and 4094".to_string()),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses IPv4 network given as `ADDRESS/PREFIXLEN`.
+fn parse_ipv4_net(s: &str) -> Result<(Ipv4Addr, u8), String> {
+    let (addr, len) = s.split_once('/').unwrap_or((s, "32"));
+    let addr: Ipv4Addr = addr.parse().map_err(|e| format!("{e}"))?;
+    let len: u8 = len.parse().map_err(|e| format!("{e}"))?;
+    if len > 32 {
+        return Err(format!("invalid prefix length {len}"));
+    }
+    Ok((addr, len))
+}
+
+/// Parses IPv6 network given as `ADDRESS/PREFIXLEN`.
+fn parse_ipv6_net(s: &str) -> Result<(Ipv6Addr, u8), String> {
+    let (addr, len) = s.split_once('/').unwrap_or((s, "128"));
+    let addr: Ipv6Addr = addr.parse().map_err(|e| format!("{e}"))?;
+    let len: u8 = len.parse().map_err(|e| format!("{e}"))?;
+    if len > 128 {
+        return Err(format!("invalid prefix length {len}"));
+    }
+    Ok((addr, len))
+}
+
+/// Parses IPv6 address mapping given as `FROM/LEN=TO/LEN`.
+fn parse_ipv6_map(s: &str) -> Result<rewrite::Ipv6Mapping, String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or("mapping needs to be given as FROM/LEN=TO/LEN")?;
+    let (from, from_len) = parse_ipv6_net(from)?;
+    let (to, to_len) = parse_ipv6_net(to)?;
+    Ok(rewrite::Ipv6Mapping::new(from, from_len, to, to_len))
+}
+
+/// Parses IPv6 flow label, given either as decimal or hexadecimal number
+/// prefixed with `0x`. Flow label is 20 bits long.
+fn parse_flow_label(s: &str) -> Result<u32, String> {
+    let label = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("{e}"))?;
+    if label > 0xfffff {
+        return Err("flow label must be at most 0xfffff".to_string());
+    }
+    Ok(label)
+}
+
+/// Parses IPv4 address mapping given as `FROM/LEN=TO/LEN`.
+fn parse_ip_map(s: &str) -> Result<rewrite::Ipv4Mapping, String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or("mapping needs to be given as FROM/LEN=TO/LEN")?;
+    let (from, from_len) = parse_ipv4_net(from)?;
+    let (to, to_len) = parse_ipv4_net(to)?;
+    Ok(rewrite::Ipv4Mapping::new(from, from_len, to, to_len))
+}
+
+/// Parses speed multiplier, which needs to be larger than zero.
+fn parse_speed(s: &str) -> Result<f64, String> {
+    let speed: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if speed > 0.0 && speed.is_finite() {
+        Ok(speed)
+    } else {
+        Err("speed multiplier must be larger than 0".to_string())
+    }
+}
+
+/// Parses percentage between 0 and 100, returning it as fraction.
+fn parse_percent(s: &str) -> Result<f64, String> {
+    let pct: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0.0..=100.0).contains(&pct) {
+        Ok(pct / 100.0)
+    } else {
+        Err("percentage must be between 0 and 100".to_string())
+    }
+}
+
+/// Parses reorder window size, which needs to be at least two packets.
+fn parse_window(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(n) if n < 2 => Err("window must contain at least 2 packets".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses real-time scheduling priority, which needs to be between 1 and
+/// 99.
+fn parse_rt_priority(s: &str) -> Result<i32, String> {
+    match s.parse::<i32>() {
+        Ok(p) if (1..=99).contains(&p) => Ok(p),
+        Ok(_) => Err("priority must be between 1 and 99".to_string()),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses batch size, which needs to be larger than zero.
+fn parse_batch(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("batch size must be larger than 0".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses number of times to write each packet, which needs to be larger
+/// than zero.
+fn parse_repeat(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(0) => Err("repeat count must be larger than 0".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses maximum packet rate, which needs to be larger than zero.
+fn parse_max_pps(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(0) => Err("packet rate must be larger than 0".to_string()),
+        Ok(pps) => Ok(pps),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses number of packets in each output file, which needs to be larger
+/// than zero.
+fn parse_rotate_packets(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(0) => Err("packet count must be larger than 0".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses maximum packet length, which needs to be larger than zero.
+fn parse_snaplen(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("snaplen must be larger than 0".to_string()),
+        Ok(len) => Ok(len),
+        Err(e) => Err(format!("{e}")),
+    }
 }
 
 impl RateParam {
@@ -204,8 +861,12 @@ impl RateParam {
             Rate::Mbps((mbps * 1_000_000_f32) as u64)
         } else if self.fullspeed {
             Rate::Full
+        } else if let Some(gap) = self.gap {
+            Rate::Gap(Duration::from_micros(gap))
+        } else if let Some(ramp) = self.ramp {
+            Rate::Ramp(ramp)
         } else {
-            Rate::Delayed
+            Rate::Delayed(self.speed.unwrap_or(1.0))
         }
     }
 }
@@ -216,41 +877,566 @@ impl RateParam {
 struct Params {
     #[command[flatten]]
     input: InputParam,
+    /// List interfaces available for reading and writing packets and exit
+    #[arg(long, exclusive = true)]
+    list_interfaces: bool,
+    /// Read through the input files and print summary of each without
+    /// replaying, exit with error if any of them is damaged
+    #[arg(long, conflicts_with_all = ["interface", "listen"])]
+    validate: bool,
+    /// Read only packets matching given BPF filter expression from interface
+    #[arg(long, requires = "interface")]
+    filter: Option<String>,
+    /// Do not read packets matching given BPF filter expression from
+    /// interface, even if they match --filter
+    #[arg(long, value_name = "EXPR", requires = "interface")]
+    filter_out: Option<String>,
+    /// Do not put the input interface into promiscuous mode
+    #[arg(long, requires = "interface")]
+    no_promisc: bool,
+    /// Let libpcap buffer packets read from interface instead of delivering
+    /// them immediately
+    #[arg(long, requires = "interface")]
+    no_immediate: bool,
+    /// Milliseconds to wait for packets from interface before checking if
+    /// reading should stop (default 100)
+    #[arg(long, value_name = "MS", value_parser = parse_millis, requires = "interface")]
+    read_timeout: Option<Duration>,
+    /// Capture at most given number of bytes of each packet read from
+    /// interface (default 262144, full packets)
+    #[arg(long, value_name = "BYTES", value_parser = parse_snaplen, requires = "interface")]
+    capture_snaplen: Option<usize>,
     #[command(flatten)]
     rate: RateParam,
-    /// Name of the interface to inject packets into. If not given, packets
-    /// are written into /dev/null
+    /// Name of the interface to inject packets into. Can be given multiple
+    /// times to write every packet to all given interfaces, or `-` to write
+    /// packets to stdout. If not given, packets are discarded
     #[arg(short, long)]
-    output: Option<String>,
+    output: Vec<String>,
+    /// Method used for writing packets to output interface
+    #[arg(long, value_enum, default_value_t)]
+    output_backend: OutputBackend,
+    /// Format of packets written to stdout with `--output -`
+    #[arg(long, value_enum, default_value_t)]
+    output_format: OutputFormat,
+    /// Size of the send buffer of output interface socket, for example
+    /// `4M`. Needs raw or txtime output backend
+    #[arg(long, value_name = "BYTES", value_parser = parse_size, requires = "output")]
+    output_buffer_size: Option<u64>,
+    /// Write packets to given transmit queue of output interface. Needs raw
+    /// or txtime output backend and mqprio qdisc on the interface
+    #[arg(long, value_name = "QUEUE", value_parser = parse_tx_queue, requires = "output")]
+    tx_queue: Option<u32>,
+    /// Write packets into pcap file with given name instead of interface
+    #[arg(long, conflicts_with = "output")]
+    output_file: Option<String>,
+    /// Start a new output file after given number of packets
+    #[arg(
+        long,
+        requires = "output_file",
+        conflicts_with = "rotate_seconds",
+        value_parser = parse_rotate_packets
+    )]
+    rotate_packets: Option<u64>,
+    /// Start a new output file after given number of seconds
+    #[arg(long, requires = "output_file", value_parser = parse_seconds)]
+    rotate_seconds: Option<Duration>,
+    /// Send each packet as UDP datagram to given address instead of
+    /// interface
+    #[arg(long, value_name = "ADDR:PORT", conflicts_with_all = ["output", "output_file"])]
+    output_udp: Option<SocketAddr>,
+    /// Stream packets over TCP to another pktreplay listening on given
+    /// address with --listen, which writes them
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        conflicts_with_all = ["output", "output_file", "output_udp"]
+    )]
+    output_tcp: Option<SocketAddr>,
+    /// Record packets written to output interface into pcap file with given
+    /// name
+    #[arg(long, requires = "output")]
+    tee_file: Option<String>,
+    /// Capture packets from given interface while writing and check that
+    /// the packets written to output interface are received there
+    #[arg(long, value_name = "INTERFACE", requires = "output")]
+    selftest: Option<String>,
+    /// Read and time packets as selected by rate options, but do not write
+    /// them anywhere
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "output_file", "output_udp", "output_tcp"]
+    )]
+    dry_run: bool,
     /// Loop pcap file instead of stopping when all packets are read
     #[arg[short, long="loop"]]
     looping: bool,
-    /// Low watermark for packet buffer
+    /// Loop pcap file given number of times, 0 loops until terminated
+    #[arg(long)]
+    loop_count: Option<u64>,
+    /// Print `loop iteration=N packets=M` line to stdout when each iteration
+    /// over the input has been written
+    #[arg(long)]
+    loop_marker: bool,
+    /// When looping with original timing, wait given number of milliseconds
+    /// between the last packet and the first packet of the next iteration
+    #[arg(long, value_parser = parse_millis)]
+    loop_gap: Option<Duration>,
+    /// When reading multiple files, keep the time between the last packet of
+    /// a file and the first packet of the next file
+    #[arg(long)]
+    preserve_gaps: bool,
+    /// When reading multiple files, replay packets from all files in
+    /// timestamp order
+    #[arg(long, conflicts_with = "preserve_gaps")]
+    merge: bool,
+    /// Low watermark for packet buffer, defaults to PKTREPLAY_LOW
+    /// environment variable if set
     #[arg[short = 'L', long]]
     low: Option<u64>,
-    /// High watermark for packet buffer
+    /// High watermark for packet buffer, defaults to PKTREPLAY_HIGH
+    /// environment variable if set
     #[arg(short = 'H', long)]
     high: Option<u64>,
+    /// Unit for low and high watermarks
+    #[arg(long, value_enum, default_value_t)]
+    watermark_unit: WatermarkUnit,
+    /// Buffer packets in a lock-free ring holding high watermark number of
+    /// packets, writer blocks only while the ring is full
+    #[arg(long, conflicts_with_all = ["low", "watermark_unit"])]
+    ring: bool,
     /// Stop replaying after given number of packets have been replayed
     #[arg[short, long]]
     count: Option<usize>,
+    /// Replay only given number of last packets. The packets are kept in
+    /// memory until the whole input has been read
+    #[arg(long, conflicts_with = "interface")]
+    tail: Option<usize>,
+    /// Stop replaying after given number of bytes have been replayed
+    #[arg(long)]
+    bytes: Option<u64>,
+    /// Stop replaying after given number of seconds, also when looping
+    #[arg(long, value_parser = parse_seconds)]
+    max_duration: Option<Duration>,
+    /// Wait until given wall-clock time before writing the first packet.
+    /// Time is given as date and time like `2024-01-01T12:00:00Z`, which
+    /// must not be in the past, or as UTC time of day `HH:MM[:SS]` to start
+    /// at its next occurrence
+    #[arg(long, value_parser = parse_start_at)]
+    start_at: Option<StartAt>,
+    /// Skip packets captured before given time. Time is given as seconds
+    /// since epoch, or as `+SECONDS` offset from the first packet
+    #[arg(long, value_parser = parse_time_point)]
+    start_time: Option<input::TimePoint>,
+    /// Stop replaying when packet captured after given time is seen. Time
+    /// is given as seconds since epoch, or as `+SECONDS` offset from the
+    /// first packet
+    #[arg(long, value_parser = parse_time_point)]
+    end_time: Option<input::TimePoint>,
     /// Print statistics with interval of given number of seconds
     #[arg[short='S', long]]
     stats: Option<u64>,
+    /// Write periodic statistics also to clients connected to Unix domain
+    /// socket at given path
+    #[arg(long, value_name = "PATH", requires = "stats")]
+    stats_socket: Option<PathBuf>,
+    /// Append every periodic statistics sample as CSV row to given file
+    #[arg(long, value_name = "PATH", requires = "stats")]
+    stats_csv: Option<PathBuf>,
+    /// Do not count packets written during given number of seconds from
+    /// start in the statistics
+    #[arg(long, value_parser = parse_seconds)]
+    warmup: Option<Duration>,
+    /// Where packet timestamps are taken from
+    #[arg(long, value_enum, default_value_t)]
+    timestamp_source: TimestampSource,
+    /// Maximum delay between packets, in seconds, when packets are replayed
+    /// with their original rate
+    #[arg(long, value_parser = parse_seconds)]
+    max_gap: Option<Duration>,
+    /// Allow bursts of given size (for example `64KB`) when replaying with
+    /// given megabits per second
+    #[arg(long, requires = "mbps", value_parser = parse_size)]
+    burst: Option<u64>,
+    /// Never write more than given number of packets per second, for
+    /// example when replaying with original rate
+    #[arg(long, conflicts_with = "pps", value_parser = parse_max_pps)]
+    max_pps: Option<u32>,
+    /// With --pps or --mbps, do not write packets without delay to catch up
+    /// the rate after writing has stalled
+    #[arg(long)]
+    no_catchup: bool,
+    /// Busy-wait the last millisecond before each packet is due instead of
+    /// sleeping, for more precise timing at the cost of CPU time
+    #[arg(long)]
+    spin: bool,
+    /// Write up to given number of packets at once when they are due
+    /// without delay
+    #[arg(long, default_value_t = 1, value_parser = parse_batch)]
+    batch: usize,
+    /// Pin the thread reading packets to given CPU (Linux only)
+    #[arg(long, value_name = "CPU")]
+    reader_cpu: Option<usize>,
+    /// Pin the thread writing packets to given CPU (Linux only)
+    #[arg(long, value_name = "CPU")]
+    writer_cpu: Option<usize>,
+    /// Run the thread writing packets with real-time SCHED_FIFO scheduling
+    /// and given priority (Linux only)
+    #[arg(long, value_name = "PRIORITY", value_parser = parse_rt_priority)]
+    writer_rt_priority: Option<i32>,
+    /// Seed for all randomized features, the same seed with the same input
+    /// reproduces the same packets. Seeds of single features override it
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Add random delay of up to given number of milliseconds before each
+    /// packet
+    #[arg(long, value_parser = parse_millis)]
+    jitter: Option<Duration>,
+    /// Seed for the random delays added with --jitter
+    #[arg(long, requires = "jitter")]
+    jitter_seed: Option<u64>,
+    /// Drop given percentage of packets at random instead of writing them
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    drop_rate: Option<f64>,
+    /// Seed for selecting the packets dropped with --drop-rate
+    #[arg(long, requires = "drop_rate")]
+    drop_seed: Option<u64>,
+    /// Write given percentage of packets twice, chosen at random
+    #[arg(long, value_name = "PERCENT", value_parser = parse_percent)]
+    dup_rate: Option<f64>,
+    /// Seed for selecting the packets duplicated with --dup-rate
+    #[arg(long, requires = "dup_rate")]
+    dup_seed: Option<u64>,
+    /// Write packets in random order within window of given number of
+    /// packets
+    #[arg(long, value_name = "WINDOW", value_parser = parse_window)]
+    reorder: Option<usize>,
+    /// Seed for reordering packets with --reorder
+    #[arg(long, requires = "reorder")]
+    reorder_seed: Option<u64>,
+    /// Write every packet given number of times in a row
+    #[arg(long, value_name = "N", value_parser = parse_repeat)]
+    repeat_each: Option<u32>,
+    /// With --repeat-each, wait before each copy as before any other packet
+    /// instead of writing the copies right after the packet
+    #[arg(long, requires = "repeat_each")]
+    repeat_paced: bool,
+    /// Convert Linux cooked capture (SLL) packets to Ethernet frames with
+    /// given destination MAC address
+    #[arg(long, value_name = "DST_MAC", value_parser = parse_mac)]
+    sll_to_ethernet: Option<[u8; 6]>,
+    /// Rewrite source MAC address of packets to given address
+    #[arg(long, value_parser = parse_mac)]
+    src_mac: Option<[u8; 6]>,
+    /// Rewrite destination MAC address of packets to given address
+    #[arg(long, value_parser = parse_mac)]
+    dst_mac: Option<[u8; 6]>,
+    /// Remove the outermost VLAN tag from tagged packets
+    #[arg(long)]
+    strip_vlan: bool,
+    /// Add VLAN tag with given VLAN ID to packets, as the outermost tag
+    #[arg(long, value_name = "ID", value_parser = parse_vlan_id)]
+    add_vlan: Option<u16>,
+    /// Rewrite IPv4 addresses in network FROM/LEN to network TO/LEN. Can be
+    /// given multiple times
+    #[arg(long, value_name = "FROM/LEN=TO/LEN", value_parser = parse_ip_map)]
+    ip_map: Vec<rewrite::Ipv4Mapping>,
+    /// Anonymize IPv4 and IPv6 addresses with prefix-preserving
+    /// anonymization keyed with --anon-key
+    #[arg(long, requires = "anon_key")]
+    anonymize: bool,
+    /// Key for --anonymize, runs with the same key anonymize addresses the
+    /// same way
+    #[arg(long, value_name = "KEY", requires = "anonymize")]
+    anon_key: Option<String>,
+    /// Recompute IPv4 header checksum and TCP and UDP checksums of IPv4
+    /// packets before writing them
+    #[arg(long)]
+    fix_checksums: bool,
+    /// Rewrite IPv6 addresses in network FROM/LEN to network TO/LEN. Can be
+    /// given multiple times
+    #[arg(long, value_name = "FROM/LEN=TO/LEN", value_parser = parse_ipv6_map)]
+    ipv6_map: Vec<rewrite::Ipv6Mapping>,
+    /// Set flow label of IPv6 packets to given value
+    #[arg(long, value_name = "LABEL", value_parser = parse_flow_label)]
+    flow_label: Option<u32>,
+    /// Truncate packets longer than given number of bytes before writing
+    #[arg(long, value_parser = parse_snaplen)]
+    snaplen: Option<usize>,
+    /// Write final statistics into file with given name
+    #[arg(long)]
+    stats_file: Option<String>,
+    /// Exit with code 2 if any packets could not be sent, or with
+    /// --selftest were not received back
+    #[arg(long)]
+    strict: bool,
+    /// Exit with error before replaying if the file contains packets larger
+    /// than MTU of output interface
+    #[arg(long, requires = "output")]
+    strict_mtu: bool,
+    /// Replay even if link type of the input differs from the output
+    #[arg(long)]
+    force: bool,
+    /// Do not print final statistics, log only errors to stderr unless
+    /// RUST_LOG is set
+    #[arg(short, long)]
+    quiet: bool,
+    /// Include histogram of written packet sizes in statistics
+    #[arg(long)]
+    size_histogram: bool,
+    /// Include packet counts by network and transport protocol in statistics
+    #[arg(long)]
+    classify: bool,
+    /// Serve Prometheus metrics at given address
+    #[arg(long, value_name = "ADDR:PORT")]
+    metrics_addr: Option<SocketAddr>,
+}
+
+/// Reads through each of `files` (`-` for standard input) and prints
+/// summary of its packets. Returns `false` if any of the files can not be
+/// read or is damaged.
+fn validate(files: &[String]) -> bool {
+    let never = AtomicBool::new(false);
+    let mut valid = true;
+    for name in files {
+        let input = if name == "-" {
+            input::pcap_stdin()
+        } else {
+            input::pcap_file(name)
+        };
+        let res = input.and_then(|input| {
+            let summary = input::summarize(input.packets(&never)?);
+            Ok((summary, input.link_type(), input.damage()))
+        });
+        match res {
+            Ok((summary, link_type, damage)) => {
+                println!(
+                    "{}: {} packets, {} bytes, {:.6}s, {}",
+                    name,
+                    summary.packets,
+                    summary.bytes,
+                    summary.duration().as_secs_f64(),
+                    link_type.map_or("unknown link type".to_string(), linktype::name)
+                );
+                if let Some(damage) = damage {
+                    println!("{name}: {damage}");
+                    valid = false;
+                }
+            }
+            Err(e) => {
+                println!("{name}: unable to read: {e:#}");
+                valid = false;
+            }
+        }
+    }
+    valid
+}
+
+/// Prints interfaces available for reading and writing packets, with their
+/// description, state and IP addresses.
+fn list_interfaces() -> anyhow::Result<()> {
+    for iface in luomu_libpcap::get_interfaces()? {
+        let mut line = iface.name.clone();
+        if let Some(ref desc) = iface.description {
+            line.push_str(&format!(": {desc}"));
+        }
+        let flags: Vec<&str> = [
+            (InterfaceFlag::Up, "up"),
+            (InterfaceFlag::Running, "running"),
+            (InterfaceFlag::Loopback, "loopback"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| iface.flags.contains(flag))
+        .map(|(_, name)| name)
+        .collect();
+        if !flags.is_empty() {
+            line.push_str(&format!(" [{}]", flags.join(", ")));
+        }
+        println!("{line}");
+        for addr in &iface.addresses {
+            match addr.addr {
+                Address::Ipv4(ip) => println!("    {ip}"),
+                Address::Ipv6(ip) => println!("    {ip}"),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Initializes logging. Logs are written to stderr to keep stdout for
+/// statistics and packets written to stdout. With `quiet`, only errors are
+/// logged unless `RUST_LOG` says otherwise.
+fn init_logging(quiet: bool) {
+    let filter = if quiet {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"))
+    } else {
+        EnvFilter::from_default_env()
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
 }
 
 fn main() {
-    tracing_subscriber::fmt::init();
     let params = Params::parse();
-    let method = params.input.method();
+    init_logging(params.quiet);
+    if params.list_interfaces {
+        if let Err(e) = list_interfaces() {
+            tracing::error!("Unable to list interfaces: {}", e);
+            std::process::exit(-1);
+        }
+        return;
+    }
+    if params.validate {
+        if !validate(&params.input.file) {
+            std::process::exit(-1);
+        }
+        return;
+    }
+    // packets written to stdout must not be mixed with other output there
+    let to_stdout = params.output.iter().any(|name| name == "-");
+    if to_stdout && params.loop_marker {
+        tracing::error!("--loop-marker can not be used when writing packets to stdout");
+        std::process::exit(-1);
+    }
+    let start_at = match params.start_at.map(|s| s.resolve(SystemTime::now())) {
+        Some(Ok(when)) => Some(when),
+        Some(Err(e)) => {
+            tracing::error!("invalid --start-at: {}", e);
+            std::process::exit(-1);
+        }
+        None => None,
+    };
+    let mut capture = input::CaptureOptions {
+        promiscuous: !params.no_promisc,
+        immediate: !params.no_immediate,
+        ..Default::default()
+    };
+    if let Some(timeout) = params.read_timeout {
+        capture.read_timeout = timeout;
+    }
+    if let Some(len) = params.capture_snaplen {
+        capture.snaplen = len;
+    }
+    let method = params
+        .input
+        .method(params.filter.clone(), params.filter_out.clone(), capture);
+    let is_file = matches!(method, InputMethod::File(_));
     let mut rate = params.rate.get_rate();
+    if let (Rate::Mbps(bps), Some(burst)) = (&rate, params.burst) {
+        rate = Rate::Burst(*bps, burst);
+    }
 
-    let ch_hi: u64 = params.high.unwrap_or(100);
-    let ch_low = params.low.unwrap_or(ch_hi / 2);
-    if ch_low >= ch_hi {
-        tracing::error!("packet buffer low watermark can not be larger than high");
-        std::process::exit(-1);
+    // --loop-count 0 loops forever, just like --loop
+    let looping = params.looping || params.loop_count.is_some();
+    let passes = if looping {
+        params.loop_count.filter(|n| *n > 0)
+    } else {
+        Some(1)
+    };
+
+    let unit = match params.watermark_unit {
+        WatermarkUnit::Packets => channel::Unit::Packets,
+        WatermarkUnit::Bytes => channel::Unit::Bytes,
+    };
+    let limits = Limits {
+        passes,
+        start: params.start_time,
+        end: params.end_time,
+        tail: params.tail,
+        count: params.count,
+        bytes: params.bytes,
+        loop_gap: params.loop_gap.unwrap_or_default(),
+        // reading starts right away, writing only at the start time
+        duration: params.max_duration.map(|d| {
+            d + start_at
+                .and_then(|t| t.duration_since(SystemTime::now()).ok())
+                .unwrap_or_default()
+        }),
+    };
+    let combine = if params.merge {
+        input::Combine::Merge
+    } else {
+        input::Combine::Sequence {
+            preserve_gaps: params.preserve_gaps,
+        }
+    };
+    let timestamps = match params.timestamp_source {
+        TimestampSource::Capture => input::TimestampSource::Capture,
+        TimestampSource::Arrival => input::TimestampSource::Arrival,
+    };
+    // command line overrides the defaults from environment, ring has no
+    // low watermark
+    let high = match params.high {
+        Some(high) => Ok(Some(high)),
+        None => env_watermark("PKTREPLAY_HIGH"),
+    };
+    let low = match params.low {
+        Some(low) => Ok(Some(low)),
+        None if params.ring => Ok(None),
+        None => env_watermark("PKTREPLAY_LOW"),
+    };
+    let (high, low) = match (high, low) {
+        (Ok(high), Ok(low)) => (high, low),
+        (Err(e), _) | (_, Err(e)) => {
+            tracing::error!("{}", e);
+            std::process::exit(-1);
+        }
+    };
+    let replayer = Replayer::builder(method)
+        .combine(combine)
+        .rate(rate)
+        .watermarks(high, low)
+        .watermark_unit(unit)
+        .ring_buffer(params.ring)
+        .reader_cpu(params.reader_cpu)
+        .limits(limits)
+        .max_gap(params.max_gap)
+        .timestamp_source(timestamps)
+        .build();
+    let replayer = match replayer {
+        Ok(replayer) => replayer,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(-1);
+        }
+    };
+    let mut input_link = replayer.link_type();
+    if let Some(lt) = input_link {
+        tracing::info!("input link type is {}", linktype::name(lt));
+    }
+    if params.sll_to_ethernet.is_some() {
+        if let Some(lt) = input_link.filter(|lt| *lt != linktype::LINUX_SLL) {
+            tracing::error!(
+                "--sll-to-ethernet needs Linux cooked capture input, input link type is {}",
+                linktype::name(lt)
+            );
+            std::process::exit(-1);
+        }
+        // packets are Ethernet frames once converted
+        input_link = input_link.map(|_| linktype::ETHERNET);
+    }
+    let mut format = output::FileFormat::default();
+    if let Some(lt) = input_link {
+        format.link_type = lt;
+    }
+    if let Some(len) = replayer.snaplen() {
+        format.snaplen = len;
+    }
+    if let Some(len) = params.snaplen {
+        format.snaplen = format.snaplen.min(u32::try_from(len).unwrap_or(u32::MAX));
     }
+    // output is opened before the input is scanned, so that a mistyped
+    // interface name fails right away
+    let mut out = match create_output(&params, input_link, format) {
+        Ok(out) => out,
+        Err(e) => {
+            tracing::error!("{:#}", e);
+            std::process::exit(-1);
+        }
+    };
 
     let terminate = Arc::new(AtomicBool::from(false));
     if let Err(e) = flag::register(SIGINT, Arc::clone(&terminate)) {
@@ -261,39 +1447,181 @@ fn main() {
         tracing::error!("Unable to register signal handler: {e}");
         std::process::exit(-1);
     }
-
-    if matches!(method, InputMethod::Interface(_)) && matches!(rate, Rate::Delayed) {
-        // if no pps or bps options are defined and we are reading from interface
-        // force the --full which causes packets to be written to the output
-        // interface as soon as they are received, which is probably what
-        // users would expect.
-        rate = Rate::Full;
+    // SIGHUP resets the statistics
+    let reset_stats = Arc::new(AtomicBool::new(false));
+    if let Err(e) = flag::register(SIGHUP, Arc::clone(&reset_stats)) {
+        tracing::error!("Unable to register signal handler: {e}");
+        std::process::exit(-1);
+    }
+    // SIGUSR1 pauses and SIGUSR2 resumes writing
+    let pause = Arc::new(AtomicUsize::new(0));
+    for (sig, value) in [(SIGUSR1, 1), (SIGUSR2, 0)] {
+        if let Err(e) = flag::register_usize(sig, Arc::clone(&pause), value) {
+            tracing::error!("Unable to register signal handler: {e}");
+            std::process::exit(-1);
+        }
     }
 
-    let (tx, rx) = channel::create(ch_hi, ch_low, terminate.clone());
     let stat_period = params.stats.map(Duration::from_secs);
-    let (stats, stat_printer) = if let Some(period) = stat_period {
-        let (s, r) = pipe::Stats::periodic(period);
-        (s, Some(start_printer_task(r)))
+    let mut csv_writer = None;
+    let (mut stats, stat_printer) = if let Some(period) = stat_period {
+        let socket = match params.stats_socket.as_deref().map(statsock::start_server) {
+            Some(Ok(socket)) => Some(socket),
+            Some(Err(e)) => {
+                tracing::error!(
+                    "Unable to serve statistics at {}: {}",
+                    params.stats_socket.as_ref().unwrap().display(),
+                    e
+                );
+                std::process::exit(-1);
+            }
+            None => None,
+        };
+        let (mut s, r) = pipe::Stats::periodic(period);
+        if let Some(ref path) = params.stats_csv {
+            match start_csv_task(s.samples(), path) {
+                Ok(handle) => csv_writer = Some(handle),
+                Err(e) => {
+                    tracing::error!("Unable to write statistics to {}: {}", path.display(), e);
+                    std::process::exit(-1);
+                }
+            }
+        }
+        (s, Some(start_printer_task(r, to_stdout, socket)))
     } else {
         (pipe::Stats::default(), None)
     };
-    let p = if let Some(ref ifname) = params.output {
-        output::interface(ifname).and_then(|o| create_pipe(rate, rx, o, stats))
-    } else {
-        output::sink().and_then(|o| create_pipe(rate, rx, o, stats))
+    if let Some(warmup) = params.warmup {
+        stats.set_warmup(warmup);
+    }
+    if params.size_histogram {
+        stats.enable_size_histogram();
+    }
+    if params.classify {
+        stats.enable_classify();
+    }
+    if let Some(addr) = params.metrics_addr {
+        let counters = Arc::new(metrics::Counters::default());
+        if let Err(e) = metrics::start_server(addr, Arc::clone(&counters)) {
+            tracing::error!("Unable to serve metrics at {}: {}", addr, e);
+            std::process::exit(-1);
+        }
+        stats.set_metrics(counters);
+    }
+    if is_file {
+        // scan the file to report progress with the periodic statistics, to
+        // estimate how long replaying takes and to check the packets fit to
+        // output interfaces
+        let want_estimate = passes.is_some() && !matches!(replayer.rate(), Rate::Full);
+        let want_mtu = !params.output.is_empty() && !to_stdout;
+        if (stat_period.is_some() && !looping) || want_estimate || want_mtu {
+            match replayer.scan() {
+                Ok(summary) => {
+                    if stat_period.is_some() && !looping {
+                        let repeat = u64::from(params.repeat_each.unwrap_or(1));
+                        stats.set_total(summary.packets * repeat);
+                    }
+                    if let Some(d) = replayer.estimate_duration(&summary) {
+                        tracing::info!("estimated replay duration {:.1}s", d.as_secs_f64());
+                    }
+                    if want_mtu
+                        && !check_mtu(&params.output, &summary, params.snaplen)
+                        && params.strict_mtu
+                    {
+                        tracing::error!(
+                            "packets larger than output interface MTU with --strict-mtu"
+                        );
+                        std::process::exit(-1);
+                    }
+                }
+                Err(err) => tracing::warn!("Unable to read input: {}", err),
+            }
+        }
+    }
+    // randomized features without their own seed share one, which is logged
+    // so that the run can be reproduced with --seed
+    let seed = params.seed.unwrap_or_else(rng::Rng::time_seed);
+    let shares_seed = (params.jitter.is_some() && params.jitter_seed.is_none())
+        || (params.drop_rate.is_some() && params.drop_seed.is_none())
+        || (params.dup_rate.is_some() && params.dup_seed.is_none())
+        || (params.reorder.is_some() && params.reorder_seed.is_none());
+    if shares_seed {
+        tracing::info!("using seed {}", seed);
+    }
+    let opts = pipe::Options {
+        jitter: params.jitter.map(|max| {
+            let seed = feature_seed(params.jitter_seed, seed, JITTER_STREAM);
+            tracing::info!("using jitter seed {}", seed);
+            pipe::Jitter { max, seed }
+        }),
+        transforms: create_transforms(&params),
+        hooks: create_hooks(&params, seed),
+        snaplen: params.snaplen,
+        pause: Some(pause),
+        reset_stats: Some(reset_stats),
+        no_catchup: params.no_catchup,
+        spin: params.spin,
+        max_duration: params.max_duration,
+        start_at,
+        max_pps: params.max_pps,
+        batch: params.batch,
+        writer_cpu: params.writer_cpu,
+        writer_rt_priority: params.writer_rt_priority,
+        loop_markers: params.loop_marker.then(|| {
+            pipe::LoopMarkers::new(|iteration, packets| {
+                println!("loop iteration={} packets={}", iteration, packets)
+            })
+        }),
+        duplicate: params.dup_rate.map(|fraction| {
+            let seed = feature_seed(params.dup_seed, seed, DUP_STREAM);
+            tracing::info!("using duplicate seed {}", seed);
+            pipe::Duplicate { fraction, seed }
+        }),
+        reorder: params.reorder.map(|window| {
+            let seed = feature_seed(params.reorder_seed, seed, REORDER_STREAM);
+            tracing::info!("using reorder seed {}", seed);
+            pipe::Reorder { window, seed }
+        }),
+        repeat: params.repeat_each.map(|count| pipe::Repeat {
+            count,
+            paced: params.repeat_paced,
+        }),
     };
 
-    let ret = match p {
-        Ok(pipe) => input_task(method, params.looping, pipe, tx, terminate, params.count),
-        Err(e) => {
-            tracing::error!("{}", e);
-            -1
+    let report = Report {
+        stats_file: params.stats_file.as_deref(),
+        strict: params.strict,
+        quiet: params.quiet,
+        stderr: to_stdout,
+    };
+    // capture is started before writing, so that no packets are missed
+    let selftest = match params.selftest.as_deref().map(selftest::start) {
+        Some(Ok(test)) => Some(test),
+        Some(Err(e)) => {
+            tracing::error!("{:#}", e);
+            std::process::exit(-1);
         }
+        None => None,
     };
+    if let Some(ref test) = selftest {
+        out = Box::new(test.writer(out));
+    }
+    let mut outcome = replayer
+        .output(out)
+        .stats(stats)
+        .options(opts)
+        .terminate(terminate)
+        .run();
+    if let Some(test) = selftest {
+        outcome.stats.set_selftest(test.finish(SELFTEST_GRACE));
+    }
+    let ret = report.finish(outcome);
     // wait for stat printer to terminate
     if let Some(handle) = stat_printer {
         handle.join().unwrap();
     }
+    if let Some(handle) = csv_writer {
+        handle.join().unwrap();
+    }
     std::process::exit(ret);
 }
@@ -0,0 +1,131 @@
+//! Prometheus metrics endpoint.
+//!
+//! Serves counters of written packets over plain HTTP at `/metrics`. The
+//! server handles one request at a time, which is plenty for scraping.
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest time to wait for reading the request or writing the response.
+/// Requests are served one at a time, so a client not sending its request
+/// would otherwise block scraping.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters shared between packet writer and metrics server.
+#[derive(Default)]
+pub struct Counters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    invalid: AtomicU64,
+}
+
+impl Counters {
+    /// Sets the counters to given values.
+    pub fn set(&self, packets: u64, bytes: u64, invalid: u64) {
+        self.packets.store(packets, Ordering::Relaxed);
+        self.bytes.store(bytes, Ordering::Relaxed);
+        self.invalid.store(invalid, Ordering::Relaxed);
+    }
+}
+
+/// Counter values at the previous scrape, used for computing rates.
+struct Previous {
+    when: Instant,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Returns the metrics in Prometheus text format. Packet and bit rates are
+/// computed over the time since `prev`, which is updated.
+fn render(counters: &Counters, prev: &mut Previous) -> String {
+    let packets = counters.packets.load(Ordering::Relaxed);
+    let bytes = counters.bytes.load(Ordering::Relaxed);
+    let invalid = counters.invalid.load(Ordering::Relaxed);
+    let now = Instant::now();
+    let elapsed = now.duration_since(prev.when).as_secs_f64();
+    let (pps, bps) = if elapsed > 0.0 {
+        (
+            packets.saturating_sub(prev.packets) as f64 / elapsed,
+            bytes.saturating_sub(prev.bytes) as f64 * 8.0 / elapsed,
+        )
+    } else {
+        (0.0, 0.0)
+    };
+    *prev = Previous {
+        when: now,
+        packets,
+        bytes,
+    };
+    format!(
+        "# HELP pktreplay_packets_total Packets written.\n\
+         # TYPE pktreplay_packets_total counter\n\
+         pktreplay_packets_total {packets}\n\
+         # HELP pktreplay_bytes_total Bytes written.\n\
+         # TYPE pktreplay_bytes_total counter\n\
+         pktreplay_bytes_total {bytes}\n\
+         # HELP pktreplay_packets_not_sent_total Packets which could not be sent.\n\
+         # TYPE pktreplay_packets_not_sent_total counter\n\
+         pktreplay_packets_not_sent_total {invalid}\n\
+         # HELP pktreplay_packets_per_second Packet rate since previous scrape.\n\
+         # TYPE pktreplay_packets_per_second gauge\n\
+         pktreplay_packets_per_second {pps:.3}\n\
+         # HELP pktreplay_bits_per_second Bit rate since previous scrape.\n\
+         # TYPE pktreplay_bits_per_second gauge\n\
+         pktreplay_bits_per_second {bps:.3}\n"
+    )
+}
+
+/// Handles single HTTP request from `stream`.
+fn handle(stream: TcpStream, counters: &Counters, prev: &mut Previous) -> io::Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut rd = BufReader::new(&stream);
+    let mut request = String::new();
+    rd.read_line(&mut request)?;
+    // skip the headers
+    let mut line = String::new();
+    while rd.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(counters, prev);
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    (&stream).write_all(response.as_bytes())
+}
+
+/// Starts thread serving metrics from `counters` at given address. Returns
+/// error if the address can not be bound.
+pub fn start_server(addr: SocketAddr, counters: Arc<Counters>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            let mut prev = Previous {
+                when: Instant::now(),
+                packets: 0,
+                bytes: 0,
+            };
+            for stream in listener.incoming() {
+                let res = stream.and_then(|s| handle(s, &counters, &mut prev));
+                if let Err(err) = res {
+                    tracing::debug!(?err, "error while serving metrics");
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//! Queries for network interface properties.
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+/// Makes interface ioctl for interface with given name and returns the
+/// resulting `ifreq`. `call` makes the actual ioctl call with given socket
+/// and request structure.
+fn ifreq_ioctl<F>(ifname: &str, call: F) -> io::Result<libc::ifreq>
+where
+    F: FnOnce(RawFd, &mut libc::ifreq) -> libc::c_int,
+{
+    let name = CString::new(ifname)?;
+    let name = name.as_bytes_with_nul();
+    if name.len() > libc::IFNAMSIZ {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
+    // SAFETY: ifreq is plain old data, all zeroes is valid.
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+    // SAFETY: plain socket(2) call, returned descriptor is checked.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a valid descriptor we own.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if call(fd.as_raw_fd(), &mut req) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(req)
+}
+
+/// Returns the MTU of network interface with given name.
+pub fn mtu(ifname: &str) -> io::Result<usize> {
+    // SAFETY: req is valid ifreq containing NUL terminated interface name.
+    let req = ifreq_ioctl(ifname, |fd, req| unsafe {
+        libc::ioctl(fd, libc::SIOCGIFMTU, req as *mut libc::ifreq)
+    })?;
+    // SAFETY: successful SIOCGIFMTU sets ifru_mtu.
+    let mtu = unsafe { req.ifr_ifru.ifru_mtu };
+    Ok(mtu as usize)
+}
+
+/// Returns `true` if network interface with given name is up and its link
+/// is running.
+pub fn link_up(ifname: &str) -> io::Result<bool> {
+    // SAFETY: req is valid ifreq containing NUL terminated interface name.
+    let req = ifreq_ioctl(ifname, |fd, req| unsafe {
+        libc::ioctl(fd, libc::SIOCGIFFLAGS, req as *mut libc::ifreq)
+    })?;
+    // SAFETY: successful SIOCGIFFLAGS sets ifru_flags.
+    let flags = libc::c_int::from(unsafe { req.ifr_ifru.ifru_flags });
+    let wanted = libc::IFF_UP | libc::IFF_RUNNING;
+    Ok(flags & wanted == wanted)
+}
@@ -1,10 +1,20 @@
 //! Outputs for writing packets
-use crate::input::Packet;
+//!
+//! Packets can be written to network interface (using libpcap or, on Linux,
+//! raw socket), UDP socket, TCP connection, pcap -file, standard output or
+//! discarded.
+#[cfg(target_os = "linux")]
+use crate::rawsock;
+use crate::{input::Packet, linktype, netif, pcapfile, rewrite::ETH_HDR_LEN};
 use anyhow::Result;
 use luomu_libpcap::Pcap;
 use std::{
-    fs::{File, OpenOptions},
-    io::Write,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    path::Path,
+    time::{Duration, Instant, SystemTime},
 };
 
 /// PacketWriter can be used to write Packets or raw packet data.
@@ -12,60 +22,919 @@ pub trait PacketWriter {
     /// Writes raw packet data returning number of bytes written.
     fn write_raw(&mut self, buf: &[u8]) -> Result<usize>;
     /// Writes given [Packet] returning number of bytes written.
-    fn write_packet(&mut self, packet: Packet) -> Result<usize> {
+    ///
+    /// The default implementation writes only the packet data, writers
+    /// recording packets (such as pcap files) should override this to keep
+    /// the timestamp of the packet.
+    fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
         self.write_raw(&packet.data)
     }
+    /// Flushes any buffered packets. Called once all packets are written.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Returns the link type of packets this writer expects, if known. See
+    /// [linktype].
+    fn link_type(&self) -> Option<u32> {
+        None
+    }
+    /// Writes given packets, pushing the number of bytes written for each
+    /// packet into `written`. On error, `written` contains the lengths of
+    /// packets written before the error. The default implementation writes
+    /// packets one at a time, writers able to write multiple packets with
+    /// single system call should override this.
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        for packet in packets {
+            written.push(self.write_packet(packet)?);
+        }
+        Ok(())
+    }
+    /// Returns `true` if this writer can have packets transmitted at given
+    /// time with [PacketWriter::write_scheduled].
+    fn can_schedule(&self) -> bool {
+        false
+    }
+    /// Writes given [Packet] to be transmitted at `at`, returning number of
+    /// bytes written. The default implementation writes the packet
+    /// immediately.
+    fn write_scheduled(&mut self, packet: &Packet, at: Instant) -> Result<usize> {
+        let _ = at;
+        self.write_packet(packet)
+    }
 }
 
-/// Sink consuming all packets written to it.
-struct Sink(File);
+impl<W: PacketWriter + ?Sized> PacketWriter for Box<W> {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        (**self).write_raw(buf)
+    }
+
+    fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
+        (**self).write_packet(packet)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        (**self).link_type()
+    }
+
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        (**self).write_batch(packets, written)
+    }
 
-impl PacketWriter for Sink {
+    fn can_schedule(&self) -> bool {
+        (**self).can_schedule()
+    }
+
+    fn write_scheduled(&mut self, packet: &Packet, at: Instant) -> Result<usize> {
+        (**self).write_scheduled(packet, at)
+    }
+}
+
+/// Writer discarding all packets without doing any I/O. This is the default
+/// output.
+struct NullWriter;
+
+impl PacketWriter for NullWriter {
     fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
-        let Sink(f) = self;
-        Ok(f.write(buf)?)
+        Ok(buf.len())
     }
 }
 
-/// Returns PacketWriter which just consumes the packets
-pub fn sink() -> Result<impl PacketWriter> {
-    let f = OpenOptions::new().write(true).open("/dev/null")?;
-    Ok(Sink(f))
+/// Returns PacketWriter which discards the packets without writing them
+/// anywhere.
+pub fn null() -> impl PacketWriter {
+    NullWriter
 }
 
 /// [Interface] allows writing packets to network interface
-struct Interface(Pcap);
+struct Interface {
+    pcap: Pcap,
+    /// Name of the interface.
+    name: String,
+    /// MTU of the interface, if known.
+    mtu: Option<usize>,
+    /// Set once a packet too large for the interface has been skipped.
+    skipped_large: bool,
+    /// Link type of the interface, if known.
+    link_type: Option<u32>,
+}
+
+/// Returns the MTU an interface needs to have for writing Ethernet frame in
+/// `buf`.
+pub fn required_mtu(buf: &[u8]) -> usize {
+    let mut hdr_len = ETH_HDR_LEN;
+    if buf.get(12..14) == Some(&[0x81, 0x00]) {
+        // VLAN tag does not count towards the MTU
+        hdr_len += 4;
+    }
+    buf.len().saturating_sub(hdr_len)
+}
+
+impl Interface {
+    /// Skips packet of `len` bytes too large for the interface, warning
+    /// about the first one. Returns 0 as the number of bytes written, so
+    /// that the packet is counted as not sent.
+    fn skip_large(&mut self, len: usize) -> usize {
+        if !self.skipped_large {
+            match self.mtu {
+                Some(mtu) => tracing::warn!(
+                    "Skipping packets larger than MTU {} of {}, first one is {} bytes",
+                    mtu,
+                    self.name,
+                    len
+                ),
+                None => tracing::warn!(
+                    "Skipping packets too large for {}, first one is {} bytes",
+                    self.name,
+                    len
+                ),
+            }
+            self.skipped_large = true;
+        }
+        tracing::debug!(?len, "packet too large for interface");
+        0
+    }
+}
 
 impl PacketWriter for Interface {
     fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
-        match self.0.inject(buf) {
+        // libpcap does not tell why injecting failed, so packets too large
+        // for the interface are detected before writing when MTU is known.
+        // We do not want to stop writing because of them.
+        if self.mtu.is_some_and(|mtu| required_mtu(buf) > mtu) {
+            return Ok(self.skip_large(buf.len()));
+        }
+        match self.pcap.inject(buf) {
             Ok(ret) => Ok(ret),
+            Err(luomu_libpcap::Error::PcapError(ref msg)) if msg.contains("Message too") => {
+                // without known MTU, the only thing we get is error message
+                // from libpcap, which can contain at least "Message too
+                // long" and "Message too large" depending on the Linux
+                // distribution of choice.
+                Ok(self.skip_large(buf.len()))
+            }
             Err(err) => {
                 tracing::warn!(?err, len = ?buf.len(), "error while trying to write");
-                // we do not want to stop writing if we get error indicating that
-                // packet was too large to write.
-                if let luomu_libpcap::Error::PcapError(ref msg) = err {
-                    if msg.contains("Message too") && buf.len() > 1500 {
-                        // this is a stupid way to detect such errors, but there
-                        // is no other way currently, as the only thing we get
-                        // is error message from libpcap and it can contain at
-                        // least "Message too long" and "Message too large"
-                        // depending on the Linux distribution of choice.
-                        Ok(0)
-                    } else {
-                        Err(err.into())
-                    }
-                } else {
-                    Err(err.into())
-                }
+                Err(err.into())
             }
         }
     }
+
+    fn link_type(&self) -> Option<u32> {
+        self.link_type
+    }
+}
+
+/// Returns the number of single character insertions, deletions and
+/// substitutions needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = subst.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Checks that interface `name` is known to libpcap. Returns error naming
+/// the available interfaces, and the closest match to `name` if there is
+/// one, if it is not. If interfaces can not be listed, opening the
+/// interface is left to report the error.
+fn check_interface(name: &str) -> Result<()> {
+    let names: Vec<String> = match luomu_libpcap::get_interfaces() {
+        Ok(ifaces) => ifaces.into_iter().map(|iface| iface.name).collect(),
+        Err(err) => {
+            tracing::debug!(?err, "unable to list interfaces");
+            return Ok(());
+        }
+    };
+    if names.is_empty() || names.iter().any(|n| n == name) {
+        return Ok(());
+    }
+    // only suggest names differing by a typo or two
+    let closest = names
+        .iter()
+        .map(|n| (edit_distance(name, n), n))
+        .filter(|(dist, n)| *dist <= 2 && *dist < n.chars().count())
+        .min_by_key(|(dist, _)| *dist);
+    let hint = match closest {
+        Some((_, n)) => format!(", did you mean {n}?"),
+        None => ".".to_string(),
+    };
+    anyhow::bail!(
+        "no interface named {name}{hint} Available interfaces: {}",
+        names.join(", ")
+    )
 }
 
-/// Returns [PacketWriter] for writing packets to given interface.
+/// Returns [PacketWriter] for writing packets to given interface. Returns
+/// error listing the available interfaces if there is no such interface.
 pub fn interface(name: &str) -> Result<impl PacketWriter> {
-    let p = Pcap::new(name)?;
-    p.activate()?;
-    Ok(Interface(p))
+    check_interface(name)?;
+    let pcap = Pcap::new(name)?;
+    pcap.activate()?;
+    let link_type = pcap.datalink().ok().map(linktype::from_dlt);
+    let mtu = match netif::mtu(name) {
+        // MTU is checked from Ethernet frames only, 802.11 and other link
+        // layer headers are not counted here
+        Ok(_) if link_type.is_some_and(|lt| lt != linktype::ETHERNET) => None,
+        Ok(mtu) => Some(mtu),
+        Err(err) => {
+            tracing::warn!(
+                "Unable to get MTU of {}, not skipping large packets: {}",
+                name,
+                err
+            );
+            None
+        }
+    };
+    Ok(Interface {
+        pcap,
+        name: name.to_string(),
+        mtu,
+        skipped_large: false,
+        link_type,
+    })
+}
+
+/// [MultiInterface] writes every packet to multiple network interfaces.
+struct MultiInterface(Vec<Box<dyn PacketWriter + Send>>);
+
+impl PacketWriter for MultiInterface {
+    /// Writes packet to all interfaces. Returns the largest number of bytes
+    /// written to any of the interfaces, thus 0 is returned only if packet
+    /// was too large for all of them. Any other error terminates writing.
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut written = 0;
+        for iface in self.0.iter_mut() {
+            written = written.max(iface.write_raw(buf)?);
+        }
+        Ok(written)
+    }
+
+    /// Returns the link type of the first interface.
+    fn link_type(&self) -> Option<u32> {
+        self.0.first().and_then(|iface| iface.link_type())
+    }
+
+    /// Writes packets to all interfaces in batches, pushing the largest
+    /// number of bytes written to any of the interfaces for each packet. On
+    /// error, `written` contains the lengths of packets written to the
+    /// failed interface before the error.
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        let start = written.len();
+        written.resize(start + packets.len(), 0);
+        let mut lens = Vec::with_capacity(packets.len());
+        for iface in self.0.iter_mut() {
+            lens.clear();
+            let ret = iface.write_batch(packets, &mut lens);
+            for (total, len) in written[start..].iter_mut().zip(&lens) {
+                *total = (*total).max(*len);
+            }
+            if let Err(err) = ret {
+                written.truncate(start + lens.len());
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Transmission can be scheduled only if all interfaces can schedule
+    /// it.
+    fn can_schedule(&self) -> bool {
+        !self.0.is_empty() && self.0.iter().all(|iface| iface.can_schedule())
+    }
+
+    /// Writes packet to all interfaces to be transmitted at `at`. Returns
+    /// the largest number of bytes written like [MultiInterface::write_raw].
+    fn write_scheduled(&mut self, packet: &Packet, at: Instant) -> Result<usize> {
+        let mut written = 0;
+        for iface in self.0.iter_mut() {
+            written = written.max(iface.write_scheduled(packet, at)?);
+        }
+        Ok(written)
+    }
+}
+
+/// Returns [PacketWriter] for writing every packet to all given interface
+/// writers.
+pub fn interfaces(ifaces: Vec<Box<dyn PacketWriter + Send>>) -> impl PacketWriter {
+    MultiInterface(ifaces)
+}
+
+/// How often link state of output interface is checked.
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Error returned when link of output interface goes down while writing.
+#[derive(Debug)]
+pub struct LinkDown(String);
+
+impl fmt::Display for LinkDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "link of interface {} went down", self.0)
+    }
+}
+
+impl std::error::Error for LinkDown {}
+
+/// [LinkMonitor] checks that link of the interface is up before writing
+/// packets to it. Packets written to interface without link are silently
+/// discarded, so writing fails with [LinkDown] error instead.
+struct LinkMonitor<W> {
+    inner: W,
+    ifname: String,
+    /// When link state was last checked.
+    last_check: Option<Instant>,
+}
+
+impl<W> LinkMonitor<W> {
+    /// Returns [LinkDown] error if link is down, checking it only if
+    /// [LINK_CHECK_INTERVAL] has passed since last check.
+    fn check_link(&mut self) -> Result<()> {
+        if self
+            .last_check
+            .is_none_or(|t| t.elapsed() >= LINK_CHECK_INTERVAL)
+        {
+            self.last_check = Some(Instant::now());
+            match netif::link_up(&self.ifname) {
+                Ok(true) => {}
+                Ok(false) => return Err(LinkDown(self.ifname.clone()).into()),
+                Err(err) => tracing::debug!(?err, "unable to check link state"),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: PacketWriter> PacketWriter for LinkMonitor<W> {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        self.check_link()?;
+        self.inner.write_raw(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        self.inner.link_type()
+    }
+
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        self.check_link()?;
+        self.inner.write_batch(packets, written)
+    }
+
+    fn can_schedule(&self) -> bool {
+        self.inner.can_schedule()
+    }
+
+    fn write_scheduled(&mut self, packet: &Packet, at: Instant) -> Result<usize> {
+        self.check_link()?;
+        self.inner.write_scheduled(packet, at)
+    }
+}
+
+/// Returns [PacketWriter] writing to `inner`, which writes to interface
+/// with given name, and failing with [LinkDown] error if link of the
+/// interface goes down. The link state is checked at most once every
+/// [LINK_CHECK_INTERVAL].
+pub fn link_monitor(inner: impl PacketWriter, ifname: &str) -> impl PacketWriter {
+    LinkMonitor {
+        inner,
+        ifname: ifname.to_string(),
+        last_check: None,
+    }
+}
+
+/// [RawSocket] allows writing packets to network interface using Linux
+/// `AF_PACKET` socket instead of libpcap.
+#[cfg(target_os = "linux")]
+struct RawSocket(rawsock::RawSocket);
+
+#[cfg(target_os = "linux")]
+impl PacketWriter for RawSocket {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.0.send(buf) {
+            Ok(ret) => Ok(ret),
+            // packet too large for the interface, skip it
+            Err(err) if err.raw_os_error() == Some(libc::EMSGSIZE) => {
+                tracing::warn!(?err, len = ?buf.len(), "packet too large to write");
+                Ok(0)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes packets with as few `sendmmsg` calls as possible.
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        let bufs: Vec<&[u8]> = packets.iter().map(|p| p.data.as_slice()).collect();
+        let mut next = 0;
+        while next < bufs.len() {
+            match self.0.send_batch(&bufs[next..], written) {
+                Ok(sent) => next += sent,
+                // packet too large for the interface, skip it
+                Err(err) if err.raw_os_error() == Some(libc::EMSGSIZE) => {
+                    tracing::warn!(?err, len = ?bufs[next].len(), "packet too large to write");
+                    written.push(0);
+                    next += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options for raw sockets writing to interface.
+#[derive(Clone, Copy, Default)]
+pub struct SocketOptions {
+    /// Size of the socket send buffer in bytes, system default if not set.
+    pub send_buffer: Option<usize>,
+    /// Transmit queue to write packets to. The queue is selected by setting
+    /// socket priority to the queue number, which needs `mqprio` qdisc
+    /// mapping each priority to the queue with the same number.
+    pub tx_queue: Option<u32>,
+}
+
+/// Opens raw socket for writing to interface `name`, configured with
+/// `opts`.
+#[cfg(target_os = "linux")]
+fn open_raw_socket(name: &str, opts: &SocketOptions) -> Result<rawsock::RawSocket> {
+    use anyhow::Context;
+
+    check_interface(name)?;
+    let sock = rawsock::RawSocket::open(name)?;
+    if let Some(size) = opts.send_buffer {
+        sock.set_send_buffer(size)
+            .with_context(|| format!("unable to set send buffer size of {name}"))?;
+    }
+    if let Some(queue) = opts.tx_queue {
+        sock.set_priority(queue)
+            .with_context(|| format!("unable to select transmit queue {queue} of {name}"))?;
+    }
+    Ok(sock)
+}
+
+/// Returns [PacketWriter] for writing packets to given interface using raw
+/// `AF_PACKET` socket configured with `opts`.
+#[cfg(target_os = "linux")]
+pub fn raw_socket(name: &str, opts: &SocketOptions) -> Result<impl PacketWriter> {
+    Ok(RawSocket(open_raw_socket(name, opts)?))
+}
+
+/// [TxtimeSocket] writes packets to network interface using Linux
+/// `AF_PACKET` socket, leaving the kernel to transmit them at the scheduled
+/// time with `SO_TXTIME`.
+#[cfg(target_os = "linux")]
+struct TxtimeSocket(RawSocket);
+
+#[cfg(target_os = "linux")]
+impl PacketWriter for TxtimeSocket {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write_raw(buf)
+    }
+
+    fn can_schedule(&self) -> bool {
+        true
+    }
+
+    fn write_scheduled(&mut self, packet: &Packet, at: Instant) -> Result<usize> {
+        let delay = at.saturating_duration_since(Instant::now());
+        let txtime = rawsock::monotonic_now() + delay.as_nanos() as u64;
+        match self.0 .0.send_at(&packet.data, txtime) {
+            Ok(ret) => Ok(ret),
+            Err(err) if err.raw_os_error() == Some(libc::EMSGSIZE) => {
+                tracing::warn!(?err, len = ?packet.data.len(), "packet too large to write");
+                Ok(0)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Returns [PacketWriter] for writing packets to given interface using raw
+/// `AF_PACKET` socket with transmission times scheduled with `SO_TXTIME`.
+/// Interface needs to have `fq` (or other qdisc supporting `SO_TXTIME`
+/// with `CLOCK_MONOTONIC`) configured for the times to be enforced. The
+/// socket is configured with `opts`.
+#[cfg(target_os = "linux")]
+pub fn txtime_socket(name: &str, opts: &SocketOptions) -> Result<impl PacketWriter> {
+    let sock = open_raw_socket(name, opts)?;
+    sock.enable_txtime()?;
+    Ok(TxtimeSocket(RawSocket(sock)))
+}
+
+/// [UdpWriter] sends each packet as a UDP datagram.
+struct UdpWriter(UdpSocket);
+
+impl PacketWriter for UdpWriter {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.0.send(buf) {
+            Ok(ret) => Ok(ret),
+            // packet too large for a datagram, skip it
+            Err(err) if err.raw_os_error() == Some(libc::EMSGSIZE) => {
+                tracing::warn!(?err, len = ?buf.len(), "packet too large to send");
+                Ok(0)
+            }
+            // ICMP error received for earlier datagram, collector might
+            // not be up yet
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                tracing::debug!(?err, "datagram refused");
+                Ok(0)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Returns [PacketWriter] sending packets as UDP datagrams to given address.
+pub fn udp(addr: SocketAddr) -> Result<impl PacketWriter> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let sock = UdpSocket::bind(local)?;
+    sock.connect(addr)?;
+    Ok(UdpWriter(sock))
+}
+
+/// Snap length written to pcap file header if snap length of the input is
+/// not known.
+const PCAP_SNAPLEN: u32 = 262_144;
+
+/// Link type and snap length written to pcap file header, these should be
+/// taken from the input so that the file describes the packets correctly.
+#[derive(Clone, Copy, Debug)]
+pub struct FileFormat {
+    /// Link type of the packets, see [linktype].
+    pub link_type: u32,
+    /// Maximum number of bytes in each packet.
+    pub snaplen: u32,
+}
+
+impl Default for FileFormat {
+    /// Ethernet packets with snap length of 262144 bytes.
+    fn default() -> Self {
+        FileFormat {
+            link_type: linktype::ETHERNET,
+            snaplen: PCAP_SNAPLEN,
+        }
+    }
+}
+
+/// [PcapFile] writes packets into pcap -file with nanosecond timestamps.
+struct PcapFile<W: Write> {
+    out: W,
+    /// Link type written to file header.
+    link_type: u32,
+}
+
+impl<W: Write> PcapFile<W> {
+    /// Creates [PcapFile] writing to `out`. The pcap file header is
+    /// written immediately.
+    fn new(mut out: W, format: FileFormat) -> Result<Self> {
+        // nanosecond resolution keeps timestamps read from pcapng and
+        // nanosecond pcap files intact
+        out.write_all(&pcapfile::MAGIC_NANOS.to_ne_bytes())?;
+        out.write_all(&2_u16.to_ne_bytes())?;
+        out.write_all(&4_u16.to_ne_bytes())?;
+        // thiszone and sigfigs
+        out.write_all(&[0u8; 8])?;
+        out.write_all(&format.snaplen.to_ne_bytes())?;
+        out.write_all(&format.link_type.to_ne_bytes())?;
+        Ok(PcapFile {
+            out,
+            link_type: format.link_type,
+        })
+    }
+
+    /// Writes a packet record with given timestamp and data.
+    fn write_record(&mut self, when: SystemTime, buf: &[u8]) -> Result<usize> {
+        let ts = when.duration_since(SystemTime::UNIX_EPOCH)?;
+        let len = u32::try_from(buf.len())?;
+        let out = &mut self.out;
+        out.write_all(&(ts.as_secs() as u32).to_ne_bytes())?;
+        out.write_all(&ts.subsec_nanos().to_ne_bytes())?;
+        out.write_all(&len.to_ne_bytes())?;
+        out.write_all(&len.to_ne_bytes())?;
+        out.write_all(buf)?;
+        Ok(buf.len())
+    }
+}
+
+impl<W: Write> PacketWriter for PcapFile<W> {
+    /// Writes packet data with current time as the timestamp.
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        self.write_record(SystemTime::now(), buf)
+    }
+
+    /// Writes packet with its original timestamp.
+    fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
+        self.write_record(packet.when, &packet.data)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.out.flush()?)
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        Some(self.link_type)
+    }
+}
+
+/// Format of packets written to standard output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// Pcap stream with file header, like a pcap file.
+    #[default]
+    Pcap,
+    /// Packet data only, without any framing.
+    Raw,
+}
+
+/// [StreamWriter] writes packets to a stream read by another program, such
+/// as standard output. Output is flushed after every write, so that the
+/// reader gets packets when they are due.
+enum StreamWriter<W: Write> {
+    Pcap(PcapFile<BufWriter<W>>),
+    Raw(BufWriter<W>),
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Writes `buf` without flushing.
+    fn write_unflushed(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            StreamWriter::Pcap(pcap) => pcap.write_raw(buf),
+            StreamWriter::Raw(out) => {
+                out.write_all(buf)?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    /// Writes `packet` without flushing.
+    fn write_packet_unflushed(&mut self, packet: &Packet) -> Result<usize> {
+        match self {
+            StreamWriter::Pcap(pcap) => pcap.write_packet(packet),
+            StreamWriter::Raw(_) => self.write_unflushed(&packet.data),
+        }
+    }
+}
+
+impl<W: Write> PacketWriter for StreamWriter<W> {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.write_unflushed(buf)?;
+        self.flush()?;
+        Ok(len)
+    }
+
+    fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
+        let len = self.write_packet_unflushed(packet)?;
+        self.flush()?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            StreamWriter::Pcap(pcap) => pcap.flush(),
+            StreamWriter::Raw(out) => Ok(out.flush()?),
+        }
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        match self {
+            StreamWriter::Pcap(pcap) => pcap.link_type(),
+            StreamWriter::Raw(_) => None,
+        }
+    }
+
+    /// Writes the packets and flushes once for the whole batch.
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        for packet in packets {
+            written.push(self.write_packet_unflushed(packet)?);
+        }
+        self.flush()
+    }
+}
+
+/// Returns [PacketWriter] for writing packets to standard output in given
+/// [StreamFormat]. Pcap stream is written with link type and snap length
+/// given in `format`.
+pub fn stdout(stream: StreamFormat, format: FileFormat) -> Result<impl PacketWriter> {
+    let out = BufWriter::new(io::stdout());
+    let mut writer = match stream {
+        StreamFormat::Pcap => StreamWriter::Pcap(PcapFile::new(out, format)?),
+        StreamFormat::Raw => StreamWriter::Raw(out),
+    };
+    // reader can parse the header before the first packet is due
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Returns [PacketWriter] streaming packets over TCP connection to `addr`,
+/// for another pktreplay reading them with [crate::input::tcp_listen].
+/// Packets are sent as pcap stream, with link type and snap length given
+/// in `format`, so that each packet is framed with its length and
+/// timestamp.
+pub fn tcp(addr: SocketAddr, format: FileFormat) -> Result<impl PacketWriter> {
+    let sock = TcpStream::connect(addr)?;
+    // packets are flushed one at a time, do not hold them back
+    sock.set_nodelay(true)?;
+    let mut writer = StreamWriter::Pcap(PcapFile::new(BufWriter::new(sock), format)?);
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// Returns [PacketWriter] for writing packets to pcap -file in given path,
+/// with link type and snap length given in `format`.
+///
+/// Existing file is truncated.
+pub fn file(path: &str, format: FileFormat) -> Result<impl PacketWriter> {
+    let f = File::create(path)?;
+    PcapFile::new(BufWriter::new(f), format)
+}
+
+/// When [Rotating] closes the current pcap file and starts a new one.
+#[derive(Clone, Copy, Debug)]
+pub enum Rotate {
+    /// After given number of packets have been written to the file.
+    Packets(u64),
+    /// After the file has been open for given time.
+    Interval(Duration),
+}
+
+/// [Rotating] writes packets into a sequence of pcap files, starting a new
+/// file as selected by [Rotate].
+struct Rotating {
+    /// Path given by the user, file names are derived from it.
+    path: String,
+    rotate: Rotate,
+    /// Format of every file.
+    format: FileFormat,
+    /// Number of files opened so far.
+    files: u64,
+    /// File currently written.
+    current: PcapFile<BufWriter<File>>,
+    /// Number of packets written to current file.
+    packets: u64,
+    /// When current file was opened.
+    opened: Instant,
+}
+
+/// Returns name for the `index`th rotated file derived from `path`. Name
+/// contains the time the file is opened as seconds since epoch and the
+/// index, for example `out.pcap` becomes `out-1700000000-0.pcap`.
+fn rotated_name(path: &str, index: u64) -> String {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let p = Path::new(path);
+    let stem = p.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match p.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{stem}-{secs}-{index}.{ext}"),
+        None => format!("{stem}-{secs}-{index}"),
+    };
+    p.with_file_name(name).to_string_lossy().into_owned()
+}
+
+impl Rotating {
+    /// Opens the next file, each file gets its own pcap file header.
+    fn open(path: &str, index: u64, format: FileFormat) -> Result<PcapFile<BufWriter<File>>> {
+        let name = rotated_name(path, index);
+        tracing::debug!("writing packets to {}", name);
+        PcapFile::new(BufWriter::new(File::create(name)?), format)
+    }
+
+    /// Closes current file and opens a new one, if it is time to rotate.
+    fn rotate_if_due(&mut self) -> Result<()> {
+        let due = match self.rotate {
+            Rotate::Packets(n) => self.packets >= n,
+            Rotate::Interval(d) => self.opened.elapsed() >= d,
+        };
+        if due {
+            self.current.flush()?;
+            self.current = Rotating::open(&self.path, self.files, self.format)?;
+            self.files += 1;
+            self.packets = 0;
+            self.opened = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+impl PacketWriter for Rotating {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        self.rotate_if_due()?;
+        self.packets += 1;
+        self.current.write_raw(buf)
+    }
+
+    fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
+        self.rotate_if_due()?;
+        self.packets += 1;
+        self.current.write_packet(packet)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.current.flush()
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        self.current.link_type()
+    }
+}
+
+/// Returns [PacketWriter] for writing packets into a sequence of pcap files
+/// named after `path`, starting a new file as selected by `rotate`. Every
+/// file has link type and snap length given in `format`. The first file is
+/// created immediately.
+pub fn rotating_file(path: &str, rotate: Rotate, format: FileFormat) -> Result<impl PacketWriter> {
+    Ok(Rotating {
+        path: path.to_string(),
+        rotate,
+        format,
+        files: 1,
+        current: Rotating::open(path, 0, format)?,
+        packets: 0,
+        opened: Instant::now(),
+    })
+}
+
+/// [Tee] writes packets to primary [PacketWriter] and records the packets
+/// successfully written to it into secondary [PacketWriter].
+struct Tee<P, S> {
+    primary: P,
+    secondary: S,
+}
+
+impl<P: PacketWriter, S: PacketWriter> PacketWriter for Tee<P, S> {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.primary.write_raw(buf)?;
+        if written > 0 {
+            self.secondary.write_raw(buf)?;
+        }
+        Ok(written)
+    }
+
+    /// Writes the whole packet to both writers, so that the timestamp is
+    /// kept by writers recording it.
+    fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
+        let written = self.primary.write_packet(packet)?;
+        if written > 0 {
+            self.secondary.write_packet(packet)?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.primary.flush()?;
+        self.secondary.flush()
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        self.primary.link_type()
+    }
+
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        let start = written.len();
+        let ret = self.primary.write_batch(packets, written);
+        // packets written before an error are recorded too
+        for (packet, len) in packets.iter().zip(&written[start..]) {
+            if *len > 0 {
+                self.secondary.write_packet(packet)?;
+            }
+        }
+        ret
+    }
+
+    fn can_schedule(&self) -> bool {
+        self.primary.can_schedule()
+    }
+
+    fn write_scheduled(&mut self, packet: &Packet, at: Instant) -> Result<usize> {
+        let written = self.primary.write_scheduled(packet, at)?;
+        if written > 0 {
+            self.secondary.write_packet(packet)?;
+        }
+        Ok(written)
+    }
+}
+
+/// Returns [PacketWriter] which writes packets to `primary` and records
+/// packets written to it into `secondary`. Packets `primary` did not write
+/// (for example because they were too large) are not recorded. Number of
+/// bytes written to `primary` is returned.
+pub fn tee(primary: impl PacketWriter, secondary: impl PacketWriter) -> impl PacketWriter {
+    Tee { primary, secondary }
 }
@@ -0,0 +1,128 @@
+//! Reader for classic pcap streams.
+//!
+//! This reader is used for all pcap data, files included, as libpcap would
+//! round nanosecond timestamps to microseconds and does not tell a
+//! truncated last packet record apart from end of file. It also reads
+//! streams libpcap can not access directly, such as decompressed data.
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime};
+
+use crate::{channel::BufferPool, input::Packet};
+
+/// Magic number for pcap with microsecond timestamps.
+pub const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+/// Magic number for pcap with nanosecond timestamps.
+pub const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+
+/// Largest packet we are willing to read.
+const MAX_PACKET_LEN: u32 = 16 * 1024 * 1024;
+
+/// Reader for reading packets from pcap stream.
+pub struct Reader<R> {
+    inner: R,
+    /// Is the stream in different byte order than ours.
+    swapped: bool,
+    /// Are the timestamps in nanoseconds.
+    nanos: bool,
+    /// Link type of the packets.
+    link_type: u32,
+    /// Maximum number of bytes captured from each packet.
+    snaplen: u32,
+}
+
+impl<R: Read> Reader<R> {
+    /// Creates a new [Reader] reading pcap data from `inner`. The pcap
+    /// file header is read immediately.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut hdr = [0u8; 24];
+        inner.read_exact(&mut hdr)?;
+        let magic = u32::from_ne_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+        let (swapped, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            m if m.swap_bytes() == MAGIC_MICROS => (true, false),
+            m if m.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a pcap stream",
+                ))
+            }
+        };
+        let mut rd = Reader {
+            inner,
+            swapped,
+            nanos,
+            link_type: 0,
+            snaplen: 0,
+        };
+        rd.snaplen = rd.u32_at(&hdr, 16);
+        // upper bits of the link type field carry FCS information
+        rd.link_type = rd.u32_at(&hdr, 20) & 0xffff;
+        Ok(rd)
+    }
+
+    /// Returns the link type of packets in the stream.
+    pub fn link_type(&self) -> u32 {
+        self.link_type
+    }
+
+    /// Returns the snap length of the stream.
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen
+    }
+
+    /// Returns the next packet from the stream, or [None] if end of stream
+    /// has been reached. Packet data is read into buffer taken from `pool`,
+    /// if given.
+    pub fn next_packet(&mut self, pool: Option<&BufferPool>) -> io::Result<Option<Packet>> {
+        let mut hdr = [0u8; 16];
+        let mut got = 0;
+        while got < hdr.len() {
+            match self.inner.read(&mut hdr[got..]) {
+                Ok(0) if got == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => got += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let secs = self.u32_at(&hdr, 0);
+        let frac = self.u32_at(&hdr, 4);
+        let caplen = self.u32_at(&hdr, 8);
+        if caplen > MAX_PACKET_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid packet length",
+            ));
+        }
+        let mut data = match pool {
+            Some(pool) => pool.buffer(caplen as usize),
+            None => vec![0u8; caplen as usize],
+        };
+        if let Err(err) = self.inner.read_exact(&mut data) {
+            if let Some(pool) = pool {
+                pool.recycle(data);
+            }
+            return Err(err);
+        }
+        let frac = if self.nanos {
+            Duration::from_nanos(u64::from(frac))
+        } else {
+            Duration::from_micros(u64::from(frac))
+        };
+        Ok(Some(Packet {
+            data,
+            when: SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from(secs)) + frac,
+        }))
+    }
+
+    fn u32_at(&self, buf: &[u8], off: usize) -> u32 {
+        let v = u32::from_ne_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]]);
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+}
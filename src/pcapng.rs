@@ -0,0 +1,610 @@
+//! Reader for pcapng -files.
+//!
+//! Only blocks needed for replaying packets are interpreted: Section Header
+//! Blocks, Interface Description Blocks and Enhanced and Simple Packet
+//! Blocks. All other blocks are skipped.
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    channel::BufferPool,
+    input::{copy_data, Packet},
+};
+
+/// Block type of Section Header Block. This is also the magic number
+/// every pcapng -file starts with.
+pub const MAGIC: u32 = 0x0A0D_0D0A;
+/// Byte order magic stored in Section Header Block.
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// Block type of Interface Description Block.
+const BLOCK_IDB: u32 = 0x0000_0001;
+/// Block type of Simple Packet Block.
+const BLOCK_SPB: u32 = 0x0000_0003;
+/// Block type of Enhanced Packet Block.
+const BLOCK_EPB: u32 = 0x0000_0006;
+
+/// Option code for end of options.
+const OPT_ENDOFOPT: u16 = 0;
+/// Option code for interface timestamp resolution.
+const OPT_IF_TSRESOL: u16 = 9;
+/// Option code for interface timestamp offset.
+const OPT_IF_TSOFFSET: u16 = 14;
+
+/// Largest block we are willing to read.
+const MAX_BLOCK_LEN: u32 = 16 * 1024 * 1024;
+
+/// Interface described by Interface Description Block.
+struct Interface {
+    /// Link type of packets captured from the interface.
+    link_type: u16,
+    /// Maximum number of bytes captured from each packet, 0 if unlimited.
+    snaplen: u32,
+    /// Number of timestamp units in a second.
+    units_per_sec: u64,
+    /// Offset in seconds to add to every timestamp.
+    offset: i64,
+}
+
+/// Reader for reading packets from pcapng stream.
+pub struct Reader<R> {
+    inner: R,
+    /// Is the current section big endian.
+    big_endian: bool,
+    /// Interfaces defined in current section.
+    interfaces: Vec<Interface>,
+    /// Timestamp of the previous packet, used for Simple Packet Blocks
+    /// which do not carry timestamp.
+    last: SystemTime,
+}
+
+impl<R: Read> Reader<R> {
+    /// Creates a new [Reader] reading pcapng data from `inner`.
+    ///
+    /// The stream needs to start with a Section Header Block.
+    pub fn new(inner: R) -> io::Result<Self> {
+        let mut rd = Reader {
+            inner,
+            big_endian: false,
+            interfaces: Vec::new(),
+            last: SystemTime::UNIX_EPOCH,
+        };
+        match rd.read_block()? {
+            Some((MAGIC, _)) => {}
+            _ => return Err(invalid("stream does not start with section header")),
+        }
+        // interface descriptions precede the packets of the interface, read
+        // the first one so that the link type is known before packets are
+        // read
+        loop {
+            match rd.read_block()? {
+                None => break,
+                Some((BLOCK_IDB, body)) => {
+                    rd.interface_description(&body)?;
+                    break;
+                }
+                Some((BLOCK_EPB | BLOCK_SPB, _)) => {
+                    return Err(invalid("packet before interface description"))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(rd)
+    }
+
+    /// Returns the link type of the first interface in the stream, if any.
+    pub fn link_type(&self) -> Option<u32> {
+        self.interfaces.first().map(|i| u32::from(i.link_type))
+    }
+
+    /// Returns the snap length of the first interface in the stream, if it
+    /// is known and limited.
+    pub fn snaplen(&self) -> Option<u32> {
+        self.interfaces
+            .first()
+            .map(|i| i.snaplen)
+            .filter(|len| *len > 0)
+    }
+
+    /// Returns the next packet from the stream, or [None] if end of stream
+    /// has been reached. Packet data is copied into buffer taken from
+    /// `pool`, if given.
+    pub fn next_packet(&mut self, pool: Option<&BufferPool>) -> io::Result<Option<Packet>> {
+        loop {
+            let Some((btype, body)) = self.read_block()? else {
+                return Ok(None);
+            };
+            match btype {
+                BLOCK_EPB => return self.enhanced_packet(&body, pool).map(Some),
+                BLOCK_SPB => return self.simple_packet(&body, pool).map(Some),
+                BLOCK_IDB => self.interface_description(&body)?,
+                // Section header is handled by read_block(), everything
+                // else is not interesting to us.
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads next block, returning its type and body. Section Header
+    /// Blocks are processed here as they determine the byte order for
+    /// rest of the section.
+    fn read_block(&mut self) -> io::Result<Option<(u32, Vec<u8>)>> {
+        let mut hdr = [0u8; 8];
+        if !read_or_eof(&mut self.inner, &mut hdr)? {
+            return Ok(None);
+        }
+        let raw_type = [hdr[0], hdr[1], hdr[2], hdr[3]];
+        if u32::from_le_bytes(raw_type) == MAGIC {
+            // Section header, byte order is determined from the
+            // byte order magic following the block length.
+            let mut bom = [0u8; 4];
+            self.inner.read_exact(&mut bom)?;
+            self.big_endian = match u32::from_le_bytes(bom) {
+                BYTE_ORDER_MAGIC => false,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                _ => return Err(invalid("invalid byte order magic")),
+            };
+            self.interfaces.clear();
+            let len = self.u32_at(&hdr, 4);
+            let mut body = self.read_body(len, 4)?;
+            body.splice(0..0, bom);
+            return Ok(Some((MAGIC, body)));
+        }
+        let btype = self.u32_at(&hdr, 0);
+        let len = self.u32_at(&hdr, 4);
+        let body = self.read_body(len, 0)?;
+        Ok(Some((btype, body)))
+    }
+
+    /// Reads rest of a block with total length of `len` when `consumed`
+    /// bytes of the body have already been read. The trailing length field
+    /// is verified and not returned.
+    fn read_body(&mut self, len: u32, consumed: u32) -> io::Result<Vec<u8>> {
+        if len < 12 + consumed || !len.is_multiple_of(4) || len > MAX_BLOCK_LEN {
+            return Err(invalid("invalid block length"));
+        }
+        let mut body = vec![0u8; (len - 12 - consumed) as usize];
+        self.inner.read_exact(&mut body)?;
+        let mut trailer = [0u8; 4];
+        self.inner.read_exact(&mut trailer)?;
+        if self.u32_at(&trailer, 0) != len {
+            return Err(invalid("block length mismatch"));
+        }
+        Ok(body)
+    }
+
+    /// Processes Interface Description Block.
+    fn interface_description(&mut self, body: &[u8]) -> io::Result<()> {
+        if body.len() < 8 {
+            return Err(invalid("truncated interface description"));
+        }
+        let mut iface = Interface {
+            link_type: self.u16_at(body, 0),
+            snaplen: self.u32_at(body, 4),
+            units_per_sec: 1_000_000,
+            offset: 0,
+        };
+        for (code, val) in self.options(&body[8..]) {
+            match code {
+                OPT_IF_TSRESOL if !val.is_empty() => {
+                    let exp = u32::from(val[0] & 0x7f);
+                    let base: u64 = if val[0] & 0x80 == 0 { 10 } else { 2 };
+                    iface.units_per_sec = base
+                        .checked_pow(exp)
+                        .ok_or_else(|| invalid("unsupported timestamp resolution"))?;
+                }
+                OPT_IF_TSOFFSET if val.len() >= 8 => {
+                    iface.offset = self.u64_at(val, 0) as i64;
+                }
+                _ => {}
+            }
+        }
+        self.interfaces.push(iface);
+        Ok(())
+    }
+
+    /// Processes Enhanced Packet Block.
+    fn enhanced_packet(&mut self, body: &[u8], pool: Option<&BufferPool>) -> io::Result<Packet> {
+        if body.len() < 20 {
+            return Err(invalid("truncated enhanced packet block"));
+        }
+        let if_id = self.u32_at(body, 0) as usize;
+        let ts = (u64::from(self.u32_at(body, 4)) << 32) | u64::from(self.u32_at(body, 8));
+        let caplen = self.u32_at(body, 12) as usize;
+        let data = body
+            .get(20..20 + caplen)
+            .ok_or_else(|| invalid("truncated packet data"))?;
+        let iface = self
+            .interfaces
+            .get(if_id)
+            .ok_or_else(|| invalid("packet for undefined interface"))?;
+        let when = timestamp(ts, iface)?;
+        self.last = when;
+        Ok(Packet {
+            data: copy_data(data, pool),
+            when,
+        })
+    }
+
+    /// Processes Simple Packet Block. These blocks do not have timestamp,
+    /// so timestamp of the previous packet is used.
+    fn simple_packet(&mut self, body: &[u8], pool: Option<&BufferPool>) -> io::Result<Packet> {
+        if body.len() < 4 {
+            return Err(invalid("truncated simple packet block"));
+        }
+        let origlen = self.u32_at(body, 0) as usize;
+        let data = &body[4..];
+        Ok(Packet {
+            data: copy_data(&data[..origlen.min(data.len())], pool),
+            when: self.last,
+        })
+    }
+
+    /// Returns (code, value) pairs of options in `buf`.
+    fn options<'a>(&self, mut buf: &'a [u8]) -> Vec<(u16, &'a [u8])> {
+        let mut ret = Vec::new();
+        while buf.len() >= 4 {
+            let code = self.u16_at(buf, 0);
+            let len = self.u16_at(buf, 2) as usize;
+            if code == OPT_ENDOFOPT {
+                break;
+            }
+            let Some(val) = buf.get(4..4 + len) else {
+                break;
+            };
+            ret.push((code, val));
+            let padded = 4 + len.div_ceil(4) * 4;
+            buf = buf.get(padded..).unwrap_or_default();
+        }
+        ret
+    }
+
+    fn u16_at(&self, buf: &[u8], off: usize) -> u16 {
+        let b = [buf[off], buf[off + 1]];
+        if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    fn u32_at(&self, buf: &[u8], off: usize) -> u32 {
+        let b = [buf[off], buf[off + 1], buf[off + 2], buf[off + 3]];
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    fn u64_at(&self, buf: &[u8], off: usize) -> u64 {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(&buf[off..off + 8]);
+        if self.big_endian {
+            u64::from_be_bytes(b)
+        } else {
+            u64::from_le_bytes(b)
+        }
+    }
+}
+
+/// Converts timestamp of `ts` units on given interface to [SystemTime].
+/// Returns error if the timestamp can not be represented.
+fn timestamp(ts: u64, iface: &Interface) -> io::Result<SystemTime> {
+    let secs = ts / iface.units_per_sec;
+    let rem = ts % iface.units_per_sec;
+    let nanos = (u128::from(rem) * 1_000_000_000 / u128::from(iface.units_per_sec)) as u32;
+    let since_epoch = Duration::new(secs, nanos);
+    let offset = Duration::from_secs(iface.offset.unsigned_abs());
+    let since_epoch = if iface.offset < 0 {
+        Some(since_epoch.saturating_sub(offset))
+    } else {
+        since_epoch.checked_add(offset)
+    };
+    since_epoch
+        .and_then(|d| SystemTime::UNIX_EPOCH.checked_add(d))
+        .ok_or_else(|| invalid("timestamp out of range"))
+}
+
+/// Fills `buf` from `rd`. Returns `false` if end of stream was reached
+/// before any bytes were read.
+fn read_or_eof(rd: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut got = 0;
+    while got < buf.len() {
+        match rd.read(&mut buf[got..]) {
+            Ok(0) if got == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => got += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns `v` as bytes in big or little endian byte order.
+    fn u16b(big_endian: bool, v: u16) -> [u8; 2] {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+
+    fn u32b(big_endian: bool, v: u32) -> [u8; 4] {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+
+    fn u64b(big_endian: bool, v: u64) -> [u8; 8] {
+        if big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+
+    /// Returns block of type `btype` with `body` padded to 32 bits.
+    fn block(be: bool, btype: u32, body: &[u8]) -> Vec<u8> {
+        let padded = body.len().div_ceil(4) * 4;
+        let len = u32::try_from(12 + padded).unwrap();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&u32b(be, btype));
+        buf.extend_from_slice(&u32b(be, len));
+        buf.extend_from_slice(body);
+        buf.resize(8 + padded, 0);
+        buf.extend_from_slice(&u32b(be, len));
+        buf
+    }
+
+    /// Returns Section Header Block.
+    fn shb(be: bool) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&u32b(be, BYTE_ORDER_MAGIC));
+        body.extend_from_slice(&u16b(be, 1));
+        body.extend_from_slice(&u16b(be, 0));
+        body.extend_from_slice(&u64b(be, u64::MAX));
+        block(be, MAGIC, &body)
+    }
+
+    /// Returns Interface Description Block for Ethernet with given options.
+    fn idb(be: bool, options: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&u16b(be, 1));
+        body.extend_from_slice(&[0; 2]);
+        body.extend_from_slice(&u32b(be, 1500));
+        for (code, val) in options {
+            body.extend_from_slice(&u16b(be, *code));
+            body.extend_from_slice(&u16b(be, u16::try_from(val.len()).unwrap()));
+            body.extend_from_slice(val);
+            body.resize(body.len().div_ceil(4) * 4, 0);
+        }
+        body.extend_from_slice(&[0; 4]);
+        block(be, BLOCK_IDB, &body)
+    }
+
+    /// Returns Enhanced Packet Block for interface `if_id`.
+    fn epb(be: bool, if_id: u32, ts: u64, data: &[u8]) -> Vec<u8> {
+        let len = u32::try_from(data.len()).unwrap();
+        let mut body = Vec::new();
+        body.extend_from_slice(&u32b(be, if_id));
+        body.extend_from_slice(&u32b(be, (ts >> 32) as u32));
+        body.extend_from_slice(&u32b(be, ts as u32));
+        body.extend_from_slice(&u32b(be, len));
+        body.extend_from_slice(&u32b(be, len));
+        body.extend_from_slice(data);
+        block(be, BLOCK_EPB, &body)
+    }
+
+    /// Returns Simple Packet Block.
+    fn spb(be: bool, origlen: u32, data: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&u32b(be, origlen));
+        body.extend_from_slice(data);
+        block(be, BLOCK_SPB, &body)
+    }
+
+    /// Reads all packets from `blocks`.
+    fn read(blocks: &[Vec<u8>]) -> io::Result<Vec<Packet>> {
+        let data = blocks.concat();
+        let mut rd = Reader::new(data.as_slice())?;
+        let mut packets = Vec::new();
+        while let Some(pkt) = rd.next_packet(None)? {
+            packets.push(pkt);
+        }
+        Ok(packets)
+    }
+
+    /// Reads packets from `blocks` and returns the error reading fails with.
+    fn read_error(blocks: &[Vec<u8>]) -> io::Error {
+        match read(blocks) {
+            Ok(_) => panic!("reading did not fail"),
+            Err(err) => err,
+        }
+    }
+
+    /// Returns timestamp of the only packet in `blocks`.
+    fn read_timestamp(blocks: &[Vec<u8>]) -> SystemTime {
+        let packets = read(blocks).unwrap();
+        assert_eq!(packets.len(), 1);
+        packets[0].when
+    }
+
+    fn at(secs: u64, nanos: u32) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(secs, nanos)
+    }
+
+    #[test]
+    fn byte_order_from_section_header() {
+        for be in [false, true] {
+            let data = [
+                shb(be),
+                idb(be, &[]),
+                epb(be, 0, 1_500_000, &[1, 2, 3, 4, 5]),
+            ]
+            .concat();
+            let mut rd = Reader::new(data.as_slice()).unwrap();
+            assert_eq!(rd.link_type(), Some(1));
+            assert_eq!(rd.snaplen(), Some(1500));
+            let pkt = rd.next_packet(None).unwrap().unwrap();
+            assert_eq!(pkt.data, [1, 2, 3, 4, 5]);
+            assert_eq!(pkt.when, at(1, 500_000_000));
+            assert!(rd.next_packet(None).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn byte_order_changes_with_section() {
+        let when = read(&[
+            shb(false),
+            idb(false, &[]),
+            epb(false, 0, 1_000_000, &[1]),
+            shb(true),
+            idb(true, &[]),
+            epb(true, 0, 2_000_000, &[2]),
+        ])
+        .unwrap()
+        .iter()
+        .map(|pkt| pkt.when)
+        .collect::<Vec<_>>();
+        assert_eq!(when, [at(1, 0), at(2, 0)]);
+    }
+
+    #[test]
+    fn tsresol_power_of_ten() {
+        let when = read_timestamp(&[
+            shb(false),
+            idb(false, &[(OPT_IF_TSRESOL, &[9])]),
+            epb(false, 0, 1_000_000_001, &[0]),
+        ]);
+        assert_eq!(when, at(1, 1));
+    }
+
+    #[test]
+    fn tsresol_power_of_two() {
+        let when = read_timestamp(&[
+            shb(true),
+            idb(true, &[(OPT_IF_TSRESOL, &[0x80 | 10])]),
+            epb(true, 0, 1024 + 256, &[0]),
+        ]);
+        assert_eq!(when, at(1, 250_000_000));
+    }
+
+    #[test]
+    fn tsoffset() {
+        let offset = 100i64.to_le_bytes();
+        let when = read_timestamp(&[
+            shb(false),
+            idb(false, &[(OPT_IF_TSOFFSET, &offset)]),
+            epb(false, 0, 1_000_000, &[0]),
+        ]);
+        assert_eq!(when, at(101, 0));
+
+        let offset = (-1i64).to_be_bytes();
+        let when = read_timestamp(&[
+            shb(true),
+            idb(true, &[(OPT_IF_TSOFFSET, &offset)]),
+            epb(true, 0, 3_000_000, &[0]),
+        ]);
+        assert_eq!(when, at(2, 0));
+    }
+
+    #[test]
+    fn simple_packet_uses_previous_timestamp() {
+        let packets = read(&[
+            shb(false),
+            idb(false, &[]),
+            epb(false, 0, 2_000_000, &[1]),
+            spb(false, 3, &[1, 2, 3]),
+        ])
+        .unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].data, [1, 2, 3]);
+        assert_eq!(packets[1].when, at(2, 0));
+    }
+
+    #[test]
+    fn invalid_streams() {
+        let mut bad_bom = shb(false);
+        bad_bom[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        let mut bad_trailer = epb(false, 0, 0, &[0]);
+        let last = bad_trailer.len() - 4;
+        bad_trailer[last] ^= 0xff;
+        let mut truncated_data = epb(false, 0, 0, &[0; 4]);
+        truncated_data[20..24].copy_from_slice(&5u32.to_le_bytes());
+        let cases = [
+            (
+                vec![idb(false, &[])],
+                "stream does not start with section header",
+            ),
+            (vec![bad_bom], "invalid byte order magic"),
+            (
+                vec![shb(false), epb(false, 0, 0, &[0])],
+                "packet before interface description",
+            ),
+            (
+                vec![shb(false), idb(false, &[(OPT_IF_TSRESOL, &[127])])],
+                "unsupported timestamp resolution",
+            ),
+            (
+                vec![shb(false), idb(false, &[]), bad_trailer],
+                "block length mismatch",
+            ),
+            (
+                vec![shb(false), idb(false, &[]), epb(false, 1, 0, &[0])],
+                "packet for undefined interface",
+            ),
+            (
+                vec![shb(false), idb(false, &[]), truncated_data],
+                "truncated packet data",
+            ),
+        ];
+        for (blocks, msg) in cases {
+            let err = read_error(&blocks);
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{msg}");
+            assert_eq!(err.to_string(), msg);
+        }
+    }
+
+    #[test]
+    fn truncated_block() {
+        let mut data = [shb(false), idb(false, &[]), epb(false, 0, 0, &[0])].concat();
+        data.truncate(data.len() - 2);
+        let mut rd = Reader::new(data.as_slice()).unwrap();
+        match rd.next_packet(None) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof),
+            Ok(_) => panic!("truncated block was read"),
+        }
+    }
+
+    #[test]
+    fn timestamp_out_of_range() {
+        // one unit per second, seconds do not fit in SystemTime
+        let err = read_error(&[
+            shb(false),
+            idb(false, &[(OPT_IF_TSRESOL, &[0])]),
+            epb(false, 0, u64::MAX, &[0]),
+        ]);
+        assert_eq!(err.to_string(), "timestamp out of range");
+
+        // offset overflows the duration
+        let offset = i64::MAX.to_le_bytes();
+        let err = read_error(&[
+            shb(false),
+            idb(false, &[(OPT_IF_TSRESOL, &[0]), (OPT_IF_TSOFFSET, &offset)]),
+            epb(false, 0, u64::MAX, &[0]),
+        ]);
+        assert_eq!(err.to_string(), "timestamp out of range");
+    }
+}
@@ -1,18 +1,41 @@
 //! Pipe can be used to write packets to outputs at given rate.
 use std::{
+    collections::VecDeque,
     fmt::Display,
-    sync::mpsc::{self, Receiver},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
     thread::{self, JoinHandle},
     time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use crate::{
-    channel::{Rx, Tx},
+    channel::{BufferPool, Monitor, Rx, Tx},
+    classify::ProtocolCounts,
     input::Packet,
-    output::PacketWriter,
+    metrics::Counters,
+    output::{LinkDown, PacketWriter},
+    rewrite::Transform,
+    rng::Rng,
+    selftest::Verdict,
 };
+/// Upper limits of packet size histogram buckets, in bytes. Last bucket
+/// holds packets larger than any of these.
+const SIZE_BUCKETS: [u64; 6] = [64, 128, 256, 512, 1024, 1518];
+
+/// Rate packets were requested to be written with.
+#[derive(Clone, Copy)]
+enum Target {
+    /// Packets per second.
+    Pps(f64),
+    /// Bits per second.
+    Bps(f64),
+}
+
 /// Statistics about processed packets.
 pub struct Stats {
     /// Number of packets processed since start or last reset
@@ -21,26 +44,91 @@ pub struct Stats {
     bytes: u64,
     /// Number of packets which we were not able to send.
     invalid: u64,
+    /// Number of packets truncated before writing.
+    truncated: u64,
+    /// Number of packets dropped by [PacketHook]s.
+    dropped: u64,
+    /// Number of duplicate packets written.
+    duplicated: u64,
+    /// Number of packets written out of their original order.
+    reordered: u64,
+    /// Number of repeated copies of packets written.
+    repeated: u64,
     /// When packet processing has started.
     start: Instant,
     /// Interval for producing stats
     interval: Option<Duration>,
     /// When stats were last produced
     last_stat: Instant,
+    /// Number of packets written when stats were last produced.
+    last_packets: u64,
+    /// Number of bytes written when stats were last produced.
+    last_bytes: u64,
     /// [mpsc::Sender] for sending stats summary
     sender: Option<mpsc::Sender<String>>,
+    /// [mpsc::Sender] for sending [Sample]s along with the summary.
+    samples: Option<mpsc::Sender<Sample>>,
+    /// Total number of packets expected to be processed, if known.
+    total: Option<u64>,
+    /// Number of packets processed before statistics were last reset,
+    /// counted for progress towards `total`.
+    processed_before_reset: u64,
+    /// [Monitor] for the channel packets are read from.
+    channel: Option<Monitor>,
+    /// Counters exported as metrics.
+    metrics: Option<Arc<Counters>>,
+    /// Number of packets written in each size bucket, if enabled.
+    sizes: Option<[u64; SIZE_BUCKETS.len() + 1]>,
+    /// Number of packets processed by protocol, if enabled.
+    protocols: Option<ProtocolCounts>,
+    /// Time from start of processing during which packets are not counted.
+    warmup: Option<Duration>,
+    /// When warmup period ends, if it is still in progress.
+    warmup_end: Option<Instant>,
+    /// Requested rate, if packets are written with constant rate.
+    target: Option<Target>,
+    /// Result of self-test, once it has completed.
+    selftest: Option<Verdict>,
+    /// Callback called every given number of packets.
+    progress: Option<(u64, ProgressCallback)>,
+    /// Number of packets processed since progress callback was last called.
+    since_progress: u64,
 }
 
+/// Function called with current [Stats] while packets are written, see
+/// [Stats::set_progress].
+pub type ProgressCallback = Box<dyn FnMut(&Stats) + Send>;
+
 impl Default for Stats {
     fn default() -> Self {
         Self {
             start: Instant::now(),
             last_stat: Instant::now(),
+            last_packets: 0,
+            last_bytes: 0,
             packets: Default::default(),
             bytes: Default::default(),
             invalid: Default::default(),
+            truncated: Default::default(),
+            dropped: Default::default(),
+            duplicated: Default::default(),
+            reordered: Default::default(),
+            repeated: Default::default(),
             sender: None,
+            samples: None,
             interval: None,
+            total: None,
+            processed_before_reset: 0,
+            channel: None,
+            metrics: None,
+            sizes: None,
+            protocols: None,
+            warmup: None,
+            warmup_end: None,
+            target: None,
+            selftest: None,
+            progress: None,
+            since_progress: 0,
         }
     }
 }
@@ -56,23 +144,96 @@ impl Stats {
             self.invalid += 1
         } else {
             self.packets += 1;
+            if let Some(ref mut sizes) = self.sizes {
+                let idx = SIZE_BUCKETS
+                    .iter()
+                    .position(|max| bytes <= *max)
+                    .unwrap_or(SIZE_BUCKETS.len());
+                sizes[idx] += 1;
+            }
         }
         self.bytes += bytes;
+        if let Some(ref metrics) = self.metrics {
+            metrics.set(self.packets, self.bytes, self.invalid);
+        }
         if let Some(val) = self.interval {
             if self.last_stat.elapsed() > val {
+                let now = Instant::now();
                 if let Err(e) = self
                     .sender
                     .as_ref()
                     .unwrap()
-                    .send(self.summary(Instant::now()))
+                    .send(self.periodic_summary(now))
                 {
                     tracing::warn!("Error while sending stat summary: {}", e)
                 }
-                self.last_stat = Instant::now();
+                if let Some(ref samples) = self.samples {
+                    if let Err(e) = samples.send(self.sample(now)) {
+                        tracing::warn!("Error while sending stat sample: {}", e)
+                    }
+                }
+                self.last_stat = now;
+                self.last_packets = self.packets;
+                self.last_bytes = self.bytes;
+            }
+        }
+        if let Some((every, mut callback)) = self.progress.take() {
+            self.since_progress += 1;
+            if self.since_progress >= every {
+                self.since_progress = 0;
+                callback(self);
+            }
+            self.progress = Some((every, callback));
+        }
+    }
+
+    /// Updates the statistics with a packet of given `kind` written with
+    /// `bytes` bytes, 0 meaning it was not sent. Duplicates are counted
+    /// separately from other packets.
+    fn count_written(&mut self, kind: Kind, bytes: u64) {
+        match kind {
+            Kind::Packet => self.update(bytes),
+            Kind::Duplicate if bytes > 0 => self.duplicated += 1,
+            Kind::Duplicate => {}
+            Kind::Repeat => {
+                if bytes > 0 {
+                    self.repeated += 1;
+                }
+                self.update(bytes);
             }
         }
     }
 
+    /// Returns [String] containing summary of statistics, followed by the
+    /// packets and bytes written since the previous periodic summary.
+    fn periodic_summary(&self, when: Instant) -> String {
+        let elapsed = when.duration_since(self.last_stat);
+        let packets = self.packets - self.last_packets;
+        let bytes = self.bytes - self.last_bytes;
+        format!(
+            "{}, last {}ms: {} packets, {} bytes / {:.3}pps, {:.3}bps",
+            self.summary(when),
+            elapsed.as_millis(),
+            packets,
+            bytes,
+            packets as f64 / elapsed.as_secs_f64(),
+            (bytes as f64 * 8_f64) / elapsed.as_secs_f64()
+        )
+    }
+
+    /// Returns [Sample] of statistics at `when`, with rates since the
+    /// previous periodic summary.
+    fn sample(&self, when: Instant) -> Sample {
+        let elapsed = when.duration_since(self.last_stat).as_secs_f64();
+        Sample {
+            when: SystemTime::now(),
+            packets: self.packets,
+            bytes: self.bytes,
+            pps: (self.packets - self.last_packets) as f64 / elapsed,
+            bps: ((self.bytes - self.last_bytes) as f64 * 8_f64) / elapsed,
+        }
+    }
+
     /// Returns [String] containing summary of statistics.
     fn summary(&self, when: Instant) -> String {
         let elapsed = when.duration_since(self.start);
@@ -80,30 +241,241 @@ impl Stats {
         let bps = (self.bytes as f64 * 8_f64) / elapsed.as_secs_f64();
         let mbps = (self.bytes as f64 / (1024 * 1024) as f64) / elapsed.as_secs_f64();
 
-        let packet_count = match self.invalid {
-            0 => format!("{} packets", self.packets),
-            _ => format!("{} packets ({} not sent)", self.packets, self.invalid),
+        let mut details = Vec::new();
+        if self.invalid > 0 {
+            details.push(format!("{} not sent", self.invalid));
+        }
+        if self.truncated > 0 {
+            details.push(format!("{} truncated", self.truncated));
+        }
+        if self.dropped > 0 {
+            details.push(format!("{} dropped", self.dropped));
+        }
+        if self.duplicated > 0 {
+            details.push(format!("{} duplicated", self.duplicated));
+        }
+        if self.reordered > 0 {
+            details.push(format!("{} reordered", self.reordered));
+        }
+        if self.repeated > 0 {
+            details.push(format!("{} repeated", self.repeated));
+        }
+        let packet_count = if details.is_empty() {
+            format!("{} packets", self.packets)
+        } else {
+            format!("{} packets ({})", self.packets, details.join(", "))
+        };
+        let progress = match self.total {
+            Some(total) if total > 0 => {
+                let done = self.processed_before_reset + self.packets + self.invalid + self.dropped;
+                format!(
+                    "{:.1}% ({}/{} packets), ",
+                    done as f64 * 100.0 / total as f64,
+                    done,
+                    total
+                )
+            }
+            _ => String::new(),
+        };
+
+        let queue = match self.channel {
+            Some(ref monitor) => format!(
+                ", max queue depth: {}, producer stalled: {}ms",
+                monitor.max_depth(),
+                monitor.stalled().as_millis()
+            ),
+            None => String::new(),
+        };
+
+        let sizes = match self.sizes {
+            Some(ref sizes) => {
+                let mut min = 0;
+                let mut buckets = Vec::with_capacity(sizes.len());
+                for (max, count) in SIZE_BUCKETS.iter().zip(sizes) {
+                    buckets.push(format!("{min}-{max}: {count}"));
+                    min = max + 1;
+                }
+                buckets.push(format!(">{}: {}", min - 1, sizes[SIZE_BUCKETS.len()]));
+                format!(", packet sizes: {}", buckets.join(", "))
+            }
+            None => String::new(),
+        };
+
+        let protocols = match self.protocols {
+            Some(ref counts) => format!(", protocols: {}", counts.summary()),
+            None => String::new(),
+        };
+
+        let accuracy = match self.target {
+            Some(Target::Pps(target)) if target > 0.0 => format!(
+                ", requested {:.3}pps ({:+.2}%)",
+                target,
+                (pps - target) * 100.0 / target
+            ),
+            Some(Target::Bps(target)) if target > 0.0 => format!(
+                ", requested {:.3}bps ({:+.2}%)",
+                target,
+                (bps - target) * 100.0 / target
+            ),
+            _ => String::new(),
+        };
+
+        let selftest = match self.selftest {
+            Some(verdict) => format!(", {verdict}"),
+            None => String::new(),
         };
 
         format!(
-            "{}, {} bytes in {}ms / {:.3}pps, {:.3}bps ({:.3} MBps)",
+            "{}{}, {} bytes in {}ms / {:.3}pps, {:.3}bps ({:.3} MBps){}{}{}{}{}",
+            progress,
             packet_count,
             self.bytes,
             elapsed.as_millis(),
             pps,
             bps,
-            mbps
+            mbps,
+            accuracy,
+            queue,
+            sizes,
+            protocols,
+            selftest
         )
     }
 
     /// Reset statistics
     fn reset(&mut self) {
+        // progress is not reset, packets still need to be processed
+        self.processed_before_reset += self.packets + self.invalid + self.dropped;
         self.bytes = 0;
         self.packets = 0;
         self.invalid = 0;
+        self.truncated = 0;
+        self.dropped = 0;
+        self.duplicated = 0;
+        self.reordered = 0;
+        self.repeated = 0;
+        self.last_packets = 0;
+        self.last_bytes = 0;
+        if let Some(ref mut sizes) = self.sizes {
+            *sizes = Default::default();
+        }
+        if let Some(ref mut counts) = self.protocols {
+            *counts = Default::default();
+        }
         self.start = Instant::now();
     }
 
+    /// Starts the warmup period, if one is set. Called when packet
+    /// processing starts.
+    fn start_warmup(&mut self) {
+        self.warmup_end = self.warmup.map(|w| self.start + w);
+    }
+
+    /// Resets statistics if warmup period has ended, so that packets
+    /// processed during it are not counted.
+    fn check_warmup(&mut self) {
+        if self.warmup_end.is_some_and(|end| Instant::now() >= end) {
+            tracing::info!("warmup done, statistics reset");
+            self.warmup_end = None;
+            self.reset();
+            self.last_stat = Instant::now();
+        }
+    }
+
+    /// Resets statistics on user request and sends a marker telling about
+    /// it to the receiver of periodic statistics.
+    fn reset_on_request(&mut self) {
+        self.reset();
+        self.last_stat = Instant::now();
+        if let Some(ref sender) = self.sender {
+            if let Err(e) = sender.send("Statistics reset".to_string()) {
+                tracing::warn!("Error while sending stat summary: {}", e)
+            }
+        }
+    }
+
+    /// Enables counting packets written in size buckets. When enabled,
+    /// summary includes the packet size histogram.
+    pub fn enable_size_histogram(&mut self) {
+        self.sizes = Some(Default::default());
+    }
+
+    /// Enables counting packets by their network and transport protocols.
+    /// When enabled, summary includes the counts.
+    pub fn enable_classify(&mut self) {
+        self.protocols = Some(Default::default());
+    }
+
+    /// Counts packet `data` by its protocols, if enabled.
+    fn classify(&mut self, data: &[u8]) {
+        if let Some(ref mut counts) = self.protocols {
+            counts.count(data);
+        }
+    }
+
+    /// Sets `counters` to be updated with the statistics, for exporting them
+    /// as metrics.
+    pub fn set_metrics(&mut self, counters: Arc<Counters>) {
+        self.metrics = Some(counters);
+    }
+
+    /// Sets the result of self-test to be included in the statistics.
+    pub fn set_selftest(&mut self, verdict: Verdict) {
+        self.selftest = Some(verdict);
+    }
+
+    /// Returns the result of self-test, if one was run.
+    pub fn selftest(&self) -> Option<Verdict> {
+        self.selftest
+    }
+
+    /// Returns the number of packets written since start or last reset.
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+
+    /// Returns the number of bytes written since start or last reset.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the time elapsed since start or last reset.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Sets `callback` to be called with current statistics every `every`
+    /// packets, counting also the packets which were not sent. The callback
+    /// is called from the thread writing the packets, so it should return
+    /// quickly to not slow down writing.
+    pub fn set_progress(&mut self, every: u64, callback: impl FnMut(&Stats) + Send + 'static) {
+        self.progress = Some((every.max(1), Box::new(callback)));
+        self.since_progress = 0;
+    }
+
+    /// Returns the number of packets which were not sent.
+    pub fn invalid(&self) -> u64 {
+        self.invalid
+    }
+
+    /// Returns the number of packets dropped by [PacketHook]s.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Sets period from start of processing during which packets are not
+    /// counted. Statistics are reset once the period has elapsed, so that
+    /// they reflect only the packets processed after it.
+    pub fn set_warmup(&mut self, warmup: Duration) {
+        self.warmup = Some(warmup);
+    }
+
+    /// Sets the total number of packets expected to be processed. When set,
+    /// summary includes the progress towards the total.
+    pub fn set_total(&mut self, total: u64) {
+        self.total = Some(total);
+    }
+
     /// Creates [Stats] which will send summary with given `period` to
     /// returned receiver.
     pub fn periodic(period: Duration) -> (Stats, Receiver<String>) {
@@ -117,6 +489,30 @@ impl Stats {
             receiver,
         )
     }
+
+    /// Returns receiver for [Sample]s of statistics, sent whenever periodic
+    /// summary is sent. Nothing is sent unless these [Stats] were created
+    /// with [Stats::periodic].
+    pub fn samples(&mut self) -> Receiver<Sample> {
+        let (sender, receiver) = mpsc::channel();
+        self.samples = Some(sender);
+        receiver
+    }
+}
+
+/// Periodic sample of statistics, see [Stats::samples].
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    /// When the sample was taken.
+    pub when: SystemTime,
+    /// Number of packets written since start or last reset.
+    pub packets: u64,
+    /// Number of bytes written since start or last reset.
+    pub bytes: u64,
+    /// Packets per second written since the previous sample.
+    pub pps: f64,
+    /// Bits per second written since the previous sample.
+    pub bps: f64,
 }
 
 impl Display for Stats {
@@ -125,18 +521,295 @@ impl Display for Stats {
     }
 }
 
+/// Random delay added before each packet.
+#[derive(Clone, Copy)]
+pub struct Jitter {
+    /// Maximum delay to add.
+    pub max: Duration,
+    /// Seed for the random number generator.
+    pub seed: u64,
+}
+
+/// Random duplication of written packets.
+#[derive(Clone, Copy)]
+pub struct Duplicate {
+    /// Fraction (`0.0` to `1.0`) of packets to write twice.
+    pub fraction: f64,
+    /// Seed for the random number generator.
+    pub seed: u64,
+}
+
+/// Random reordering of packets within a window.
+#[derive(Clone, Copy)]
+pub struct Reorder {
+    /// Number of packets in the window.
+    pub window: usize,
+    /// Seed for the random number generator.
+    pub seed: u64,
+}
+
+/// Writing every packet several times in a row.
+#[derive(Clone, Copy)]
+pub struct Repeat {
+    /// Number of times each packet is written.
+    pub count: u32,
+    /// Wait before each copy like before any other packet. Otherwise the
+    /// copies are written right after the packet.
+    pub paced: bool,
+}
+
+/// [Iterator] returning every packet `count` times in a row.
+struct Repeated<I> {
+    inner: I,
+    count: u32,
+    /// Packet to return again and the number of times left to return it.
+    pending: Option<(Packet, u32)>,
+    /// Pool the buffers for copies are taken from.
+    pool: BufferPool,
+    /// Was the last packet returned a repeated copy.
+    copy: bool,
+}
+
+impl<I: Iterator<Item = Packet>> Repeated<I> {
+    fn new(inner: I, count: u32, pool: BufferPool) -> Self {
+        Repeated {
+            inner,
+            count,
+            pending: None,
+            pool,
+            copy: false,
+        }
+    }
+
+    /// Returns copy of `pkt` with data buffer from the pool.
+    fn copy_of(&self, pkt: &Packet) -> Packet {
+        Packet {
+            data: self.pool.copy_of(&pkt.data),
+            when: pkt.when,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Packet>> Iterator for Repeated<I> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((pkt, left)) = self.pending.take() {
+            self.copy = true;
+            if left > 1 {
+                let copy = self.copy_of(&pkt);
+                self.pending = Some((pkt, left - 1));
+                return Some(copy);
+            }
+            return Some(pkt);
+        }
+        self.copy = false;
+        let pkt = self.inner.next()?;
+        if self.count > 1 {
+            self.pending = Some((self.copy_of(&pkt), self.count - 1));
+        }
+        Some(pkt)
+    }
+}
+
+/// [Iterator] holding up to `window` packets and returning randomly chosen
+/// one of them next.
+///
+/// Packets move, but timestamps stay in their place: the packet returned
+/// gets the timestamp of the earliest packet in the window and the packets
+/// it passed get the timestamps of the following packets. Thus the times
+/// packets are written at are kept, only the order of the packets changes.
+struct Reordered<I> {
+    inner: I,
+    window: VecDeque<Packet>,
+    size: usize,
+    rng: Rng,
+    /// Number of packets returned before some earlier packet, since the
+    /// count was last taken.
+    reordered: u64,
+}
+
+impl<I: Iterator<Item = Packet>> Reordered<I> {
+    fn new(inner: I, reorder: Reorder) -> Self {
+        Reordered {
+            inner,
+            window: VecDeque::with_capacity(reorder.window),
+            size: reorder.window,
+            rng: Rng::new(reorder.seed),
+            reordered: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = Packet>> Iterator for Reordered<I> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.window.len() < self.size {
+            match self.inner.next() {
+                Some(pkt) => self.window.push_back(pkt),
+                None => break,
+            }
+        }
+        if self.window.is_empty() {
+            return None;
+        }
+        let idx = (self.rng.next_u64() % self.window.len() as u64) as usize;
+        let mut pkt = self.window.remove(idx)?;
+        if idx > 0 {
+            self.reordered += 1;
+            let mut when = pkt.when;
+            for passed in self.window.range_mut(..idx).rev() {
+                std::mem::swap(&mut passed.when, &mut when);
+            }
+            pkt.when = when;
+        }
+        Some(pkt)
+    }
+}
+
+/// Returns [PacketHook] dropping randomly given `fraction` (`0.0` to `1.0`)
+/// of packets, using random number generator seeded with `seed`.
+pub fn random_drop(fraction: f64, seed: u64) -> PacketHook {
+    let mut rng = Rng::new(seed);
+    Box::new(move |_| rng.next_f64() >= fraction)
+}
+
+/// Function called with the iteration number and the total number of
+/// packets processed when an iteration over the input has been processed,
+/// see [LoopMarkers].
+pub type MarkerCallback = Box<dyn FnMut(u64, u64) + Send>;
+
+/// State of [LoopMarkers].
+struct MarkerState {
+    /// Number of packets processed by the writer.
+    processed: u64,
+    /// Number of iterations read.
+    iterations: u64,
+    /// Iterations read, but not yet processed by the writer, with the total
+    /// number of packets at the end of the iteration.
+    pending: VecDeque<(u64, u64)>,
+    /// Called at the end of each iteration.
+    callback: MarkerCallback,
+}
+
+/// Marks the points where an iteration over the input has been processed by
+/// the writer, by calling a [MarkerCallback].
+///
+/// The reader records where each iteration ends and the writer calls the
+/// callback once it has processed the last packet of the iteration. The
+/// total number of packets processed given to the callback includes
+/// packets not sent.
+#[derive(Clone)]
+pub struct LoopMarkers(Arc<Mutex<MarkerState>>);
+
+impl LoopMarkers {
+    /// Creates [LoopMarkers] calling `callback` with the iteration number
+    /// and the total number of packets processed at the end of each
+    /// iteration.
+    pub fn new(callback: impl FnMut(u64, u64) + Send + 'static) -> Self {
+        LoopMarkers(Arc::new(Mutex::new(MarkerState {
+            processed: 0,
+            iterations: 0,
+            pending: VecDeque::new(),
+            callback: Box::new(callback),
+        })))
+    }
+
+    /// Records that an iteration over the input ended after `packets`
+    /// packets in total were read.
+    pub fn iteration_read(&self, packets: u64) {
+        let mut state = self.0.lock().unwrap();
+        state.iterations += 1;
+        let iteration = state.iterations;
+        if state.processed >= packets {
+            (state.callback)(iteration, packets);
+        } else {
+            state.pending.push_back((iteration, packets));
+        }
+    }
+
+    /// Records that writer has processed `packets` packets in total.
+    fn processed(&self, packets: u64) {
+        let mut state = self.0.lock().unwrap();
+        state.processed = packets;
+        while let Some(&(iteration, end)) = state.pending.front() {
+            if end > packets {
+                break;
+            }
+            (state.callback)(iteration, end);
+            state.pending.pop_front();
+        }
+    }
+}
+
+/// Function called with every packet before it is written. The packet can be
+/// modified, returning `false` drops the packet and counts it as dropped in
+/// [Stats].
+pub type PacketHook = Box<dyn FnMut(&mut Packet) -> bool + Send>;
+
+/// Options for writing packets, common to all packet rates.
+#[derive(Default)]
+pub struct Options {
+    /// Random delay to add before each packet.
+    pub jitter: Option<Jitter>,
+    /// Transforms applied to each packet before it is written. Packets
+    /// failing a transform are not written and are counted as not sent.
+    pub transforms: Vec<Box<dyn Transform + Send>>,
+    /// Hooks called in order for each packet after transforms, until one
+    /// of them drops the packet.
+    pub hooks: Vec<PacketHook>,
+    /// Packets longer than this are truncated after transforms are applied.
+    pub snaplen: Option<usize>,
+    /// Writing is held while this is set to nonzero value.
+    pub pause: Option<Arc<AtomicUsize>>,
+    /// Statistics are reset when this is set, the flag is cleared then.
+    pub reset_stats: Option<Arc<AtomicBool>>,
+    /// With constant packet or bit rate, restart the schedule after writing
+    /// has stalled instead of writing without delay to catch up.
+    pub no_catchup: bool,
+    /// Never write more than this many packets per second, regardless of
+    /// the rate.
+    pub max_pps: Option<u32>,
+    /// Markers called when iterations over the input have been processed.
+    pub loop_markers: Option<LoopMarkers>,
+    /// Write some packets twice, the duplicate immediately after the
+    /// packet.
+    pub duplicate: Option<Duplicate>,
+    /// Write packets in random order within a window.
+    pub reorder: Option<Reorder>,
+    /// Write every packet several times in a row.
+    pub repeat: Option<Repeat>,
+    /// Largest number of packets written together when they are due
+    /// without delay. Values 0 and 1 write every packet separately.
+    pub batch: usize,
+    /// CPU the writer thread is pinned to.
+    pub writer_cpu: Option<usize>,
+    /// Real-time `SCHED_FIFO` priority for the writer thread.
+    pub writer_rt_priority: Option<i32>,
+    /// Busy-wait instead of sleeping for the last millisecond before a
+    /// packet is due, for more precise timing at the cost of CPU time.
+    pub spin: bool,
+    /// Stop writing once this much time has passed since writing started.
+    pub max_duration: Option<Duration>,
+    /// Wall-clock time to wait for before writing the first packet.
+    pub start_at: Option<SystemTime>,
+}
+
 /// Pipe can be used to process packets from packet iterator to output
 pub struct Pipe {
     /// Handle for writer thread.
-    wr_handle: JoinHandle<Result<Stats>>,
+    wr_handle: JoinHandle<(Stats, Result<()>)>,
 }
 
 impl Pipe {
     /// Waits until packet processor thread for this [Pipe] has stopped.
-    pub fn wait(self) -> Result<Stats> {
-        let wr_stat = self.wr_handle.join().unwrap()?;
+    /// Returns [Stats] of the packets processed, and the error which
+    /// stopped writing, if any.
+    pub fn wait(self) -> (Stats, Result<()>) {
+        let (wr_stat, result) = self.wr_handle.join().unwrap();
         tracing::trace!("Writer terminated, processed: {}", wr_stat);
-        Ok(wr_stat)
+        (wr_stat, result)
     }
 }
 
@@ -157,6 +830,24 @@ trait Delayer {
     fn init(&mut self);
     /// Returns how long to wait before writing given [Packet].
     fn wait_time_for(&mut self, pkt: &Packet) -> Option<Duration>;
+    /// Called when writing has been paused for `duration`. Delayers
+    /// following a schedule should move it forward, so that they do not try
+    /// to catch up the time spent paused.
+    fn paused(&mut self, _duration: Duration) {}
+}
+
+impl<D: Delayer + ?Sized> Delayer for Box<D> {
+    fn init(&mut self) {
+        (**self).init()
+    }
+
+    fn wait_time_for(&mut self, pkt: &Packet) -> Option<Duration> {
+        (**self).wait_time_for(pkt)
+    }
+
+    fn paused(&mut self, duration: Duration) {
+        (**self).paused(duration)
+    }
 }
 
 /// [Delayer] which will cause every packet to be sent immediately
@@ -175,15 +866,20 @@ struct BpsDelay {
     start: Instant,
     bits_sent: u64,
     bps: u64,
+    /// Try to catch up lost time after writing has stalled.
+    catch_up: bool,
 }
 
 impl BpsDelay {
     /// Creates new [BpsDelay] with given speed (as in bits per second).
-    fn new(bps: u64) -> Self {
+    /// Unless `catch_up` is set, schedule is restarted after a stall
+    /// instead of writing packets without delay until rate is reached.
+    fn new(bps: u64, catch_up: bool) -> Self {
         BpsDelay {
             start: Instant::now(),
             bits_sent: 0,
             bps,
+            catch_up,
         }
     }
 }
@@ -193,10 +889,26 @@ impl Delayer for BpsDelay {
         self.start = Instant::now();
     }
 
+    fn paused(&mut self, duration: Duration) {
+        self.start += duration;
+    }
+
     fn wait_time_for(&mut self, pkt: &Packet) -> Option<Duration> {
-        let estimated = Duration::from_micros((self.bits_sent * 1_000_000) / self.bps);
+        // multiplying in u128 as bits_sent * 1_000_000 overflows u64 after
+        // a few terabits
+        let micros = u128::from(self.bits_sent) * 1_000_000 / u128::from(self.bps);
+        let estimated = Duration::from_micros(u64::try_from(micros).unwrap_or(u64::MAX));
         let elapsed = self.start.elapsed();
-        self.bits_sent += pkt.data.len() as u64 * 8;
+        let bits = pkt.data.len() as u64 * 8;
+        let interval = Duration::from_secs_f64(bits as f64 / self.bps as f64);
+        if !self.catch_up && elapsed > estimated + interval {
+            // stalled for longer than it takes to write this packet, start
+            // new schedule from this packet
+            self.start = Instant::now();
+            self.bits_sent = bits;
+            return None;
+        }
+        self.bits_sent += bits;
         if elapsed < estimated {
             Some(estimated - elapsed)
         } else {
@@ -205,21 +917,80 @@ impl Delayer for BpsDelay {
     }
 }
 
+/// [Delayer] implementing token bucket: packets are written with given bits
+/// per second speed on average, but bursts up to the bucket capacity are
+/// written without delay.
+struct TokenBucketDelay {
+    /// Rate at which tokens (bits) are added to the bucket.
+    bps: f64,
+    /// Maximum number of tokens (bits) in the bucket.
+    capacity: f64,
+    /// Tokens currently in the bucket. Negative if the last packet did not
+    /// fit into the bucket.
+    tokens: f64,
+    /// When tokens were last added.
+    last: Instant,
+}
+
+impl TokenBucketDelay {
+    /// Creates new [TokenBucketDelay] with given speed (as in bits per
+    /// second) and bucket capacity in bytes.
+    fn new(bps: u64, burst: u64) -> Self {
+        let capacity = burst as f64 * 8.0;
+        TokenBucketDelay {
+            bps: bps as f64,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl Delayer for TokenBucketDelay {
+    fn init(&mut self) {
+        self.tokens = self.capacity;
+        self.last = Instant::now();
+    }
+
+    fn paused(&mut self, duration: Duration) {
+        self.last += duration;
+    }
+
+    fn wait_time_for(&mut self, pkt: &Packet) -> Option<Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.bps;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last = now;
+        self.tokens -= pkt.data.len() as f64 * 8.0;
+        if self.tokens >= 0.0 {
+            None
+        } else {
+            // wait until the bucket has refilled enough to cover the packet
+            Some(Duration::from_secs_f64(-self.tokens / self.bps))
+        }
+    }
+}
+
 /// [Delayer] which will cause to write packets to be written with given
 /// packets per second speed.
 struct PpsDelay {
     start: Instant,
     packets: u64,
     pps: u64,
+    /// Try to catch up lost time after writing has stalled.
+    catch_up: bool,
 }
 
 impl PpsDelay {
     /// Creates new [PpsDelay] with given speed (as in packets per second).
-    fn new(pps: u32) -> Self {
+    /// Unless `catch_up` is set, schedule is restarted after a stall
+    /// instead of writing packets without delay until rate is reached.
+    fn new(pps: u32, catch_up: bool) -> Self {
         PpsDelay {
             start: Instant::now(),
             packets: 0,
             pps: u64::from(pps),
+            catch_up,
         }
     }
 }
@@ -229,6 +1000,10 @@ impl Delayer for PpsDelay {
         self.start = Instant::now();
     }
 
+    fn paused(&mut self, duration: Duration) {
+        self.start += duration;
+    }
+
     fn wait_time_for(&mut self, _pkt: &Packet) -> Option<Duration> {
         if self.packets == 0 {
             self.packets += 1;
@@ -236,8 +1011,18 @@ impl Delayer for PpsDelay {
         }
         let elapsed = self.start.elapsed();
         // calculate how log it should have taken us to send this many
-        // packets.
-        let estimated = Duration::from_micros((self.packets * 1_000_000) / self.pps);
+        // packets. Nanoseconds keep the interval above zero with rates over
+        // million packets per second.
+        let nanos = u128::from(self.packets) * 1_000_000_000 / u128::from(self.pps);
+        let estimated = Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+        let interval = Duration::from_nanos(1_000_000_000 / self.pps);
+        if !self.catch_up && elapsed > estimated + interval {
+            // stalled for longer than one interval, start new schedule from
+            // this packet
+            self.start = Instant::now();
+            self.packets = 1;
+            return None;
+        }
         self.packets += 1;
         if estimated > elapsed {
             Some(estimated - elapsed)
@@ -248,6 +1033,106 @@ impl Delayer for PpsDelay {
     }
 }
 
+/// Packet rate changing linearly over time.
+#[derive(Clone, Copy, Debug)]
+pub struct Ramp {
+    /// Packets per second at start.
+    pub from: u32,
+    /// Packets per second at the end of ramp-up.
+    pub to: u32,
+    /// Time it takes for the rate to change from `from` to `to`, and back
+    /// when ramping down.
+    pub duration: Duration,
+    /// Time the rate is held at `to` before ramping back down to `from`.
+    /// If not given, rate stays at `to` after ramping up.
+    pub hold: Option<Duration>,
+}
+
+impl Ramp {
+    /// Returns packets per second `elapsed` time after start.
+    fn rate_at(&self, elapsed: Duration) -> f64 {
+        let from = f64::from(self.from);
+        let to = f64::from(self.to);
+        let ramp = self.duration.as_secs_f64();
+        let mut t = elapsed.as_secs_f64();
+        if t < ramp {
+            return from + (to - from) * t / ramp;
+        }
+        t -= ramp;
+        let Some(hold) = self.hold else {
+            return to;
+        };
+        t -= hold.as_secs_f64();
+        if t < 0.0 {
+            to
+        } else if t < ramp {
+            to + (from - to) * t / ramp
+        } else {
+            from
+        }
+    }
+}
+
+/// [Delayer] writing packets with rate following a [Ramp].
+///
+/// Each packet is scheduled one interval at the current rate after the
+/// previous one, so the rate follows the ramp as closely as the packet
+/// intervals allow.
+struct RampDelay {
+    ramp: Ramp,
+    start: Instant,
+    /// When the next packet is due, [None] before the first packet.
+    next: Option<Instant>,
+    /// Try to catch up lost time after writing has stalled.
+    catch_up: bool,
+}
+
+impl RampDelay {
+    /// Creates new [RampDelay] following `ramp`. Unless `catch_up` is set,
+    /// schedule is restarted after a stall instead of writing packets
+    /// without delay until the schedule is reached.
+    fn new(ramp: Ramp, catch_up: bool) -> Self {
+        RampDelay {
+            ramp,
+            start: Instant::now(),
+            next: None,
+            catch_up,
+        }
+    }
+
+    /// Returns time between packets with the rate at `at`.
+    fn interval_at(&self, at: Instant) -> Duration {
+        let rate = self.ramp.rate_at(at.saturating_duration_since(self.start));
+        Duration::from_secs_f64(1.0 / rate)
+    }
+}
+
+impl Delayer for RampDelay {
+    fn init(&mut self) {
+        self.start = Instant::now();
+        self.next = None;
+    }
+
+    fn paused(&mut self, duration: Duration) {
+        self.start += duration;
+        if let Some(ref mut next) = self.next {
+            *next += duration;
+        }
+    }
+
+    fn wait_time_for(&mut self, _pkt: &Packet) -> Option<Duration> {
+        let now = Instant::now();
+        let mut due = self.next.unwrap_or(now);
+        if !self.catch_up && now > due + self.interval_at(due) {
+            // stalled for longer than one interval, continue the schedule
+            // from this packet
+            due = now;
+        }
+        self.next = Some(due + self.interval_at(due));
+        due.checked_duration_since(now).filter(|d| !d.is_zero())
+    }
+}
+
 /// [Delayer] which will delay packets according to delay on their original
 /// timestamps.
 ///
@@ -255,12 +1140,22 @@ impl Delayer for PpsDelay {
 /// it is desired to write them at the same speed as they were captured.
 struct PacketRateDelay {
     last_packet: Option<SystemTime>,
+    /// Multiplier for replay speed, delays are divided by this.
+    speed: f64,
+    /// Longest delay allowed between packets.
+    max_gap: Option<Duration>,
 }
 
 impl PacketRateDelay {
-    /// Returns new [PacketRateDelay]
-    fn new() -> PacketRateDelay {
-        PacketRateDelay { last_packet: None }
+    /// Returns new [PacketRateDelay] replaying packets `speed` times faster
+    /// than they were captured, never waiting longer than `max_gap` between
+    /// packets.
+    fn new(speed: f64, max_gap: Option<Duration>) -> PacketRateDelay {
+        PacketRateDelay {
+            last_packet: None,
+            speed,
+            max_gap,
+        }
     }
 }
 
@@ -270,38 +1165,452 @@ impl Delayer for PacketRateDelay {
     fn wait_time_for(&mut self, pkt: &Packet) -> Option<Duration> {
         let ret = self
             .last_packet
-            .and_then(|t| pkt.when.duration_since(t).ok());
+            .and_then(|t| pkt.when.duration_since(t).ok())
+            .map(|d| d.div_f64(self.speed))
+            .map(|d| match self.max_gap {
+                Some(max) => d.min(max),
+                None => d,
+            });
         self.last_packet = Some(pkt.when);
         ret
     }
 }
 
+/// [Delayer] which waits given time between every packet.
+struct FixedGapDelay {
+    gap: Duration,
+    /// Set once the first packet has been written.
+    started: bool,
+}
+
+impl FixedGapDelay {
+    /// Returns new [FixedGapDelay] waiting `gap` between packets.
+    fn new(gap: Duration) -> FixedGapDelay {
+        FixedGapDelay {
+            gap,
+            started: false,
+        }
+    }
+}
+
+impl Delayer for FixedGapDelay {
+    fn init(&mut self) {}
+
+    fn wait_time_for(&mut self, _pkt: &Packet) -> Option<Duration> {
+        if self.started {
+            Some(self.gap)
+        } else {
+            self.started = true;
+            None
+        }
+    }
+}
+
+/// Longest time the writer sleeps before checking if it should terminate.
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+/// Final part of a wait spent busy-waiting instead of sleeping when
+/// [Options::spin] is set.
+const SPIN_THRESHOLD: Duration = Duration::from_millis(1);
+
+/// Sleeps for given `duration` in at most [SLEEP_SLICE] long increments,
+/// checking `stop` between them. With `spin`, the last [SPIN_THRESHOLD] of
+/// the duration is busy-waited for instead, as sleeping may overshoot by
+/// about that much. Returns `false` if sleep was interrupted because `stop`
+/// was set.
+fn interruptible_sleep(duration: Duration, stop: &AtomicBool, spin: bool) -> bool {
+    let until = Instant::now() + duration;
+    let wake = if spin {
+        until - duration.min(SPIN_THRESHOLD)
+    } else {
+        until
+    };
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= wake {
+            break;
+        }
+        thread::sleep((wake - now).min(SLEEP_SLICE));
+    }
+    while Instant::now() < until {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        std::hint::spin_loop();
+    }
+    true
+}
+
+/// How often the writer checks if it should resume when paused.
+const PAUSE_POLL: Duration = Duration::from_millis(10);
+
+/// Waits while `pause` is set. Returns the time spent paused, or [None] if
+/// `stop` was set while waiting.
+fn wait_while_paused(pause: &AtomicUsize, stop: &AtomicBool) -> Option<Duration> {
+    if pause.load(Ordering::Relaxed) == 0 {
+        return Some(Duration::ZERO);
+    }
+    tracing::info!("replay paused");
+    let start = Instant::now();
+    while pause.load(Ordering::Relaxed) != 0 {
+        if stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        thread::sleep(PAUSE_POLL);
+    }
+    tracing::info!("replay resumed");
+    Some(start.elapsed())
+}
+
+/// [Delayer] combining two [Delayer]s by waiting the longer of their wait
+/// times. Used for capping the packet rate of another [Delayer] with
+/// [PpsDelay].
+struct MaxDelay<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: Delayer, B: Delayer> Delayer for MaxDelay<A, B> {
+    fn init(&mut self) {
+        self.first.init();
+        self.second.init();
+    }
+
+    fn paused(&mut self, duration: Duration) {
+        self.first.paused(duration);
+        self.second.paused(duration);
+    }
+
+    fn wait_time_for(&mut self, pkt: &Packet) -> Option<Duration> {
+        let first = self.first.wait_time_for(pkt);
+        let second = self.second.wait_time_for(pkt);
+        first.max(second)
+    }
+}
+
+/// [Delayer] adding uniformly random delay to the wait time of another
+/// [Delayer].
+///
+/// With delayers following an absolute schedule (packet or bit rate) the
+/// added delay does not accumulate, as following packets are written
+/// sooner to catch up. With [PacketRateDelay] every gap between packets is
+/// extended.
+struct JitterDelay<D> {
+    inner: D,
+    max: Duration,
+    rng: Rng,
+}
+
+impl<D: Delayer> JitterDelay<D> {
+    /// Creates [JitterDelay] adding random delay defined by `jitter` to
+    /// wait times from `inner`.
+    fn new(inner: D, jitter: Jitter) -> Self {
+        JitterDelay {
+            inner,
+            max: jitter.max,
+            rng: Rng::new(jitter.seed),
+        }
+    }
+}
+
+impl<D: Delayer> Delayer for JitterDelay<D> {
+    fn init(&mut self) {
+        self.inner.init()
+    }
+
+    fn paused(&mut self, duration: Duration) {
+        self.inner.paused(duration)
+    }
+
+    fn wait_time_for(&mut self, pkt: &Packet) -> Option<Duration> {
+        let extra = self.max.mul_f64(self.rng.next_f64());
+        let wait = self.inner.wait_time_for(pkt).unwrap_or_default() + extra;
+        if wait.is_zero() {
+            None
+        } else {
+            Some(wait)
+        }
+    }
+}
+
+/// Kind of a written packet, for counting it in [Stats].
+#[derive(Clone, Copy)]
+enum Kind {
+    /// Packet read from the input.
+    Packet,
+    /// Duplicate written with [Options::duplicate].
+    Duplicate,
+    /// Copy of a packet written with [Options::repeat].
+    Repeat,
+}
+
+/// Packets waiting to be written together with
+/// [PacketWriter::write_batch].
+struct Batch {
+    packets: Vec<Packet>,
+    /// Kind of the packet at the same index.
+    kinds: Vec<Kind>,
+    /// Pool buffers of written packets are returned to.
+    pool: BufferPool,
+}
+
+impl Batch {
+    fn new(pool: BufferPool) -> Self {
+        Batch {
+            packets: Vec::new(),
+            kinds: Vec::new(),
+            pool,
+        }
+    }
+
+    fn push(&mut self, packet: Packet, kind: Kind) {
+        self.packets.push(packet);
+        self.kinds.push(kind);
+    }
+
+    fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Writes the packets to `output` and updates `stats` with them,
+    /// emptying the batch. Returns error if link of the output went down
+    /// and `false` if writing failed otherwise.
+    fn write(&mut self, output: &mut impl PacketWriter, stats: &mut Stats) -> Result<bool> {
+        let mut written = Vec::with_capacity(self.packets.len());
+        let ret = output.write_batch(&self.packets, &mut written);
+        for (len, kind) in written.into_iter().zip(self.kinds.drain(..)) {
+            stats.count_written(kind, len as u64);
+        }
+        self.kinds.clear();
+        for pkt in self.packets.drain(..) {
+            self.pool.recycle(pkt.data);
+        }
+        match ret {
+            Ok(()) => Ok(true),
+            Err(e) if e.is::<LinkDown>() => {
+                anyhow::bail!("{} after {} packets were written", e, stats.packets);
+            }
+            Err(e) => {
+                tracing::error!("Unable to write packet: {}", e);
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// How long before the time packet is due it is given to output which
+/// schedules the transmission.
+const SCHEDULE_AHEAD: Duration = Duration::from_millis(2);
+
 /// Writes packets from `Rx` to `output` using `delay` to manage the speed
-/// in which packets are written.
+/// in which packets are written. If `output` can schedule transmission,
+/// packets are given to it shortly before they are due, with the time they
+/// are due.
+///
+/// Writing stops early if the stop flag of `rx` is set, also while waiting
+/// for the next packet to be due. `stats` are updated as packets are
+/// written, so they are valid also when an error stops writing.
+///
+/// Packets due without delay are written in batches of up to
+/// [Options::batch] packets. Packets are not held in the batch waiting for
+/// more packets to arrive to the channel.
 fn write_packets(
     rx: Rx,
     mut output: impl PacketWriter,
     mut delay: impl Delayer,
-    mut stats: Stats,
-) -> Result<Stats> {
+    stats: &mut Stats,
+    mut opts: Options,
+) -> Result<()> {
+    let stop = rx.stop_flag();
+    if let Some(start_at) = opts.start_at {
+        if let Ok(wait) = start_at.duration_since(SystemTime::now()) {
+            tracing::info!("waiting {:.3}s for start time", wait.as_secs_f64());
+            if !interruptible_sleep(wait, &stop, opts.spin) {
+                return Ok(());
+            }
+        }
+    }
     stats.reset();
+    stats.start_warmup();
+    stats.channel = Some(rx.monitor());
+    let monitor = rx.monitor();
+    let pool = rx.pool();
     delay.init();
-    for pkt in rx {
-        if let Some(wait_time) = delay.wait_time_for(&pkt) {
-            tracing::trace!("sleeping {}us before write", wait_time.as_micros());
-            thread::sleep(wait_time);
+    let deadline = opts.max_duration.map(|d| Instant::now() + d);
+    let mut processed: u64 = 0;
+    let mut duplicate = opts.duplicate.map(|d| (d.fraction, Rng::new(d.seed)));
+    let schedule = output.can_schedule();
+    let mut batch = Batch::new(pool.clone());
+    let repeat = opts.repeat.unwrap_or(Repeat {
+        count: 1,
+        paced: true,
+    });
+    // window of one packet keeps the order
+    let mut packets = Repeated::new(
+        Reordered::new(
+            rx.into_iter(),
+            opts.reorder.unwrap_or(Reorder { window: 1, seed: 0 }),
+        ),
+        repeat.count,
+        pool.clone(),
+    );
+    while let Some(mut pkt) = packets.next() {
+        if deadline.is_some_and(|end| Instant::now() >= end) {
+            tracing::info!("maximum duration reached, terminating writer");
+            stop.store(true, Ordering::Relaxed);
+            break;
+        }
+        let kind = if packets.copy {
+            Kind::Repeat
+        } else {
+            processed += 1;
+            Kind::Packet
+        };
+        // counted as they happen, so that resetting statistics clears them
+        stats.reordered += std::mem::take(&mut packets.inner.reordered);
+        stats.check_warmup();
+        if let Some(ref flag) = opts.reset_stats {
+            if flag.swap(false, Ordering::Relaxed) {
+                tracing::info!("statistics reset");
+                stats.reset_on_request();
+            }
+        }
+        if !opts.transforms.iter_mut().all(|t| t.apply(&mut pkt.data)) {
+            stats.update(0);
+            pool.recycle(pkt.data);
+            if let Some(markers) = opts.loop_markers.as_ref().filter(|_| batch.is_empty()) {
+                markers.processed(processed);
+            }
+            continue;
+        }
+        if !opts.hooks.iter_mut().all(|hook| hook(&mut pkt)) {
+            stats.dropped += 1;
+            pool.recycle(pkt.data);
+            if let Some(markers) = opts.loop_markers.as_ref().filter(|_| batch.is_empty()) {
+                markers.processed(processed);
+            }
+            continue;
+        }
+        if let Some(snaplen) = opts.snaplen.filter(|len| pkt.data.len() > *len) {
+            pkt.data.truncate(snaplen);
+            stats.truncated += 1;
+        }
+        stats.classify(&pkt.data);
+        if let Some(ref pause) = opts.pause {
+            match wait_while_paused(pause, &stop) {
+                Some(paused) if !paused.is_zero() => delay.paused(paused),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let mut due = None;
+        // unpaced copies are written right after the packet
+        let wait = if packets.copy && !repeat.paced {
+            None
+        } else {
+            delay.wait_time_for(&pkt)
+        };
+        if let Some(wait_time) = wait {
+            // packets are not held in batch while waiting
+            if !batch.is_empty() && !batch.write(&mut output, stats)? {
+                break;
+            }
+            // when output schedules transmission, wake up early and leave
+            // the rest of the wait to it
+            let sleep = if schedule {
+                due = Some(Instant::now() + wait_time);
+                wait_time.saturating_sub(SCHEDULE_AHEAD)
+            } else {
+                wait_time
+            };
+            tracing::trace!("sleeping {}us before write", sleep.as_micros());
+            // spinning is pointless when the output does the final wait
+            let spin = opts.spin && !schedule;
+            // do not wait past the deadline for a packet which is not
+            // going to be written
+            if let Some(left) = deadline.map(|end| end.saturating_duration_since(Instant::now())) {
+                if left < sleep {
+                    if interruptible_sleep(left, &stop, spin) {
+                        tracing::info!("maximum duration reached, terminating writer");
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    break;
+                }
+            }
+            if !sleep.is_zero() && !interruptible_sleep(sleep, &stop, spin) {
+                tracing::trace!("sleep interrupted, terminating writer");
+                break;
+            }
+        }
+        let dup = duplicate
+            .as_mut()
+            .is_some_and(|(fraction, rng)| rng.next_f64() < *fraction);
+        if opts.batch > 1 && due.is_none() {
+            let dup_data = dup.then(|| pool.copy_of(&pkt.data));
+            batch.push(pkt, kind);
+            if let Some(data) = dup_data {
+                let when = SystemTime::now();
+                batch.push(Packet { data, when }, Kind::Duplicate);
+            }
+            if (batch.len() >= opts.batch || monitor.depth() == 0)
+                && !batch.write(&mut output, stats)?
+            {
+                break;
+            }
+            if let Some(markers) = opts.loop_markers.as_ref().filter(|_| batch.is_empty()) {
+                markers.processed(processed);
+            }
+            continue;
         }
-        match output.write_packet(pkt) {
+        let written = match due {
+            Some(at) => output.write_scheduled(&pkt, at),
+            None => output.write_packet(&pkt),
+        };
+        match written {
             Ok(len) => {
-                stats.update(len as u64);
+                stats.count_written(kind, len as u64);
+            }
+            Err(e) if e.is::<LinkDown>() => {
+                anyhow::bail!("{} after {} packets were written", e, stats.packets);
             }
             Err(e) => {
                 tracing::error!("Unable to write packet: {}", e);
                 break;
             }
         }
+        if dup {
+            match output.write_raw(&pkt.data) {
+                Ok(len) if len > 0 => stats.duplicated += 1,
+                Ok(_) => {}
+                Err(e) if e.is::<LinkDown>() => {
+                    anyhow::bail!("{} after {} packets were written", e, stats.packets);
+                }
+                Err(e) => {
+                    tracing::error!("Unable to write duplicate packet: {}", e);
+                    break;
+                }
+            }
+        }
+        pool.recycle(pkt.data);
+        if let Some(ref markers) = opts.loop_markers {
+            markers.processed(processed);
+        }
+    }
+    if !batch.is_empty() && batch.write(&mut output, stats)? {
+        if let Some(ref markers) = opts.loop_markers {
+            markers.processed(processed);
+        }
     }
-    Ok(stats)
+    // statistics are kept by the caller, packets counted there might still
+    // be lost
+    output.flush().context("Unable to flush output")
 }
 
 /// Returns a [Pipe] writing packets from `rx` to `output` using `delayer`.
@@ -310,25 +1619,69 @@ fn create_pipe_for(
     output: impl PacketWriter + Send + 'static,
     delayer: impl Delayer + Send + 'static,
     stats: Stats,
+    opts: Options,
 ) -> Result<Pipe> {
+    let mut delayer: Box<dyn Delayer + Send> = Box::new(delayer);
+    if let Some(pps) = opts.max_pps {
+        // the cap must not allow bursts after idle periods, so it does not
+        // catch up
+        delayer = Box::new(MaxDelay {
+            first: delayer,
+            second: PpsDelay::new(pps, false),
+        });
+    }
+    if let Some(jitter) = opts.jitter {
+        delayer = Box::new(JitterDelay::new(delayer, jitter));
+    }
+    let cpu = opts.writer_cpu;
+    let priority = opts.writer_rt_priority;
     let wr_handle = thread::Builder::new()
         .name("pkt-writer".to_string())
-        .spawn(|| write_packets(rx, output, delayer, stats))?;
+        .spawn(move || {
+            let mut stats = stats;
+            let result = write_packets(rx, output, delayer, &mut stats, opts);
+            (stats, result)
+        })?;
+    if let Some(cpu) = cpu {
+        crate::sched::pin(&wr_handle, cpu);
+    }
+    if let Some(priority) = priority {
+        crate::sched::realtime(&wr_handle, priority);
+    }
     Ok(Pipe { wr_handle })
 }
 
 /// creates a pipe writing packets from `rx` to `output``.
 ///
-/// The packets are written with original rate they were recorded.
-pub fn delaying(rx: Rx, output: impl PacketWriter + Send + 'static, stats: Stats) -> Result<Pipe> {
-    create_pipe_for(rx, output, PacketRateDelay::new(), stats)
+/// The packets are written with original rate they were recorded multiplied
+/// by `speed`. If `max_gap` is given, delay between packets is capped to it.
+pub fn delaying(
+    rx: Rx,
+    output: impl PacketWriter + Send + 'static,
+    speed: f64,
+    max_gap: Option<Duration>,
+    stats: Stats,
+    opts: Options,
+) -> Result<Pipe> {
+    create_pipe_for(
+        rx,
+        output,
+        PacketRateDelay::new(speed, max_gap),
+        stats,
+        opts,
+    )
 }
 
 /// Creates a pipe writing packets from `rx` to `output`.
 ///
 /// The packets are written out as fast as they are read with no delay between
-pub fn fullspeed(rx: Rx, output: impl PacketWriter + Send + 'static, stats: Stats) -> Result<Pipe> {
-    create_pipe_for(rx, output, NoDelay {}, stats)
+pub fn fullspeed(
+    rx: Rx,
+    output: impl PacketWriter + Send + 'static,
+    stats: Stats,
+    opts: Options,
+) -> Result<Pipe> {
+    create_pipe_for(rx, output, NoDelay {}, stats, opts)
 }
 
 /// Creates a pipe writing packets from `rx` to `output`.
@@ -339,9 +1692,46 @@ pub fn pps(
     rx: Rx,
     output: impl PacketWriter + Send + 'static,
     pps: u32,
+    mut stats: Stats,
+    opts: Options,
+) -> Result<Pipe> {
+    let delayer = PpsDelay::new(pps, !opts.no_catchup);
+    stats.target = Some(Target::Pps(f64::from(pps)));
+    create_pipe_for(rx, output, delayer, stats, opts)
+}
+
+/// Creates a pipe writing packets from `rx` to `output`.
+///
+/// The packets are written with packet rate changing over time as given by
+/// `ramp`. Returns error if either of the rates is 0.
+pub fn ramp(
+    rx: Rx,
+    output: impl PacketWriter + Send + 'static,
+    ramp: Ramp,
     stats: Stats,
+    opts: Options,
+) -> Result<Pipe> {
+    if ramp.from == 0 || ramp.to == 0 {
+        anyhow::bail!("ramp packet rates must be larger than 0");
+    }
+    let delayer = RampDelay::new(ramp, !opts.no_catchup);
+    create_pipe_for(rx, output, delayer, stats, opts)
+}
+
+/// Creates a pipe writing packets from `rx` to `output`.
+///
+/// The packets are written with `gap` between every packet.
+pub fn fixed_gap(
+    rx: Rx,
+    output: impl PacketWriter + Send + 'static,
+    gap: Duration,
+    mut stats: Stats,
+    opts: Options,
 ) -> Result<Pipe> {
-    create_pipe_for(rx, output, PpsDelay::new(pps), stats)
+    if !gap.is_zero() {
+        stats.target = Some(Target::Pps(1.0 / gap.as_secs_f64()));
+    }
+    create_pipe_for(rx, output, FixedGapDelay::new(gap), stats, opts)
 }
 
 /// Creates a pipe writing packets from `rx` to `output`.
@@ -352,7 +1742,185 @@ pub fn bps(
     rx: Rx,
     output: impl PacketWriter + Send + 'static,
     bps: u64,
-    stats: Stats,
+    mut stats: Stats,
+    opts: Options,
 ) -> Result<Pipe> {
-    create_pipe_for(rx, output, BpsDelay::new(bps), stats)
+    let delayer = BpsDelay::new(bps, !opts.no_catchup);
+    stats.target = Some(Target::Bps(bps as f64));
+    create_pipe_for(rx, output, delayer, stats, opts)
+}
+
+/// Creates a pipe writing packets from `rx` to `output`.
+///
+/// The packets are written at given number of bits per second on average,
+/// but bursts of up to `burst` bytes are written without delay.
+pub fn token_bucket(
+    rx: Rx,
+    output: impl PacketWriter + Send + 'static,
+    bps: u64,
+    burst: u64,
+    mut stats: Stats,
+    opts: Options,
+) -> Result<Pipe> {
+    stats.target = Some(Target::Bps(bps as f64));
+    create_pipe_for(rx, output, TokenBucketDelay::new(bps, burst), stats, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pcapfile;
+
+    /// Returns packet of `len` bytes with timestamp `when`.
+    fn packet(len: usize, when: SystemTime) -> Packet {
+        Packet {
+            data: vec![0; len],
+            when,
+        }
+    }
+
+    #[test]
+    fn bps_delay_past_overflow() {
+        // bits_sent * 1_000_000 no longer fits into u64 above this
+        const THRESHOLD: u64 = u64::MAX / 1_000_000;
+        const BPS: u64 = 1_000_000;
+        const LEN: usize = 65536;
+        let bits = LEN as u64 * 8;
+        let mut delay = BpsDelay::new(BPS, true);
+        delay.init();
+        delay.bits_sent = THRESHOLD - 10 * bits;
+        let pkt = packet(LEN, SystemTime::UNIX_EPOCH);
+        let mut prev = Duration::ZERO;
+        for _ in 0..20 {
+            let expected = Duration::from_secs(delay.bits_sent / BPS);
+            let wait = delay.wait_time_for(&pkt).expect("should wait");
+            assert!(wait >= prev, "wait {wait:?} shorter than {prev:?}");
+            assert!(wait <= expected + Duration::from_secs(1));
+            assert!(wait + Duration::from_secs(1) >= expected);
+            prev = wait;
+        }
+        assert!(delay.bits_sent > THRESHOLD);
+    }
+
+    /// Returns pcap stream with nanosecond timestamps, holding one packet
+    /// for each of the `(seconds, nanoseconds)` timestamps.
+    fn nanosecond_pcap(timestamps: &[(u32, u32)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&pcapfile::MAGIC_NANOS.to_le_bytes());
+        buf.extend_from_slice(&2u16.to_le_bytes());
+        buf.extend_from_slice(&4u16.to_le_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&65535u32.to_le_bytes());
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        for (secs, nanos) in timestamps {
+            buf.extend_from_slice(&secs.to_le_bytes());
+            buf.extend_from_slice(&nanos.to_le_bytes());
+            buf.extend_from_slice(&60u32.to_le_bytes());
+            buf.extend_from_slice(&60u32.to_le_bytes());
+            buf.extend_from_slice(&[0; 60]);
+        }
+        buf
+    }
+
+    #[test]
+    fn packet_rate_delay_nanosecond_gaps() {
+        let timestamps = [(100, 999_999_900), (101, 250), (101, 251), (101, 1_001)];
+        let data = nanosecond_pcap(&timestamps);
+        let mut reader = pcapfile::Reader::new(data.as_slice()).unwrap();
+        let mut packets = Vec::new();
+        while let Some(pkt) = reader.next_packet(None).unwrap() {
+            packets.push(pkt);
+        }
+        let when: Vec<SystemTime> = timestamps
+            .iter()
+            .map(|(s, n)| SystemTime::UNIX_EPOCH + Duration::new(u64::from(*s), *n))
+            .collect();
+        assert_eq!(packets.iter().map(|p| p.when).collect::<Vec<_>>(), when);
+
+        let mut delay = PacketRateDelay::new(1.0, None);
+        delay.init();
+        let gaps: Vec<Option<Duration>> = packets.iter().map(|p| delay.wait_time_for(p)).collect();
+        assert_eq!(
+            gaps,
+            [
+                None,
+                Some(Duration::from_nanos(350)),
+                Some(Duration::from_nanos(1)),
+                Some(Duration::from_nanos(750)),
+            ]
+        );
+    }
+
+    #[test]
+    fn ramp_rate_up_hold_down() {
+        let ramp = Ramp {
+            from: 100,
+            to: 1100,
+            duration: Duration::from_secs(10),
+            hold: Some(Duration::from_secs(5)),
+        };
+        let rate = |secs: f64| ramp.rate_at(Duration::from_secs_f64(secs));
+        // ramping up
+        assert_eq!(rate(0.0), 100.0);
+        assert_eq!(rate(2.5), 350.0);
+        assert_eq!(rate(5.0), 600.0);
+        // holding
+        assert_eq!(rate(10.0), 1100.0);
+        assert_eq!(rate(14.0), 1100.0);
+        // ramping down
+        assert_eq!(rate(15.0), 1100.0);
+        assert_eq!(rate(20.0), 600.0);
+        assert_eq!(rate(22.5), 350.0);
+        // back at start
+        assert_eq!(rate(25.0), 100.0);
+        assert_eq!(rate(100.0), 100.0);
+    }
+
+    #[test]
+    fn ramp_rate_without_hold() {
+        let ramp = Ramp {
+            from: 1000,
+            to: 0,
+            duration: Duration::from_secs(4),
+            hold: None,
+        };
+        let rate = |secs: f64| ramp.rate_at(Duration::from_secs_f64(secs));
+        assert_eq!(rate(0.0), 1000.0);
+        assert_eq!(rate(1.0), 750.0);
+        assert_eq!(rate(4.0), 0.0);
+        assert_eq!(rate(100.0), 0.0);
+    }
+
+    #[test]
+    fn ramp_rejects_zero_rate() {
+        for (from, to) in [(0, 10), (10, 0)] {
+            let (_tx, rx) =
+                crate::channel::create(16, 8, crate::channel::Unit::Packets, Arc::default());
+            let ramp = Ramp {
+                from,
+                to,
+                duration: Duration::from_secs(1),
+                hold: None,
+            };
+            let res = super::ramp(
+                rx,
+                crate::output::null(),
+                ramp,
+                Stats::default(),
+                Options::default(),
+            );
+            assert!(res.is_err());
+        }
+    }
+
+    #[test]
+    fn progress_kept_over_reset() {
+        let mut stats = Stats::default();
+        stats.set_total(10);
+        stats.packets = 3;
+        stats.invalid = 1;
+        stats.reset();
+        stats.packets = 2;
+        assert!(stats.to_string().contains("60.0% (6/10 packets)"));
+    }
 }
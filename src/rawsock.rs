@@ -0,0 +1,215 @@
+//! Linux `AF_PACKET` raw sockets for writing packets to interface.
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// `AF_PACKET` socket bound to a network interface.
+pub struct RawSocket {
+    fd: OwnedFd,
+}
+
+impl RawSocket {
+    /// Opens raw socket for sending packets to interface with given name.
+    ///
+    /// The socket is created with protocol 0, so no packets are received
+    /// from it.
+    pub fn open(ifname: &str) -> io::Result<Self> {
+        let name = CString::new(ifname)?;
+        // SAFETY: name is valid NUL terminated string.
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: plain socket(2) call, returned descriptor is checked.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a valid descriptor we own.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_ll is plain old data, all zeroes is valid.
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_ifindex = ifindex as i32;
+        // SAFETY: addr is valid sockaddr_ll and its size is given.
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RawSocket { fd })
+    }
+
+    /// Enables scheduling transmission time of packets with `SO_TXTIME`,
+    /// see [RawSocket::send_at]. Transmission times are given in
+    /// `CLOCK_MONOTONIC`, which requires `fq` qdisc on the interface.
+    pub fn enable_txtime(&self) -> io::Result<()> {
+        let txtime = libc::sock_txtime {
+            clockid: libc::CLOCK_MONOTONIC,
+            flags: 0,
+        };
+        // SAFETY: txtime is valid sock_txtime and its size is given.
+        let ret = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TXTIME,
+                &txtime as *const libc::sock_txtime as *const libc::c_void,
+                mem::size_of::<libc::sock_txtime>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Sets the size of the socket send buffer to `size` bytes. Beyond
+    /// `net.core.wmem_max` the size can only be set with `CAP_NET_ADMIN`,
+    /// without it the kernel caps the size to the limit.
+    pub fn set_send_buffer(&self, size: usize) -> io::Result<()> {
+        let size = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+        match self.set_int_option(libc::SO_SNDBUFFORCE, size) {
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+                self.set_int_option(libc::SO_SNDBUF, size)
+            }
+            ret => ret,
+        }
+    }
+
+    /// Sets `SO_PRIORITY` of the socket, which qdiscs like `mqprio` use to
+    /// select the transmit queue of the packets. Priorities above 6 need
+    /// `CAP_NET_ADMIN`.
+    pub fn set_priority(&self, priority: u32) -> io::Result<()> {
+        let priority = libc::c_int::try_from(priority).unwrap_or(libc::c_int::MAX);
+        self.set_int_option(libc::SO_PRIORITY, priority)
+    }
+
+    /// Sets integer valued socket option `opt` on `SOL_SOCKET` level.
+    fn set_int_option(&self, opt: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        // SAFETY: value is valid c_int and its size is given.
+        let ret = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                opt,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Sends frames in `bufs` with single system call, pushing the number
+    /// of bytes sent for each frame into `sent`. Returns the number of
+    /// frames sent, which may be less than given. Error is returned if the
+    /// first frame could not be sent.
+    pub fn send_batch(&self, bufs: &[&[u8]], sent: &mut Vec<usize>) -> io::Result<usize> {
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .map(|iov| {
+                // SAFETY: mmsghdr is plain old data, all zeroes is valid.
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+        // SAFETY: msgs contains msgs.len() valid headers, each pointing to
+        // iovec describing a buffer that lives until the call returns.
+        let ret = unsafe {
+            libc::sendmmsg(
+                self.fd.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let count = ret as usize;
+        sent.extend(msgs[..count].iter().map(|m| m.msg_len as usize));
+        Ok(count)
+    }
+
+    /// Sends a frame contained in `buf` to be transmitted at `txtime`,
+    /// given as nanoseconds of `CLOCK_MONOTONIC`. Requires that
+    /// [RawSocket::enable_txtime] has been called.
+    pub fn send_at(&self, buf: &[u8], txtime: u64) -> io::Result<usize> {
+        // u64 aligned buffer large enough for one control message with
+        // the transmission time
+        let mut control = [0u64; 4];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // SAFETY: msghdr is plain old data, all zeroes is valid.
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        // SAFETY: CMSG_SPACE only computes the size.
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u64>() as u32) } as _;
+        // SAFETY: msg_control points to buffer of msg_controllen bytes, so
+        // the first header and its data fit in it.
+        let ret = unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_TXTIME;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u64>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u64, txtime);
+            libc::sendmsg(self.fd.as_raw_fd(), &msg, 0)
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    /// Sends a frame contained in `buf` to the interface, returning
+    /// number of bytes sent.
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        // SAFETY: buf is valid for reads of buf.len() bytes.
+        let ret = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+}
+
+/// Returns current time of `CLOCK_MONOTONIC` in nanoseconds.
+pub fn monotonic_now() -> u64 {
+    // SAFETY: timespec is plain old data, all zeroes is valid.
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    // SAFETY: ts is valid timespec to write to.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
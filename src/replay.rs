@@ -0,0 +1,686 @@
+//! High level interface for replaying packets.
+//!
+//! [Replayer] ties together the input, packet buffer and [pipe::Pipe]
+//! writing packets to output.
+use std::cell::OnceCell;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+
+use crate::{channel, input, output, pipe};
+
+/// Method to read packets
+pub enum InputMethod {
+    /// Read packets from pcap -files, one after another
+    File(Vec<String>),
+    /// Read pcap stream from standard input
+    Stdin,
+    /// Read pcap stream from TCP connection accepted on given address
+    Tcp(SocketAddr),
+    /// Read packets from interface. If `filter` is given, only packets
+    /// matching the BPF filter expression are read. Packets matching
+    /// `exclude` expression are not read.
+    Interface {
+        name: String,
+        filter: Option<String>,
+        exclude: Option<String>,
+        options: input::CaptureOptions,
+    },
+}
+
+impl InputMethod {
+    /// Creates [input::PcapInput] for this input method. If multiple files
+    /// are read, they are combined as selected by `combine`.
+    pub fn to_pcap_input(&self, combine: input::Combine) -> Result<input::PcapInput> {
+        match self {
+            InputMethod::File(fnames) => match fnames.as_slice() {
+                [fname] => Ok(input::pcap_file(fname)?),
+                _ => Ok(input::pcap_files(fnames, combine)?),
+            },
+            InputMethod::Stdin => Ok(input::pcap_stdin()?),
+            InputMethod::Tcp(addr) => Ok(input::tcp_listen(*addr)?),
+            InputMethod::Interface {
+                name,
+                filter,
+                exclude,
+                options,
+            } => Ok(input::pcap_interface(
+                name,
+                filter.as_deref(),
+                exclude.as_deref(),
+                *options,
+            )?),
+        }
+    }
+}
+
+/// Packet rate for writing packets
+pub enum Rate {
+    /// Write as fast as possible
+    Full,
+    /// Write with set packet per second
+    Pps(u32),
+    /// Write given megabits per second.
+    Mbps(u64),
+    /// Write packets with given time between every packet.
+    Gap(Duration),
+    /// Write given bits per second on average, allowing bursts of given
+    /// number of bytes.
+    Burst(u64, u64),
+    /// Write packets with packet rate changing over time.
+    Ramp(pipe::Ramp),
+    /// Write packets with a delay implied by their timestamps. This is used
+    /// when reding from a pcap file and we want to output packets in same
+    /// rate as they were saved to the file. The delays are divided by
+    /// given speed multiplier.
+    Delayed(f64),
+}
+
+/// Limits for the packets read from input.
+///
+/// Other than `passes` and `duration`, limits apply to each iteration over
+/// the input when looping.
+#[derive(Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of iterations over the input, `Some(1)` when not
+    /// looping. [None] loops until terminated.
+    pub passes: Option<u64>,
+    /// Skip packets captured before this time.
+    pub start: Option<input::TimePoint>,
+    /// Stop reading once packets captured after this time are seen.
+    pub end: Option<input::TimePoint>,
+    /// Read only this many last packets. Applied before `count` and
+    /// `bytes`.
+    pub tail: Option<usize>,
+    /// Maximum number of packets to read.
+    pub count: Option<usize>,
+    /// Maximum number of bytes to read. The packet crossing this limit is
+    /// still included.
+    pub bytes: Option<u64>,
+    /// Time between the last packet of an iteration and the first packet of
+    /// the next one when looping.
+    pub loop_gap: Duration,
+    /// Stop reading once this much time has passed since reading started.
+    /// Reading is stopped by setting the stop flag, so that writing stops
+    /// too.
+    pub duration: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            passes: Some(1),
+            start: None,
+            end: None,
+            tail: None,
+            count: None,
+            bytes: None,
+            loop_gap: Duration::ZERO,
+            duration: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Returns [Iterator] yielding the packets from `it` within these limits.
+    pub fn apply<'a>(
+        &self,
+        mut it: Box<dyn Iterator<Item = input::Packet> + 'a>,
+    ) -> Box<dyn Iterator<Item = input::Packet> + 'a> {
+        if self.start.is_some() || self.end.is_some() {
+            it = Box::new(input::time_window(it, self.start, self.end));
+        }
+        if let Some(n) = self.tail {
+            it = Box::new(input::take_last(it, n));
+        }
+        if let Some(n) = self.count {
+            it = Box::new(it.take(n));
+        }
+        if let Some(max) = self.bytes {
+            it = Box::new(input::take_bytes(it, max));
+        }
+        it
+    }
+}
+
+/// Reads packets using given [InputMethod] and sends them to `tx`. Input is
+/// read the number of times given by `limits`, or until `stop` is set.
+/// Packet timestamps are taken from `timestamps`. End of each iteration is
+/// recorded to `markers`, if given.
+fn read_input(
+    method: &InputMethod,
+    combine: input::Combine,
+    tx: &channel::Tx,
+    stop: &AtomicBool,
+    limits: Limits,
+    timestamps: input::TimestampSource,
+    markers: Option<&pipe::LoopMarkers>,
+) -> Result<()> {
+    let loop_file = limits.passes != Some(1);
+    // set this to true if we are looping and have been able to read
+    // the file at least once.
+    let mut opened: bool = false;
+    let mut pass: u64 = 0;
+    let pool = tx.pool();
+    // timestamp of the last packet read on previous iteration
+    let mut last: Option<SystemTime> = None;
+    // total number of packets read
+    let mut read: u64 = 0;
+    let deadline = limits.duration.map(|d| Instant::now() + d);
+    loop {
+        let input = match method.to_pcap_input(combine) {
+            Ok(input) => {
+                if loop_file {
+                    opened = true
+                }
+                Some(input)
+            }
+            Err(err) => {
+                if loop_file && opened {
+                    // we have been able to open this file at least
+                    // once, thus just terminate the looping if
+                    // file has been removed
+                    tracing::info!(?err, "looping and file removed?, terminating");
+                    None
+                } else {
+                    return Err(err);
+                }
+            }
+        };
+        let Some(inp) = input else {
+            // Input not opened, but do not return error
+            break;
+        };
+
+        // timestamps on following iterations continue from the
+        // previous one, so that the packets are replayed with
+        // the loop gap between iterations
+        let start = last.map(|t| t + limits.loop_gap);
+        let mut it = limits.apply(inp.pooled_packets(stop, &pool)?);
+        if timestamps == input::TimestampSource::Arrival {
+            it = Box::new(input::arrival_time(it));
+        }
+        let it = input::shift_to(it, start)
+            .take_while(|_| {
+                if deadline.is_some_and(|end| Instant::now() >= end) {
+                    tracing::info!("maximum duration reached, terminating reader");
+                    stop.store(true, Ordering::Relaxed);
+                    return false;
+                }
+                true
+            })
+            .inspect(|pkt| {
+                last = Some(pkt.when);
+                read += 1;
+            });
+        pipe::read_packets_to(it, tx)?;
+        if let Some(err) = inp.take_error() {
+            return Err(err);
+        }
+        pass += 1;
+        // iteration interrupted by termination is not complete
+        if let Some(markers) = markers.filter(|_| !stop.load(Ordering::Relaxed)) {
+            markers.iteration_read(read);
+        }
+        if !loop_file || stop.load(Ordering::Relaxed) || limits.passes.is_some_and(|n| pass >= n) {
+            break;
+        }
+        tracing::info!("pcap file iteration complete");
+    }
+    Ok(())
+}
+
+/// Starts thread setting `stop` once `duration` has passed, unless the
+/// returned [mpsc::Sender] is dropped before that. Reader and writer check
+/// [Limits::duration] only when packets are read or written, this stops
+/// them also when no packets arrive.
+fn start_deadline_timer(duration: Duration, stop: Arc<AtomicBool>) -> mpsc::Sender<()> {
+    let (cancel, cancelled) = mpsc::channel();
+    thread::Builder::new()
+        .name("deadline".to_string())
+        .spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = cancelled.recv_timeout(duration) {
+                tracing::info!("maximum duration reached, terminating");
+                stop.store(true, Ordering::Relaxed);
+            }
+        })
+        .unwrap();
+    cancel
+}
+
+/// Creates a [pipe::Pipe] with given parameters.
+fn create_pipe(
+    rate: Rate,
+    rx: channel::Rx,
+    output: impl output::PacketWriter + Send + 'static,
+    stats: pipe::Stats,
+    max_gap: Option<Duration>,
+    opts: pipe::Options,
+) -> Result<pipe::Pipe> {
+    match rate {
+        Rate::Full => pipe::fullspeed(rx, output, stats, opts),
+        Rate::Delayed(speed) => pipe::delaying(rx, output, speed, max_gap, stats, opts),
+        Rate::Mbps(bps) => pipe::bps(rx, output, bps, stats, opts),
+        Rate::Burst(bps, burst) => pipe::token_bucket(rx, output, bps, burst, stats, opts),
+        Rate::Pps(pps) => pipe::pps(rx, output, pps, stats, opts),
+        Rate::Gap(gap) => pipe::fixed_gap(rx, output, gap, stats, opts),
+        Rate::Ramp(ramp) => pipe::ramp(rx, output, ramp, stats, opts),
+    }
+}
+
+/// Result of [Replayer::run].
+pub struct RunOutcome {
+    /// Statistics of the packets written, also when replaying failed.
+    pub stats: pipe::Stats,
+    /// Error which stopped replaying, [None] if it completed.
+    pub error: Option<anyhow::Error>,
+}
+
+impl RunOutcome {
+    /// Returns [pipe::Stats] if replaying completed, the error otherwise.
+    pub fn into_result(self) -> Result<pipe::Stats> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.stats),
+        }
+    }
+}
+
+/// Default high watermark of packet buffer when using [channel::Unit::Packets].
+const DEFAULT_HIGH_PACKETS: u64 = 100;
+/// Default high watermark of packet buffer when using [channel::Unit::Bytes].
+const DEFAULT_HIGH_BYTES: u64 = 1024 * 1024;
+
+/// Builder for [Replayer].
+///
+/// Collects the replay configuration and checks that it is consistent when
+/// [Replayer] is built. By default packets are replayed once with the rate
+/// they have been captured with.
+pub struct ReplayBuilder {
+    input: InputMethod,
+    combine: input::Combine,
+    rate: Rate,
+    high: Option<u64>,
+    low: Option<u64>,
+    unit: channel::Unit,
+    ring: bool,
+    limits: Limits,
+    max_gap: Option<Duration>,
+    timestamps: input::TimestampSource,
+    reader_cpu: Option<usize>,
+}
+
+impl ReplayBuilder {
+    /// Sets the [InputMethod] packets are read with.
+    pub fn input(mut self, input: InputMethod) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Sets how multiple input files are combined.
+    pub fn combine(mut self, combine: input::Combine) -> Self {
+        self.combine = combine;
+        self
+    }
+
+    /// Sets the rate packets are written with.
+    pub fn rate(mut self, rate: Rate) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Sets high and low watermarks of the packet buffer. If not given, high
+    /// watermark is 100 packets or 1MB depending on the unit and low
+    /// watermark is half of the high watermark.
+    pub fn watermarks(mut self, high: Option<u64>, low: Option<u64>) -> Self {
+        self.high = high;
+        self.low = low;
+        self
+    }
+
+    /// Sets unit of the packet buffer watermarks.
+    pub fn watermark_unit(mut self, unit: channel::Unit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    /// Buffers packets in a lock-free ring holding high watermark number of
+    /// packets instead of using low and high watermarks.
+    pub fn ring_buffer(mut self, ring: bool) -> Self {
+        self.ring = ring;
+        self
+    }
+
+    /// Pins the thread reading packets to given CPU.
+    pub fn reader_cpu(mut self, cpu: Option<usize>) -> Self {
+        self.reader_cpu = cpu;
+        self
+    }
+
+    /// Sets [Limits] for the packets read from input, replacing the limits
+    /// set with [ReplayBuilder::count] and [ReplayBuilder::loop_file].
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Reads at most `count` packets on each pass over the input.
+    pub fn count(mut self, count: usize) -> Self {
+        self.limits.count = Some(count);
+        self
+    }
+
+    /// Loops the input given number of times, [None] loops until terminated.
+    pub fn loop_file(mut self, passes: Option<u64>) -> Self {
+        self.limits.passes = passes;
+        self
+    }
+
+    /// Sets maximum delay between packets when replaying with their original
+    /// rate.
+    pub fn max_gap(mut self, max_gap: Option<Duration>) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    /// Sets where packet timestamps are taken from. With
+    /// [input::TimestampSource::Arrival], packets replayed with their
+    /// original rate are written as soon as they are read.
+    pub fn timestamp_source(mut self, timestamps: input::TimestampSource) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Creates [Replayer] with this configuration. Returns error if the
+    /// configuration is not valid.
+    pub fn build(self) -> Result<Replayer> {
+        let default_high = match self.unit {
+            channel::Unit::Packets => DEFAULT_HIGH_PACKETS,
+            channel::Unit::Bytes => DEFAULT_HIGH_BYTES,
+        };
+        let high = self.high.unwrap_or(default_high);
+        let low = self.low.unwrap_or(high / 2);
+        if low >= high {
+            anyhow::bail!("packet buffer low watermark can not be larger than high");
+        }
+        if self.ring && (self.low.is_some() || self.unit != channel::Unit::Packets) {
+            anyhow::bail!("ring buffer can only be sized with high watermark in packets");
+        }
+        if matches!(self.input, InputMethod::Stdin) && self.limits.passes != Some(1) {
+            anyhow::bail!("can not loop packets read from standard input");
+        }
+        if matches!(self.input, InputMethod::Tcp(_)) && self.limits.passes != Some(1) {
+            anyhow::bail!("can not loop packets read from TCP connection");
+        }
+        let mut rate = self.rate;
+        if matches!(self.input, InputMethod::Interface { .. }) && matches!(rate, Rate::Delayed(_)) {
+            // if no pps or bps options are defined and we are reading from interface
+            // force the --full which causes packets to be written to the output
+            // interface as soon as they are received, which is probably what
+            // users would expect.
+            rate = Rate::Full;
+        }
+        if self.timestamps == input::TimestampSource::Arrival && matches!(rate, Rate::Delayed(_)) {
+            // timestamps only tell when packets were read, waiting between
+            // them would just repeat the stalls of the reader
+            rate = Rate::Full;
+        }
+        Ok(Replayer {
+            input: self.input,
+            combine: self.combine,
+            rate,
+            output: None,
+            high,
+            low,
+            unit: self.unit,
+            ring: self.ring,
+            limits: self.limits,
+            max_gap: self.max_gap,
+            timestamps: self.timestamps,
+            reader_cpu: self.reader_cpu,
+            stats: pipe::Stats::default(),
+            options: pipe::Options::default(),
+            terminate: Arc::new(AtomicBool::new(false)),
+            header: OnceCell::new(),
+        })
+    }
+}
+
+/// Replays packets from input to output.
+///
+/// [Replayer] is created with [ReplayBuilder], packets are discarded unless
+/// output is set.
+pub struct Replayer {
+    input: InputMethod,
+    combine: input::Combine,
+    rate: Rate,
+    output: Option<Box<dyn output::PacketWriter + Send>>,
+    high: u64,
+    low: u64,
+    unit: channel::Unit,
+    ring: bool,
+    limits: Limits,
+    max_gap: Option<Duration>,
+    timestamps: input::TimestampSource,
+    reader_cpu: Option<usize>,
+    stats: pipe::Stats,
+    options: pipe::Options,
+    terminate: Arc<AtomicBool>,
+    /// Header of the input, checked when first asked for.
+    header: OnceCell<InputHeader>,
+}
+
+/// Header values of the input inspected before replay.
+#[derive(Clone, Copy, Default)]
+struct InputHeader {
+    link_type: Option<u32>,
+    snaplen: Option<u32>,
+}
+
+impl Replayer {
+    /// Returns [ReplayBuilder] for replaying packets read with given
+    /// [InputMethod].
+    pub fn builder(input: InputMethod) -> ReplayBuilder {
+        ReplayBuilder {
+            input,
+            combine: input::Combine::Sequence {
+                preserve_gaps: false,
+            },
+            rate: Rate::Delayed(1.0),
+            high: None,
+            low: None,
+            unit: channel::Unit::Packets,
+            ring: false,
+            limits: Limits::default(),
+            max_gap: None,
+            timestamps: input::TimestampSource::Capture,
+            reader_cpu: None,
+        }
+    }
+
+    /// Returns the rate packets are written with.
+    pub fn rate(&self) -> &Rate {
+        &self.rate
+    }
+
+    /// Opens the input for inspecting it before replay. Returns [None] if
+    /// the input can not be opened for it, as is the case for standard
+    /// input and TCP connection which can be read only once.
+    fn inspect_input(&self) -> Option<input::PcapInput> {
+        if matches!(self.input, InputMethod::Stdin | InputMethod::Tcp(_)) {
+            return None;
+        }
+        match self.input.to_pcap_input(self.combine) {
+            Ok(inp) => Some(inp),
+            Err(err) => {
+                tracing::debug!(?err, "unable to open input for inspection");
+                None
+            }
+        }
+    }
+
+    /// Returns the header of the input, opening the input only on the
+    /// first call.
+    fn header(&self) -> InputHeader {
+        *self.header.get_or_init(|| {
+            self.inspect_input()
+                .map(|inp| InputHeader {
+                    link_type: inp.link_type(),
+                    snaplen: inp.snaplen(),
+                })
+                .unwrap_or_default()
+        })
+    }
+
+    /// Returns the link type of packets in the input, see
+    /// [crate::linktype]. Returns [None] if it is not known or the input can
+    /// not be opened for checking it, as is the case for standard input.
+    pub fn link_type(&self) -> Option<u32> {
+        self.header().link_type
+    }
+
+    /// Returns the snap length of the input. Returns [None] if it is not
+    /// known or the input can not be opened for checking it.
+    pub fn snaplen(&self) -> Option<u32> {
+        self.header().snaplen
+    }
+
+    /// Reads through the input and returns [input::Summary] of the packets
+    /// one pass over the input would replay.
+    pub fn scan(&self) -> Result<input::Summary> {
+        let never = AtomicBool::new(false);
+        let inp = self.input.to_pcap_input(self.combine)?;
+        let it = self.limits.apply(inp.packets(&never)?);
+        let summary = input::summarize(it);
+        match inp.take_error() {
+            Some(err) => Err(err),
+            None => Ok(summary),
+        }
+    }
+
+    /// Returns estimated time replaying all passes over the input takes,
+    /// given [input::Summary] of one pass. Returns [None] if it can not be
+    /// estimated.
+    pub fn estimate_duration(&self, summary: &input::Summary) -> Option<Duration> {
+        let passes = self.limits.passes?;
+        let pass =
+            match self.rate {
+                Rate::Full | Rate::Ramp(_) => None,
+                Rate::Delayed(speed) => Some(summary.duration().div_f64(speed)),
+                Rate::Pps(pps) => (pps > 0)
+                    .then(|| Duration::from_secs_f64(summary.packets as f64 / f64::from(pps))),
+                Rate::Gap(gap) => Some(gap.mul_f64(summary.packets.saturating_sub(1) as f64)),
+                Rate::Mbps(bps) | Rate::Burst(bps, _) => (bps > 0)
+                    .then(|| Duration::from_secs_f64(summary.bytes as f64 * 8.0 / bps as f64)),
+            }?;
+        Some(pass.mul_f64(passes as f64))
+    }
+
+    /// Sets the output packets are written to.
+    pub fn output(mut self, output: Box<dyn output::PacketWriter + Send>) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Sets [pipe::Stats] updated while writing packets.
+    pub fn stats(mut self, stats: pipe::Stats) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Sets `callback` to be called with current [pipe::Stats] every `every`
+    /// packets, see [pipe::Stats::set_progress].
+    pub fn progress(
+        mut self,
+        every: u64,
+        callback: impl FnMut(&pipe::Stats) + Send + 'static,
+    ) -> Self {
+        self.stats.set_progress(every, callback);
+        self
+    }
+
+    /// Sets [pipe::Options] used when writing packets.
+    pub fn options(mut self, options: pipe::Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets flag which stops replaying when set to true.
+    pub fn terminate(mut self, terminate: Arc<AtomicBool>) -> Self {
+        self.terminate = terminate;
+        self
+    }
+
+    /// Replays packets, returning once all packets are written or replaying
+    /// is terminated. Returns [RunOutcome] with final [pipe::Stats], which
+    /// are kept also when an error stops replaying.
+    pub fn run(self) -> RunOutcome {
+        let (tx, rx) = if self.ring {
+            channel::ring(self.high as usize, self.terminate.clone())
+        } else {
+            channel::create(self.high, self.low, self.unit, self.terminate.clone())
+        };
+        let output = self.output.unwrap_or_else(|| Box::new(output::null()));
+        let markers = self.options.loop_markers.clone();
+        let pipe = match create_pipe(
+            self.rate,
+            rx,
+            output,
+            self.stats,
+            self.max_gap,
+            self.options,
+        ) {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                // nothing has been written yet
+                return RunOutcome {
+                    stats: pipe::Stats::default(),
+                    error: Some(err),
+                };
+            }
+        };
+        let method = self.input;
+        let combine = self.combine;
+        let limits = self.limits;
+        let timestamps = self.timestamps;
+        let stop = self.terminate.clone();
+        // cancelled when dropped at return
+        let _deadline = limits
+            .duration
+            .map(|d| start_deadline_timer(d, self.terminate.clone()));
+        let rd_handle: thread::JoinHandle<Result<()>> = thread::Builder::new()
+            .name("pcap-reader".to_string())
+            .spawn(move || {
+                read_input(
+                    &method,
+                    combine,
+                    &tx,
+                    &stop,
+                    limits,
+                    timestamps,
+                    markers.as_ref(),
+                )
+            })
+            .unwrap();
+        if let Some(cpu) = self.reader_cpu {
+            crate::sched::pin(&rd_handle, cpu);
+        }
+        let read_result = rd_handle.join().unwrap();
+        tracing::trace!("Reader terminated");
+        let (stats, write_result) = pipe.wait();
+        let error = match write_result.context("Error while writing packets") {
+            Err(err) => Some(err),
+            // if we have received signal indicating we should stop, discard
+            // reader errors as the packet writer might have terminated
+            // already and reader just complains about closed channel.
+            Ok(()) if self.terminate.load(Ordering::Relaxed) => None,
+            Ok(()) => read_result.context("Error while reading packets").err(),
+        };
+        RunOutcome { stats, error }
+    }
+}
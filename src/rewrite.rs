@@ -0,0 +1,584 @@
+//! Transforms for rewriting packets before they are written.
+//!
+//! Transforms operate on Ethernet frames. They are applied in order to each
+//! packet by the packet writer.
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::{anon::PrefixPreserving, checksum};
+
+/// Length of Ethernet header.
+pub const ETH_HDR_LEN: usize = 14;
+/// EtherType for IPv4.
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+/// EtherType for IPv6.
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+/// EtherType for 802.1Q VLAN tag.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+/// EtherType for 802.1ad service VLAN tag.
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+/// IP protocol number for TCP.
+pub const IPPROTO_TCP: u8 = 6;
+/// IP protocol number for UDP.
+pub const IPPROTO_UDP: u8 = 17;
+/// IP protocol number for ICMPv6.
+pub const IPPROTO_ICMPV6: u8 = 58;
+/// IPv6 extension headers skipped when looking for transport protocol.
+const IPV6_EXT_HEADERS: [u8; 4] = [0, 43, 44, 60];
+/// IPv6 extension header for fragments.
+const IPV6_FRAGMENT: u8 = 44;
+
+/// Returns EtherType and offset of the network layer header of Ethernet
+/// frame in `data`, skipping any VLAN tags.
+pub fn network_layer(data: &[u8]) -> Option<(u16, usize)> {
+    let mut off = 12;
+    loop {
+        let ethertype = u16::from_be_bytes([*data.get(off)?, *data.get(off + 1)?]);
+        match ethertype {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ => off += 4,
+            _ => return Some((ethertype, off + 2)),
+        }
+    }
+}
+
+/// Returns the transport protocol of IPv6 packet with header at `off`,
+/// skipping extension headers, and offset of the transport header. The
+/// offset is [None] for fragments other than the first one, as they do not
+/// carry the transport header.
+pub fn ipv6_transport(data: &[u8], off: usize) -> Option<(u8, Option<usize>)> {
+    let mut next = *data.get(off + 6)?;
+    let mut off = off + 40;
+    if data.len() < off {
+        return None;
+    }
+    let mut first = true;
+    while IPV6_EXT_HEADERS.contains(&next) {
+        let hdr = next;
+        next = *data.get(off)?;
+        if hdr == IPV6_FRAGMENT {
+            let frag_off = u16::from_be_bytes([*data.get(off + 2)?, *data.get(off + 3)?]) >> 3;
+            first = frag_off == 0;
+            off += 8;
+        } else {
+            off += (usize::from(*data.get(off + 1)?) + 1) * 8;
+        }
+    }
+    Some((next, first.then_some(off)))
+}
+
+/// Returns offset of the checksum field of transport layer header starting
+/// at `off` for given IP protocol, if the protocol has a checksum covering
+/// the IP addresses and the header is present in `data`. Of ICMP, only the
+/// ICMPv6 checksum covers the addresses.
+fn transport_checksum(data: &[u8], proto: u8, off: usize) -> Option<usize> {
+    let csum_off = match proto {
+        IPPROTO_TCP => off + 16,
+        IPPROTO_UDP => off + 6,
+        IPPROTO_ICMPV6 => off + 2,
+        _ => return None,
+    };
+    if data.len() < csum_off + 2 {
+        return None;
+    }
+    // UDP checksum of 0 means checksum is not used
+    if proto == IPPROTO_UDP && data[csum_off..csum_off + 2] == [0, 0] {
+        return None;
+    }
+    Some(csum_off)
+}
+
+/// Replaces 16 bit checksum at `off` of `data` with the checksum adjusted
+/// for change from `old` to `new`.
+fn adjust_checksum_at(data: &mut [u8], off: usize, old: &[u8], new: &[u8], udp: bool) {
+    let csum = u16::from_be_bytes([data[off], data[off + 1]]);
+    let mut csum = checksum::adjust(csum, old, new);
+    if udp && csum == 0 {
+        // 0 would mean no checksum for UDP
+        csum = 0xffff;
+    }
+    data[off..off + 2].copy_from_slice(&csum.to_be_bytes());
+}
+
+/// Transform modifies packet data before it is written.
+pub trait Transform {
+    /// Transforms packet `data` in place. Returns `false` if the packet
+    /// could not be transformed, in which case it should not be written.
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool;
+}
+
+/// [Transform] overwriting source and/or destination MAC address of
+/// Ethernet frames.
+pub struct MacRewrite {
+    src: Option<[u8; 6]>,
+    dst: Option<[u8; 6]>,
+}
+
+impl MacRewrite {
+    /// Creates [MacRewrite] setting source MAC address to `src` and
+    /// destination MAC address to `dst`, if given.
+    pub fn new(src: Option<[u8; 6]>, dst: Option<[u8; 6]>) -> Self {
+        MacRewrite { src, dst }
+    }
+}
+
+impl Transform for MacRewrite {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        if data.len() < ETH_HDR_LEN {
+            return false;
+        }
+        if let Some(dst) = self.dst {
+            data[0..6].copy_from_slice(&dst);
+        }
+        if let Some(src) = self.src {
+            data[6..12].copy_from_slice(&src);
+        }
+        true
+    }
+}
+
+/// [Transform] adding VLAN tag with given VLAN ID to Ethernet frames, as the
+/// outermost tag. Untagged frames get 802.1Q tag, frames already tagged get
+/// 802.1ad service tag so that they become valid QinQ frames.
+pub struct VlanAdd {
+    id: u16,
+}
+
+impl VlanAdd {
+    /// Creates [VlanAdd] adding tag with VLAN ID `id`.
+    pub fn new(id: u16) -> Self {
+        VlanAdd { id }
+    }
+}
+
+impl Transform for VlanAdd {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        if data.len() < ETH_HDR_LEN {
+            return false;
+        }
+        let tpid = match u16::from_be_bytes([data[12], data[13]]) {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ => ETHERTYPE_QINQ,
+            _ => ETHERTYPE_VLAN,
+        };
+        let mut tag = [0u8; 4];
+        tag[..2].copy_from_slice(&tpid.to_be_bytes());
+        tag[2..].copy_from_slice(&(self.id & 0x0fff).to_be_bytes());
+        data.splice(12..12, tag);
+        true
+    }
+}
+
+/// [Transform] removing the outermost VLAN tag of Ethernet frames. Untagged
+/// frames are passed unchanged.
+pub struct VlanStrip;
+
+impl Transform for VlanStrip {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        if data.len() < ETH_HDR_LEN {
+            return false;
+        }
+        match u16::from_be_bytes([data[12], data[13]]) {
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ if data.len() < ETH_HDR_LEN + 4 => false,
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ => {
+                data.drain(12..16);
+                true
+            }
+            _ => true,
+        }
+    }
+}
+
+/// [Transform] recomputing IPv4 header checksum and TCP and UDP checksums
+/// of IPv4 packets. Applied after other transforms, this fixes checksums
+/// invalidated by rewriting, or left unset by checksum offload when the
+/// packets were captured.
+///
+/// Packets which are not IPv4 are passed unchanged. IPv4 packets with
+/// truncated IP, TCP or UDP header can not be fixed. Transport checksum is
+/// not recomputed for fragments or packets truncated when captured, as
+/// it covers data which is not present.
+pub struct FixChecksums;
+
+impl Transform for FixChecksums {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        let Some((ETHERTYPE_IPV4, off)) = network_layer(data) else {
+            return true;
+        };
+        if data.len() < off + 20 {
+            return false;
+        }
+        let ihl = usize::from(data[off] & 0x0f) * 4;
+        if ihl < 20 || data.len() < off + ihl {
+            return false;
+        }
+        data[off + 10..off + 12].fill(0);
+        let csum = checksum::finish(checksum::add(0, &data[off..off + ihl]));
+        data[off + 10..off + 12].copy_from_slice(&csum.to_be_bytes());
+
+        let total_len = usize::from(u16::from_be_bytes([data[off + 2], data[off + 3]]));
+        let flags_frag = u16::from_be_bytes([data[off + 6], data[off + 7]]);
+        // more fragments flag or fragment offset set
+        if flags_frag & 0x3fff != 0 || total_len < ihl || data.len() < off + total_len {
+            return true;
+        }
+        let proto = data[off + 9];
+        let l4 = off + ihl;
+        let l4_len = total_len - ihl;
+        let csum_off = match proto {
+            IPPROTO_TCP if l4_len >= 20 => l4 + 16,
+            IPPROTO_UDP if l4_len >= 8 => l4 + 6,
+            IPPROTO_TCP | IPPROTO_UDP => return false,
+            _ => return true,
+        };
+        // UDP checksum of 0 means checksum is not used
+        if proto == IPPROTO_UDP && data[csum_off..csum_off + 2] == [0, 0] {
+            return true;
+        }
+        data[csum_off..csum_off + 2].fill(0);
+        // pseudo header of addresses, protocol and length
+        let mut sum = checksum::add(0, &data[off + 12..off + 20]);
+        sum = checksum::add(sum, &[0, proto]);
+        sum = checksum::add(sum, &(l4_len as u16).to_be_bytes());
+        sum = checksum::add(sum, &data[l4..off + total_len]);
+        let mut csum = checksum::finish(sum);
+        if proto == IPPROTO_UDP && csum == 0 {
+            // 0 would mean no checksum for UDP
+            csum = 0xffff;
+        }
+        data[csum_off..csum_off + 2].copy_from_slice(&csum.to_be_bytes());
+        true
+    }
+}
+
+/// Length of Linux cooked capture (SLL) header.
+const SLL_HDR_LEN: usize = 16;
+/// `ARPHRD_` type of Ethernet devices in SLL header.
+const ARPHRD_ETHER: u16 = 1;
+/// Smallest value of the EtherType field, smaller values are lengths.
+/// SLL headers use those for protocols without an EtherType.
+const ETHERTYPE_MIN: u16 = 0x0600;
+
+/// [Transform] replacing Linux cooked capture (SLL) header of packets with
+/// Ethernet header, for writing packets captured from Linux `any` device to
+/// an Ethernet interface.
+///
+/// Source MAC address is taken from the SLL header if the packet was
+/// captured from an Ethernet device, and is zero otherwise. Packets
+/// without EtherType (such as 802.2 LLC frames) can not be converted.
+pub struct SllToEthernet {
+    dst: [u8; 6],
+}
+
+impl SllToEthernet {
+    /// Creates [SllToEthernet] setting destination MAC address of the
+    /// frames to `dst`.
+    pub fn new(dst: [u8; 6]) -> Self {
+        SllToEthernet { dst }
+    }
+}
+
+impl Transform for SllToEthernet {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        if data.len() < SLL_HDR_LEN {
+            return false;
+        }
+        let protocol = u16::from_be_bytes([data[14], data[15]]);
+        if protocol < ETHERTYPE_MIN {
+            return false;
+        }
+        let hatype = u16::from_be_bytes([data[2], data[3]]);
+        let addr_len = u16::from_be_bytes([data[4], data[5]]);
+        let mut src = [0u8; 6];
+        if hatype == ARPHRD_ETHER && addr_len == 6 {
+            src.copy_from_slice(&data[6..12]);
+        }
+        // Ethernet header is 2 bytes shorter, reuse the end of SLL header
+        data.drain(..SLL_HDR_LEN - ETH_HDR_LEN);
+        data[0..6].copy_from_slice(&self.dst);
+        data[6..12].copy_from_slice(&src);
+        // EtherType is already in place
+        true
+    }
+}
+
+/// Mapping of IPv4 addresses in one network into another.
+///
+/// Network part of matching addresses is replaced with the target network,
+/// bits of the original address outside the target prefix are kept. For
+/// example with mapping `10.0.0.0/8=192.168.0.0/16`, address `10.1.2.3` is
+/// mapped to `192.168.2.3`.
+#[derive(Clone, Copy)]
+pub struct Ipv4Mapping {
+    from: u32,
+    from_mask: u32,
+    to: u32,
+    to_mask: u32,
+}
+
+/// Returns netmask for prefix of given length.
+fn prefix_mask(len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - u32::from(len.min(32)))
+        .unwrap_or(0)
+}
+
+impl Ipv4Mapping {
+    /// Creates mapping from network `from/from_len` to `to/to_len`.
+    pub fn new(from: Ipv4Addr, from_len: u8, to: Ipv4Addr, to_len: u8) -> Self {
+        let from_mask = prefix_mask(from_len);
+        let to_mask = prefix_mask(to_len);
+        Ipv4Mapping {
+            from: u32::from(from) & from_mask,
+            from_mask,
+            to: u32::from(to) & to_mask,
+            to_mask,
+        }
+    }
+
+    /// Returns the mapped address if `addr` belongs to source network.
+    fn map(&self, addr: u32) -> Option<u32> {
+        if addr & self.from_mask == self.from {
+            Some(self.to | (addr & !self.to_mask))
+        } else {
+            None
+        }
+    }
+}
+
+/// [Transform] rewriting source and destination addresses of IPv4
+/// packets according to [Ipv4Mapping]s. IPv4 header checksum and TCP and
+/// UDP checksums are updated to match the new addresses.
+///
+/// Packets which are not IPv4 are passed unchanged, IPv4 packets with
+/// truncated header can not be rewritten.
+pub struct IpRewrite {
+    mappings: Vec<Ipv4Mapping>,
+}
+
+impl IpRewrite {
+    /// Creates [IpRewrite] using given mappings. For each address, the first
+    /// matching mapping is used.
+    pub fn new(mappings: Vec<Ipv4Mapping>) -> Self {
+        IpRewrite { mappings }
+    }
+
+    /// Returns address `addr` mapped by the first matching mapping.
+    fn map(&self, addr: u32) -> Option<u32> {
+        self.mappings.iter().find_map(|m| m.map(addr))
+    }
+}
+
+impl Transform for IpRewrite {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        let Some((ETHERTYPE_IPV4, off)) = network_layer(data) else {
+            return true;
+        };
+        if data.len() < off + 20 {
+            return false;
+        }
+        let ihl = usize::from(data[off] & 0x0f) * 4;
+        if ihl < 20 || data.len() < off + ihl {
+            return false;
+        }
+        let proto = data[off + 9];
+        let frag_off = u16::from_be_bytes([data[off + 6], data[off + 7]]) & 0x1fff;
+        // only the first fragment carries the transport header
+        let l4_csum = if frag_off == 0 {
+            transport_checksum(data, proto, off + ihl)
+        } else {
+            None
+        };
+        for field in [off + 12, off + 16] {
+            let old: [u8; 4] = data[field..field + 4].try_into().unwrap();
+            let Some(new) = self.map(u32::from_be_bytes(old)) else {
+                continue;
+            };
+            let new = new.to_be_bytes();
+            data[field..field + 4].copy_from_slice(&new);
+            adjust_checksum_at(data, off + 10, &old, &new, false);
+            if let Some(csum) = l4_csum {
+                adjust_checksum_at(data, csum, &old, &new, proto == IPPROTO_UDP);
+            }
+        }
+        true
+    }
+}
+
+/// [Transform] anonymizing source and destination addresses of IPv4 and
+/// IPv6 packets with [PrefixPreserving] anonymization. IPv4 header checksum
+/// and TCP, UDP and ICMPv6 checksums are updated to match the new
+/// addresses.
+///
+/// Packets which are not IP are passed unchanged, IP packets with truncated
+/// header can not be anonymized.
+pub struct Anonymize {
+    anon: PrefixPreserving,
+}
+
+impl Anonymize {
+    /// Creates [Anonymize] anonymizing addresses with key derived from
+    /// `key`.
+    pub fn new(key: &str) -> Self {
+        Anonymize {
+            anon: PrefixPreserving::new(key),
+        }
+    }
+
+    /// Anonymizes addresses of IPv4 packet with header at `off`.
+    fn ipv4(&mut self, data: &mut [u8], off: usize) -> bool {
+        if data.len() < off + 20 {
+            return false;
+        }
+        let ihl = usize::from(data[off] & 0x0f) * 4;
+        if ihl < 20 || data.len() < off + ihl {
+            return false;
+        }
+        let proto = data[off + 9];
+        let frag_off = u16::from_be_bytes([data[off + 6], data[off + 7]]) & 0x1fff;
+        // only the first fragment carries the transport header
+        let l4_csum = if frag_off == 0 {
+            transport_checksum(data, proto, off + ihl)
+        } else {
+            None
+        };
+        for field in [off + 12, off + 16] {
+            let old: [u8; 4] = data[field..field + 4].try_into().unwrap();
+            let new = self.anon.ipv4(u32::from_be_bytes(old)).to_be_bytes();
+            data[field..field + 4].copy_from_slice(&new);
+            adjust_checksum_at(data, off + 10, &old, &new, false);
+            if let Some(csum) = l4_csum {
+                adjust_checksum_at(data, csum, &old, &new, proto == IPPROTO_UDP);
+            }
+        }
+        true
+    }
+
+    /// Anonymizes addresses of IPv6 packet with header at `off`.
+    fn ipv6(&mut self, data: &mut [u8], off: usize) -> bool {
+        if data.len() < off + 40 {
+            return false;
+        }
+        let l4_csum = match ipv6_transport(data, off) {
+            Some((proto, Some(l4))) => transport_checksum(data, proto, l4).map(|c| (c, proto)),
+            _ => None,
+        };
+        for field in [off + 8, off + 24] {
+            let old: [u8; 16] = data[field..field + 16].try_into().unwrap();
+            let new = self.anon.ipv6(u128::from_be_bytes(old)).to_be_bytes();
+            data[field..field + 16].copy_from_slice(&new);
+            if let Some((csum, proto)) = l4_csum {
+                adjust_checksum_at(data, csum, &old, &new, proto == IPPROTO_UDP);
+            }
+        }
+        true
+    }
+}
+
+impl Transform for Anonymize {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        match network_layer(data) {
+            Some((ETHERTYPE_IPV4, off)) => self.ipv4(data, off),
+            Some((ETHERTYPE_IPV6, off)) => self.ipv6(data, off),
+            _ => true,
+        }
+    }
+}
+
+/// Mapping of IPv6 addresses in one network into another, like
+/// [Ipv4Mapping] for IPv4 addresses.
+#[derive(Clone, Copy)]
+pub struct Ipv6Mapping {
+    from: u128,
+    from_mask: u128,
+    to: u128,
+    to_mask: u128,
+}
+
+/// Returns IPv6 netmask for prefix of given length.
+fn prefix_mask_v6(len: u8) -> u128 {
+    u128::MAX
+        .checked_shl(128 - u32::from(len.min(128)))
+        .unwrap_or(0)
+}
+
+impl Ipv6Mapping {
+    /// Creates mapping from network `from/from_len` to `to/to_len`.
+    pub fn new(from: Ipv6Addr, from_len: u8, to: Ipv6Addr, to_len: u8) -> Self {
+        let from_mask = prefix_mask_v6(from_len);
+        let to_mask = prefix_mask_v6(to_len);
+        Ipv6Mapping {
+            from: u128::from(from) & from_mask,
+            from_mask,
+            to: u128::from(to) & to_mask,
+            to_mask,
+        }
+    }
+
+    /// Returns the mapped address if `addr` belongs to source network.
+    fn map(&self, addr: u128) -> Option<u128> {
+        if addr & self.from_mask == self.from {
+            Some(self.to | (addr & !self.to_mask))
+        } else {
+            None
+        }
+    }
+}
+
+/// [Transform] rewriting source and destination addresses of IPv6 packets
+/// according to [Ipv6Mapping]s and optionally setting their flow label.
+/// TCP, UDP and ICMPv6 checksums, which cover the addresses through the
+/// pseudo-header, are updated to match the new addresses.
+///
+/// Packets which are not IPv6 are passed unchanged, IPv6 packets with
+/// truncated header can not be rewritten.
+pub struct Ipv6Rewrite {
+    mappings: Vec<Ipv6Mapping>,
+    flow_label: Option<u32>,
+}
+
+impl Ipv6Rewrite {
+    /// Creates [Ipv6Rewrite] using given mappings and setting flow label to
+    /// `flow_label` (lowest 20 bits), if given. For each address, the first
+    /// matching mapping is used.
+    pub fn new(mappings: Vec<Ipv6Mapping>, flow_label: Option<u32>) -> Self {
+        Ipv6Rewrite {
+            mappings,
+            flow_label,
+        }
+    }
+
+    /// Returns address `addr` mapped by the first matching mapping.
+    fn map(&self, addr: u128) -> Option<u128> {
+        self.mappings.iter().find_map(|m| m.map(addr))
+    }
+}
+
+impl Transform for Ipv6Rewrite {
+    fn apply(&mut self, data: &mut Vec<u8>) -> bool {
+        let Some((ETHERTYPE_IPV6, off)) = network_layer(data) else {
+            return true;
+        };
+        if data.len() < off + 40 {
+            return false;
+        }
+        if let Some(label) = self.flow_label {
+            let word = u32::from_be_bytes(data[off..off + 4].try_into().unwrap());
+            let word = (word & !0xfffff) | (label & 0xfffff);
+            data[off..off + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        let l4_csum = match ipv6_transport(data, off) {
+            Some((proto, Some(l4))) => transport_checksum(data, proto, l4).map(|c| (c, proto)),
+            _ => None,
+        };
+        for field in [off + 8, off + 24] {
+            let old: [u8; 16] = data[field..field + 16].try_into().unwrap();
+            let Some(new) = self.map(u128::from_be_bytes(old)) else {
+                continue;
+            };
+            let new = new.to_be_bytes();
+            data[field..field + 16].copy_from_slice(&new);
+            if let Some((csum, proto)) = l4_csum {
+                adjust_checksum_at(data, csum, &old, &new, proto == IPPROTO_UDP);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,285 @@
+//! Bounded single producer, single consumer queue.
+//!
+//! [Ring] is used by [crate::channel] to pass packets from reader to writer
+//! without locking. Capacity of the ring limits the number of queued
+//! packets, producer waits while the ring is full and consumer while it is
+//! empty.
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+/// Longest time to wait for the other side before checking the state
+/// again. Wakeups are not missed, but the stop flag given to [Ring::pop]
+/// is only noticed when checked.
+const MAX_WAIT: Duration = Duration::from_millis(10);
+
+/// Thread waiting for the other side of [Ring].
+#[derive(Default)]
+struct Waiter {
+    thread: OnceLock<Thread>,
+    waiting: AtomicBool,
+}
+
+impl Waiter {
+    /// Waits until woken up, or `ready` returns `true`. Must always be
+    /// called from the same thread.
+    fn wait(&self, ready: impl Fn() -> bool) {
+        self.thread.get_or_init(thread::current);
+        self.waiting.store(true, Ordering::SeqCst);
+        if !ready() {
+            thread::park_timeout(MAX_WAIT);
+        }
+        self.waiting.store(false, Ordering::SeqCst);
+    }
+
+    /// Wakes up the thread if it is waiting.
+    fn wake(&self) {
+        if self.waiting.load(Ordering::SeqCst) {
+            if let Some(thread) = self.thread.get() {
+                thread.unpark();
+            }
+        }
+    }
+}
+
+/// Bounded queue for passing values from one producer thread to one
+/// consumer thread.
+pub struct Ring<T> {
+    slots: Box<[UnsafeCell<Option<T>>]>,
+    /// Number of values popped, only written by consumer.
+    head: AtomicUsize,
+    /// Number of values pushed, only written by producer.
+    tail: AtomicUsize,
+    /// Largest number of values in the ring at any time.
+    max_len: AtomicUsize,
+    /// Total time producer has waited for room, in nanoseconds.
+    stalled: AtomicU64,
+    /// Set when producer will not push any more values.
+    tx_closed: AtomicBool,
+    /// Set when consumer will not pop any more values.
+    rx_closed: AtomicBool,
+    producer: Waiter,
+    consumer: Waiter,
+}
+
+// SAFETY: slot is accessed by producer only while it is not in the range
+// of values available to consumer, and by consumer only while it is. The
+// range is published with release stores of head and tail.
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    /// Creates [Ring] holding at most `capacity` values.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Ring {
+            slots: (0..capacity).map(|_| UnsafeCell::new(None)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            max_len: AtomicUsize::new(0),
+            stalled: AtomicU64::new(0),
+            tx_closed: AtomicBool::new(false),
+            rx_closed: AtomicBool::new(false),
+            producer: Waiter::default(),
+            consumer: Waiter::default(),
+        }
+    }
+
+    /// Returns the number of values in the ring.
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::SeqCst)
+            .wrapping_sub(self.head.load(Ordering::SeqCst))
+    }
+
+    /// Returns the largest number of values that have been in the ring at
+    /// the same time.
+    pub fn max_len(&self) -> usize {
+        self.max_len.load(Ordering::Relaxed)
+    }
+
+    /// Returns the total time producer has waited for room in the ring.
+    pub fn stalled(&self) -> Duration {
+        Duration::from_nanos(self.stalled.load(Ordering::Relaxed))
+    }
+
+    /// Pushes `value` to the ring, waiting while the ring is full. Returns
+    /// the value back if consumer has closed the ring. Must only be called
+    /// from the producer thread.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            if self.rx_closed.load(Ordering::SeqCst) {
+                return Err(value);
+            }
+            if tail.wrapping_sub(self.head.load(Ordering::SeqCst)) < self.slots.len() {
+                break;
+            }
+            let start = Instant::now();
+            self.producer.wait(|| {
+                self.rx_closed.load(Ordering::SeqCst)
+                    || tail.wrapping_sub(self.head.load(Ordering::SeqCst)) < self.slots.len()
+            });
+            let waited = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+            self.stalled.fetch_add(waited, Ordering::Relaxed);
+        }
+        // SAFETY: slot is not available to consumer until tail is advanced.
+        unsafe { *self.slots[tail % self.slots.len()].get() = Some(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::SeqCst);
+        self.max_len.fetch_max(
+            tail.wrapping_add(1)
+                .wrapping_sub(self.head.load(Ordering::SeqCst)),
+            Ordering::Relaxed,
+        );
+        self.consumer.wake();
+        Ok(())
+    }
+
+    /// Pops next value from the ring, waiting while the ring is empty.
+    /// Returns [None] once producer has closed the ring and all values
+    /// have been popped, or when `stop` is set. Must only be called from
+    /// the consumer thread.
+    pub fn pop(&self, stop: &AtomicBool) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        loop {
+            if stop.load(Ordering::Relaxed) {
+                return None;
+            }
+            if self.tail.load(Ordering::SeqCst) != head {
+                break;
+            }
+            if self.tx_closed.load(Ordering::SeqCst) {
+                // producer might have pushed before closing
+                if self.tail.load(Ordering::SeqCst) != head {
+                    break;
+                }
+                return None;
+            }
+            self.consumer.wait(|| {
+                self.tx_closed.load(Ordering::SeqCst) || self.tail.load(Ordering::SeqCst) != head
+            });
+        }
+        // SAFETY: slot is not written by producer until head is advanced.
+        let value = unsafe { (*self.slots[head % self.slots.len()].get()).take() };
+        self.head.store(head.wrapping_add(1), Ordering::SeqCst);
+        self.producer.wake();
+        value
+    }
+
+    /// Closes the producer side, consumer gets the remaining values.
+    pub fn close_tx(&self) {
+        self.tx_closed.store(true, Ordering::SeqCst);
+        self.consumer.wake();
+    }
+
+    /// Closes the consumer side, producer fails to push any more values.
+    pub fn close_rx(&self) {
+        self.rx_closed.store(true, Ordering::SeqCst);
+        self.producer.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+
+    use super::*;
+
+    /// Longest time a test waits for the other thread before failing.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Runs `f` in a thread and returns its result. Panics if `f` does
+    /// not return in time.
+    fn run_with_timeout<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> R {
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = done_tx.send(f());
+        });
+        match done_rx.recv_timeout(TIMEOUT) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => panic!("ring deadlocked"),
+            Err(err) => panic!("thread failed: {err}"),
+        }
+    }
+
+    #[test]
+    fn push_pop_order_across_threads() {
+        const VALUES: u32 = 10_000;
+        let ring = Arc::new(Ring::new(8));
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                for i in 0..VALUES {
+                    ring.push(i).unwrap();
+                }
+                ring.close_tx();
+            })
+        };
+        let received = run_with_timeout(move || {
+            let stop = AtomicBool::new(false);
+            std::iter::from_fn(|| ring.pop(&stop)).collect::<Vec<_>>()
+        });
+        assert_eq!(received, (0..VALUES).collect::<Vec<_>>());
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn producer_waits_at_capacity_one() {
+        let ring = Arc::new(Ring::new(1));
+        let stop = AtomicBool::new(false);
+        ring.push(1).unwrap();
+        let pushed = Arc::new(AtomicBool::new(false));
+        let producer = {
+            let ring = ring.clone();
+            let pushed = pushed.clone();
+            thread::spawn(move || {
+                ring.push(2).unwrap();
+                pushed.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pushed.load(Ordering::SeqCst), "push did not wait for room");
+        assert_eq!(ring.len(), 1);
+        assert_eq!(ring.pop(&stop), Some(1));
+        producer.join().unwrap();
+        assert!(pushed.load(Ordering::SeqCst));
+        assert_eq!(ring.pop(&stop), Some(2));
+        assert_eq!(ring.max_len(), 1);
+        assert!(ring.stalled() > Duration::ZERO);
+    }
+
+    #[test]
+    fn close_tx_drains_remaining() {
+        let ring = Ring::new(4);
+        let stop = AtomicBool::new(false);
+        for i in 0..3 {
+            ring.push(i).unwrap();
+        }
+        ring.close_tx();
+        assert_eq!(ring.pop(&stop), Some(0));
+        assert_eq!(ring.pop(&stop), Some(1));
+        assert_eq!(ring.pop(&stop), Some(2));
+        assert_eq!(ring.pop(&stop), None);
+    }
+
+    #[test]
+    fn close_rx_unblocks_push() {
+        let ring = Arc::new(Ring::new(1));
+        ring.push(1).unwrap();
+        let closer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                ring.close_rx();
+            })
+        };
+        let result = run_with_timeout(move || ring.push(2));
+        assert_eq!(result, Err(2));
+        closer.join().unwrap();
+    }
+}
@@ -0,0 +1,46 @@
+//! Pseudo random number generator for randomized replay features.
+//!
+//! The generator is seedable so that randomized runs can be reproduced, it
+//! is not suitable for anything requiring cryptographic randomness.
+use std::time::SystemTime;
+
+/// SplitMix64 pseudo random number generator.
+pub struct Rng(u64);
+
+impl Rng {
+    /// Creates [Rng] with given seed.
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    /// Returns a seed derived from current time, for runs where no seed
+    /// was given.
+    pub fn time_seed() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default()
+    }
+
+    /// Returns seed for stream number `stream` of a run seeded with
+    /// `seed`. Randomized features take their seeds from separate streams,
+    /// so that the same `seed` gives each feature the same numbers
+    /// regardless of which other features are used.
+    pub fn stream_seed(seed: u64, stream: u64) -> u64 {
+        Rng::new(seed ^ Rng::new(stream).next_u64()).next_u64()
+    }
+
+    /// Returns next random [u64].
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns random [f64] uniformly distributed in range `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
@@ -0,0 +1,90 @@
+//! Scheduling of the reader and writer threads.
+use std::{io, thread::JoinHandle};
+
+/// Pins the thread of `handle` to run only on `cpu`.
+#[cfg(target_os = "linux")]
+fn set_affinity<T>(handle: &JoinHandle<T>, cpu: usize) -> io::Result<()> {
+    use std::os::unix::thread::JoinHandleExt;
+
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "CPU number too large",
+        ));
+    }
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    let ret = unsafe {
+        libc::pthread_setaffinity_np(
+            handle.as_pthread_t(),
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &set,
+        )
+    };
+    // pthread functions return the error instead of setting errno
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_affinity<T>(_handle: &JoinHandle<T>, _cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "not supported on this platform",
+    ))
+}
+
+/// Sets the thread of `handle` to use real-time `SCHED_FIFO` scheduling
+/// policy with given priority.
+#[cfg(target_os = "linux")]
+fn set_fifo<T>(handle: &JoinHandle<T>, priority: i32) -> io::Result<()> {
+    use std::os::unix::thread::JoinHandleExt;
+
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    let ret =
+        unsafe { libc::pthread_setschedparam(handle.as_pthread_t(), libc::SCHED_FIFO, &param) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fifo<T>(_handle: &JoinHandle<T>, _priority: i32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "not supported on this platform",
+    ))
+}
+
+/// Pins the thread of `handle` to run only on `cpu`. Failing to do so is
+/// not fatal, the thread keeps running where the scheduler puts it.
+pub fn pin<T>(handle: &JoinHandle<T>, cpu: usize) {
+    let name = handle.thread().name().unwrap_or("unnamed");
+    match set_affinity(handle, cpu) {
+        Ok(()) => tracing::debug!("pinned thread {} to CPU {}", name, cpu),
+        Err(e) => tracing::warn!("unable to pin thread {} to CPU {}: {}", name, cpu, e),
+    }
+}
+
+/// Sets the thread of `handle` to real-time priority `priority`. Failing to
+/// do so is not fatal, the thread keeps the normal scheduling policy.
+pub fn realtime<T>(handle: &JoinHandle<T>, priority: i32) {
+    let name = handle.thread().name().unwrap_or("unnamed");
+    match set_fifo(handle, priority) {
+        Ok(()) => tracing::debug!("thread {} uses real-time priority {}", name, priority),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => tracing::warn!(
+            "unable to set real-time priority for thread {}, CAP_SYS_NICE is needed",
+            name
+        ),
+        Err(e) => tracing::warn!(
+            "unable to set real-time priority for thread {}: {}",
+            name,
+            e
+        ),
+    }
+}
@@ -0,0 +1,216 @@
+//! Self-test checking that written packets are received back.
+//!
+//! [SelfTest] captures packets from an interface while packets are written
+//! to an output cabled (or tapped) back to it. Writers wrapped with
+//! [SelfTest::writer] record the packets written, and once writing is done
+//! [SelfTest::finish] matches them with the captured packets. Packets are
+//! matched by hash of their contents, so the order they are received in
+//! does not matter.
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::{DefaultHasher, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    input::{self, Packet},
+    output::PacketWriter,
+};
+
+/// Minimum size of Ethernet frame without FCS. Shorter frames are padded
+/// with zeroes when transmitted, so they are received longer than written.
+const MIN_FRAME_LEN: usize = 60;
+
+/// Number of packets by hash of their contents.
+type Counts = HashMap<u64, u64>;
+
+/// Returns hash of packet `data`, padded like a transmitted Ethernet frame.
+fn packet_hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    for _ in data.len()..MIN_FRAME_LEN {
+        hasher.write_u8(0);
+    }
+    hasher.finish()
+}
+
+/// Result of matching written packets with received ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Verdict {
+    /// Number of written packets that were received.
+    pub matched: u64,
+    /// Number of written packets that were not received.
+    pub unmatched: u64,
+    /// Number of received packets that were not written.
+    pub extra: u64,
+}
+
+impl Verdict {
+    /// Returns [Verdict] for packets counted in `sent` and `received`.
+    fn from_counts(sent: &Counts, received: &Counts) -> Self {
+        let mut verdict = Verdict::default();
+        for (hash, count) in sent {
+            let seen = received.get(hash).copied().unwrap_or(0);
+            verdict.matched += seen.min(*count);
+            verdict.unmatched += count.saturating_sub(seen);
+        }
+        let received: u64 = received.values().sum();
+        verdict.extra = received - verdict.matched;
+        verdict
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "selftest: {} matched, {} unmatched, {} extra",
+            self.matched, self.unmatched, self.extra
+        )
+    }
+}
+
+/// Running self-test, capturing packets from an interface.
+pub struct SelfTest {
+    /// Packets written through [SelfTest::writer].
+    sent: Arc<Mutex<Counts>>,
+    /// Stops capturing when set.
+    stop: Arc<AtomicBool>,
+    /// Handle for the capturing thread, returns the packets received.
+    capture: JoinHandle<Counts>,
+}
+
+/// Starts self-test capturing packets from interface `ifname`. Returns
+/// error if the interface can not be opened for capturing.
+pub fn start(ifname: &str) -> Result<SelfTest> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let capture_stop = Arc::clone(&stop);
+    let name = ifname.to_string();
+    // the capture is opened in the capturing thread, which reports back
+    // once it is ready so that no written packets are missed
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let capture = thread::Builder::new()
+        .name("selftest-capture".to_string())
+        .spawn(move || {
+            let mut received = Counts::new();
+            let input = match input::pcap_interface(&name, None, None, Default::default()) {
+                Ok(input) => input,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return received;
+                }
+            };
+            let packets = match input.packets(&capture_stop) {
+                Ok(packets) => packets,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return received;
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+            for pkt in packets {
+                *received.entry(packet_hash(&pkt.data)).or_default() += 1;
+            }
+            received
+        })?;
+    ready_rx
+        .recv()
+        .context("selftest capture terminated")?
+        .with_context(|| format!("unable to capture from {ifname} for selftest"))?;
+    Ok(SelfTest {
+        sent: Arc::default(),
+        stop,
+        capture,
+    })
+}
+
+impl SelfTest {
+    /// Returns [PacketWriter] writing to `inner` and recording the packets
+    /// written for matching.
+    pub fn writer(&self, inner: impl PacketWriter) -> impl PacketWriter {
+        Recorder {
+            inner,
+            sent: Arc::clone(&self.sent),
+        }
+    }
+
+    /// Waits `grace` for packets still on their way back, stops capturing
+    /// and returns the [Verdict] for packets written so far.
+    pub fn finish(self, grace: Duration) -> Verdict {
+        thread::sleep(grace);
+        self.stop.store(true, Ordering::Relaxed);
+        let received = self.capture.join().unwrap();
+        let sent = self.sent.lock().unwrap();
+        Verdict::from_counts(&sent, &received)
+    }
+}
+
+/// [PacketWriter] recording the packets it writes successfully.
+struct Recorder<W> {
+    inner: W,
+    sent: Arc<Mutex<Counts>>,
+}
+
+impl<W> Recorder<W> {
+    /// Records `data` as written if `len` bytes of it were written.
+    fn record(&self, data: &[u8], len: usize) {
+        if len > 0 {
+            *self
+                .sent
+                .lock()
+                .unwrap()
+                .entry(packet_hash(data))
+                .or_default() += 1;
+        }
+    }
+}
+
+impl<W: PacketWriter> PacketWriter for Recorder<W> {
+    fn write_raw(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.inner.write_raw(buf)?;
+        self.record(buf, len);
+        Ok(len)
+    }
+
+    fn write_packet(&mut self, packet: &Packet) -> Result<usize> {
+        let len = self.inner.write_packet(packet)?;
+        self.record(&packet.data, len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn link_type(&self) -> Option<u32> {
+        self.inner.link_type()
+    }
+
+    fn write_batch(&mut self, packets: &[Packet], written: &mut Vec<usize>) -> Result<()> {
+        let start = written.len();
+        let ret = self.inner.write_batch(packets, written);
+        // packets written before an error were sent too
+        for (packet, len) in packets.iter().zip(&written[start..]) {
+            self.record(&packet.data, *len);
+        }
+        ret
+    }
+
+    fn can_schedule(&self) -> bool {
+        self.inner.can_schedule()
+    }
+
+    fn write_scheduled(&mut self, packet: &Packet, at: Instant) -> Result<usize> {
+        let len = self.inner.write_scheduled(packet, at)?;
+        self.record(&packet.data, len);
+        Ok(len)
+    }
+}
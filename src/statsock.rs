@@ -0,0 +1,86 @@
+//! Unix domain socket serving periodic statistics.
+//!
+//! Every statistics line is written to all clients connected to the socket.
+//! Clients can connect and disconnect at any time, they get the lines
+//! written while they are connected.
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long writing a line to a client may block. Clients not reading the
+/// lines are disconnected instead of holding up the others.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Unix domain socket statistics lines are written to. The socket file is
+/// removed when this is dropped.
+pub struct StatsSocket {
+    path: PathBuf,
+    clients: Arc<Mutex<Vec<UnixStream>>>,
+}
+
+impl StatsSocket {
+    /// Writes `line` to all connected clients, disconnecting clients the
+    /// line can not be written to.
+    pub fn send(&self, line: &str) {
+        let msg = format!("{line}\n");
+        self.clients
+            .lock()
+            .unwrap()
+            .retain_mut(|client| match client.write_all(msg.as_bytes()) {
+                Ok(()) => true,
+                Err(err) => {
+                    tracing::debug!(?err, "statistics socket client disconnected");
+                    false
+                }
+            });
+    }
+}
+
+impl Drop for StatsSocket {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::debug!(?err, "unable to remove statistics socket");
+        }
+    }
+}
+
+/// Starts thread accepting clients to Unix domain socket at `path`. Socket
+/// left behind by earlier run is replaced, but other existing files are
+/// not. Returns error if the socket can not be bound.
+pub fn start_server(path: &Path) -> io::Result<StatsSocket> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file exists and is not a socket",
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    let clients: Arc<Mutex<Vec<UnixStream>>> = Arc::default();
+    let accepted = Arc::clone(&clients);
+    thread::Builder::new()
+        .name("stats-socket".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(|s| {
+                    s.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    accepted.lock().unwrap().push(s);
+                    Ok(())
+                });
+                if let Err(err) = res {
+                    tracing::debug!(?err, "error while accepting statistics client");
+                }
+            }
+        })?;
+    Ok(StatsSocket {
+        path: path.to_path_buf(),
+        clients,
+    })
+}